@@ -0,0 +1,46 @@
+// compares write throughput under each durability setting. run with `cargo bench`
+use diskmap::disk::durability::Durability;
+use diskmap::disk::map::{DiskMap, Options};
+use diskmap::disk::store::KvStore;
+use std::{env, error, fs, process, time};
+
+const WRITES: usize = 1000;
+
+fn main() -> Result<(), Box<dyn error::Error>> {
+    let modes = [
+        Durability::Always,
+        Durability::Interval(time::Duration::from_millis(100)),
+        Durability::Never,
+    ];
+
+    for durability in modes {
+        let file_path = env::temp_dir().join(format!("diskmap-bench-{}", process::id()));
+        let file_path = file_path.to_str().ok_or("temp dir is not valid utf-8")?;
+        let _ = fs::remove_file(file_path);
+
+        let options = Options {
+            durability,
+            ..Options::default()
+        };
+        let disk_map = DiskMap::new(file_path, options)?;
+
+        let start = time::Instant::now();
+        for i in 0..WRITES {
+            disk_map.set(format!("key{i}").as_bytes(), b"value")?;
+        }
+        let elapsed = start.elapsed();
+
+        println!(
+            "{:<24} {:>10.0} writes/s ({} writes in {:?})",
+            durability.to_string(),
+            WRITES as f64 / elapsed.as_secs_f64(),
+            WRITES,
+            elapsed
+        );
+
+        drop(disk_map);
+        fs::remove_file(file_path)?;
+    }
+
+    Ok(())
+}
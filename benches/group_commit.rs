@@ -0,0 +1,51 @@
+// compares write throughput with one writer against many concurrent writers, with an fsync on
+// every write. with group commit, concurrent writers share fsyncs. run with `cargo bench`
+use diskmap::disk::durability::Durability;
+use diskmap::disk::map::{DiskMap, Options};
+use diskmap::disk::store::KvStore;
+use std::{env, error, fs, process, thread, time};
+
+const WRITES: usize = 2000;
+
+fn main() -> Result<(), Box<dyn error::Error>> {
+    for writers in [1, 4, 16, 64] {
+        let file_path = env::temp_dir().join(format!("diskmap-bench-{}", process::id()));
+        let file_path = file_path.to_str().ok_or("temp dir is not valid utf-8")?;
+        let _ = fs::remove_file(file_path);
+
+        let options = Options {
+            durability: Durability::Always,
+            ..Options::default()
+        };
+        let disk_map = DiskMap::new(file_path, options)?;
+
+        let start = time::Instant::now();
+        thread::scope(|scope| {
+            for writer in 0..writers {
+                let disk_map = &disk_map;
+                scope.spawn(move || {
+                    for i in 0..(WRITES / writers) {
+                        let key = format!("key{writer}-{i}");
+                        if let Err(err) = disk_map.set(key.as_bytes(), b"value") {
+                            eprintln!("error writing {key}: {err}");
+                        }
+                    }
+                });
+            }
+        });
+        let elapsed = start.elapsed();
+
+        println!(
+            "{:>3} writers {:>10.0} writes/s ({} writes in {:?})",
+            writers,
+            WRITES as f64 / elapsed.as_secs_f64(),
+            WRITES,
+            elapsed
+        );
+
+        drop(disk_map);
+        fs::remove_file(file_path)?;
+    }
+
+    Ok(())
+}
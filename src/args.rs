@@ -0,0 +1,124 @@
+use std::error;
+
+use crate::disk::export::Format;
+use crate::disk::map::{Backend, Options};
+
+// what to do with the map once it's open
+#[derive(Clone)]
+pub enum Command {
+    // serve it over tcp
+    Serve,
+    // write every entry to stdout
+    Export(Format),
+    // set every entry read from stdin. fails if anything else has the map open, a server
+    // included
+    Import(Format),
+    // set every entry of the snapshot of this name, then serve the map. the map has to be empty
+    Restore(String),
+}
+
+pub struct Args {
+    pub command: Command,
+    // the active segment for the log engine, a directory for the lsm engine. it holds the
+    // default keyspace
+    pub file_path: String,
+    // where every other keyspace is kept
+    pub data_dir: String,
+    // where snapshots are written and restored from
+    pub snapshot_dir: String,
+    // keep every keyspace in memory instead, like a cache that starts out empty
+    pub in_memory: bool,
+    pub port: String,
+    // how many bytes a client can send without a newline before it's cut off, which bounds what
+    // each connection can make the server buffer. escaped, a value can take up to 4 bytes per
+    // byte of it, so setting values near --max-value-size may need this raised too
+    pub max_line_len: usize,
+    pub options: Options,
+}
+
+impl Args {
+    pub fn usage() -> String {
+        String::from(
+            "usage: diskmap [export <jsonl|csv> | import <jsonl|csv> | restore <snapshot name>] [--file <path>] [--data-dir <path>] [--snapshot-dir <path>] [--port <port>] [--max-line-len <bytes>] [--max-key-size <bytes>] [--max-value-size <bytes>] [--fsync <always|never|<n>ms>] [--engine <log|lsm>] [--in-memory] [--compact-ratio <0-1>] [--compact-growth <bytes>]",
+        )
+    }
+
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Args, Box<dyn error::Error>> {
+        let mut args = args.peekable();
+        let command = match args.next_if(|arg| !arg.starts_with("--")).as_deref() {
+            None => Command::Serve,
+            Some("restore") => {
+                Command::Restore(args.next().ok_or("missing snapshot name for restore")?)
+            }
+            Some(command @ ("export" | "import")) => {
+                let format = args
+                    .next()
+                    .ok_or(format!("missing format for {command}"))?
+                    .parse()?;
+                match command {
+                    "export" => Command::Export(format),
+                    _ => Command::Import(format),
+                }
+            }
+            Some(command) => {
+                return Err(format!("unrecognized command {command}\n{}", Args::usage()).into());
+            }
+        };
+
+        let mut file_path = None;
+        let mut data_dir = None;
+        let mut parsed = Args {
+            command,
+            file_path: String::new(),
+            data_dir: String::new(),
+            snapshot_dir: String::from("/tmp/snapshots"),
+            in_memory: false,
+            port: String::from("8080"),
+            max_line_len: 16 * 1024 * 1024,
+            options: Options::default(),
+        };
+
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value for {flag}"));
+            match flag.as_str() {
+                "--file" => file_path = Some(value()?),
+                "--data-dir" => data_dir = Some(value()?),
+                "--snapshot-dir" => parsed.snapshot_dir = value()?,
+                "--port" => parsed.port = value()?,
+                "--max-line-len" => parsed.max_line_len = value()?.parse()?,
+                "--max-key-size" => parsed.options.max_key_size = value()?.parse()?,
+                "--max-value-size" => parsed.options.max_value_size = value()?.parse()?,
+                "--fsync" => parsed.options.durability = value()?.parse()?,
+                "--engine" => parsed.options.backend = value()?.parse()?,
+                "--in-memory" => parsed.in_memory = true,
+                "--compact-ratio" => {
+                    let ratio: f64 = value()?.parse()?;
+                    if !(0.0..=1.0).contains(&ratio) {
+                        return Err(format!(
+                            "--compact-ratio must be between 0 and 1, got {ratio}"
+                        )
+                        .into());
+                    }
+                    parsed.options.compaction.dead_ratio = ratio;
+                }
+                "--compact-growth" => parsed.options.compaction.growth = value()?.parse()?,
+                _ => return Err(format!("unrecognized argument {flag}\n{}", Args::usage()).into()),
+            }
+        }
+
+        if parsed.in_memory && !matches!(parsed.command, Command::Serve) {
+            return Err("export, import and restore need a map on disk, not --in-memory".into());
+        }
+
+        parsed.file_path = file_path.unwrap_or_else(|| match parsed.options.backend {
+            Backend::Log => String::from("/tmp/map"),
+            Backend::Lsm => String::from("/tmp/map-lsm"),
+        });
+        parsed.data_dir = data_dir.unwrap_or_else(|| match parsed.options.backend {
+            Backend::Log => String::from("/tmp/keyspaces"),
+            Backend::Lsm => String::from("/tmp/keyspaces-lsm"),
+        });
+
+        Ok(parsed)
+    }
+}
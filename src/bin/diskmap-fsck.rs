@@ -0,0 +1,72 @@
+// checks a map file offline: lists every record in it, then whatever is wrong with the file.
+// exits with 1 if anything is
+use diskmap::disk::fsck;
+use diskmap::net::escape;
+use std::{env, error, process};
+
+fn usage() -> String {
+    String::from("usage: diskmap-fsck <file> [--repair <output>] [--quiet]")
+}
+
+fn main() -> Result<(), Box<dyn error::Error>> {
+    let mut path = None;
+    let mut repair_path = None;
+    let mut quiet = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repair" => repair_path = Some(args.next().ok_or("missing value for --repair")?),
+            "--quiet" => quiet = true,
+            _ if arg.starts_with("--") || path.is_some() => {
+                return Err(format!("unrecognized argument {arg}\n{}", usage()).into());
+            }
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+
+    let report = fsck::check(&path)?;
+    if !quiet {
+        for record in &report.records {
+            let mut line = format!(
+                "{:>10}  {:<12} {:<11} len {}, key {}, value {}, version {}",
+                record.offset,
+                record.kind,
+                record.status.to_string(),
+                record.len,
+                record.key.len(),
+                record.value_len,
+                record.version
+            );
+            if record.expires_at != 0 {
+                line.push_str(&format!(", expires at {}", record.expires_at));
+            }
+            if !record.key.is_empty() {
+                line.push_str(&format!("  {}", escape::render(&record.key)));
+            }
+            println!("{line}");
+        }
+    }
+
+    println!(
+        "{path}: format version {}, {} records",
+        report.format_version,
+        report.records.len()
+    );
+    for problem in &report.problems {
+        println!("problem: {problem}");
+    }
+    if let Some((offset, reason)) = &report.torn_tail {
+        println!("torn tail at offset {offset}: {reason}. opening the map cuts it off");
+    }
+
+    if let Some(repair_path) = repair_path {
+        let n = report.repair(&repair_path)?;
+        println!("wrote a repaired copy to {repair_path}, {n} bytes");
+    }
+
+    if !report.is_clean() {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::{error, mem};
+
+// group commit. writers queue up what they want written, and whichever writer finds nobody else
+// committing becomes the leader: it takes everything queued so far and commits it in one go,
+// so a hundred concurrent writers cost one write and one fsync instead of a hundred. everyone
+// else sleeps until the leader posts their outcome
+pub struct CommitQueue<T, O> {
+    state: Mutex<State<T, O>>,
+    cvar: Condvar,
+}
+
+struct State<T, O> {
+    next_ticket: u64,
+    pending: Vec<(u64, T)>,
+    leading: bool,
+    done: HashMap<u64, O>,
+    // tickets whose group's leader panicked before it had an outcome for them
+    abandoned: HashSet<u64>,
+}
+
+// leads one group. however the leader gets out of `commit`, even by panicking, dropping this
+// posts the group's outcomes and hands leadership back, so nobody waits on it forever
+struct Leader<'a, T, O> {
+    queue: &'a CommitQueue<T, O>,
+    tickets: Vec<u64>,
+    outcomes: Vec<O>,
+}
+
+impl<T, O> Drop for Leader<'_, T, O> {
+    fn drop(&mut self) {
+        // nobody panics while holding the lock, but the lock has to be had either way
+        let mut state = self
+            .queue
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut outcomes = mem::take(&mut self.outcomes).into_iter();
+        for ticket in self.tickets.drain(..) {
+            match outcomes.next() {
+                Some(outcome) => {
+                    state.done.insert(ticket, outcome);
+                }
+                None => {
+                    state.abandoned.insert(ticket);
+                }
+            }
+        }
+        state.leading = false;
+        self.queue.cvar.notify_all();
+    }
+}
+
+impl<T, O> CommitQueue<T, O> {
+    pub fn new() -> CommitQueue<T, O> {
+        CommitQueue {
+            state: Mutex::new(State {
+                next_ticket: 0,
+                pending: Vec::new(),
+                leading: false,
+                done: HashMap::new(),
+                abandoned: HashSet::new(),
+            }),
+            cvar: Condvar::new(),
+        }
+    }
+
+    // queues `item` and returns once it has been committed. `commit` gets the items of a whole
+    // group in the order they were submitted and has to return one outcome per item
+    pub fn submit(
+        &self,
+        item: T,
+        commit: impl FnOnce(Vec<T>) -> Vec<O>,
+    ) -> Result<O, Box<dyn error::Error>> {
+        let mut state = self.state.lock().map_err(|e| e.to_string())?;
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, item));
+
+        let mut commit = Some(commit);
+        loop {
+            if let Some(outcome) = state.done.remove(&ticket) {
+                return Ok(outcome);
+            }
+            if state.abandoned.remove(&ticket) {
+                return Err("the write was lost: the writer committing it panicked".into());
+            }
+
+            // somebody else is committing. our item is either in their group or will be in the
+            // next one
+            if state.leading {
+                state = self.cvar.wait(state).map_err(|e| e.to_string())?;
+                continue;
+            }
+
+            // nobody is committing, so we lead. our own item is always in this group, since we
+            // can only get here before it was taken or after its outcome was posted
+            let Some(commit) = commit.take() else {
+                return Err("commit outcome went missing".into());
+            };
+            state.leading = true;
+            let group = mem::take(&mut state.pending);
+            drop(state);
+
+            let (tickets, items): (Vec<u64>, Vec<T>) = group.into_iter().unzip();
+            let mut leader = Leader {
+                queue: self,
+                tickets,
+                outcomes: Vec::new(),
+            };
+            leader.outcomes = commit(items);
+            drop(leader);
+
+            state = self.state.lock().map_err(|e| e.to_string())?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::{panic, thread, time};
+
+    #[test]
+    fn every_writer_gets_its_own_outcome() {
+        let queue = CommitQueue::<usize, usize>::new();
+        let groups = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..64)
+                .map(|i| {
+                    let (queue, groups) = (&queue, &groups);
+                    scope.spawn(move || {
+                        let outcome = queue.submit(i, |items| {
+                            groups.fetch_add(1, Ordering::Relaxed);
+                            thread::sleep(time::Duration::from_millis(1));
+                            items.into_iter().map(|item| item * 2).collect()
+                        });
+                        (i, outcome.unwrap())
+                    })
+                })
+                .collect();
+            for handle in handles {
+                let (i, outcome) = handle.join().unwrap();
+                assert_eq!(outcome, i * 2);
+            }
+        });
+        let groups = groups.load(Ordering::Relaxed);
+        assert!((1..=64).contains(&groups));
+    }
+
+    #[test]
+    fn queue_still_works_after_a_leader_panics() {
+        let queue = CommitQueue::<usize, usize>::new();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            queue.submit(1, |_| panic!("commit failed"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(queue.submit(2, |items| items).unwrap(), 2);
+    }
+
+    #[test]
+    fn writers_in_a_group_whose_leader_panics_get_an_error() {
+        let queue = CommitQueue::<usize, usize>::new();
+        let (started, wait) = mpsc::channel::<()>();
+        let (go, release) = mpsc::channel::<()>();
+        let queue = &queue;
+        thread::scope(|scope| {
+            // holds on to leadership until both of the others are queued up behind it
+            let first = scope.spawn(move || {
+                queue
+                    .submit(0, |items| {
+                        started.send(()).unwrap();
+                        release.recv().unwrap();
+                        items
+                    })
+                    .map_err(|e| e.to_string())
+            });
+            wait.recv().unwrap();
+            let others: Vec<_> = (1..3)
+                .map(|i| {
+                    scope.spawn(move || {
+                        queue
+                            .submit(i, |_| panic!("commit failed"))
+                            .map_err(|e| e.to_string())
+                    })
+                })
+                .collect();
+            while queue.state.lock().unwrap().pending.len() < 2 {
+                thread::yield_now();
+            }
+            go.send(()).unwrap();
+            assert_eq!(first.join().unwrap().unwrap(), 0);
+
+            // one of them leads the next group and panics, the other was in that group
+            let results: Vec<_> = others.into_iter().map(|other| other.join()).collect();
+            assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+            let err = results
+                .into_iter()
+                .find_map(|result| result.ok())
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(err, "the write was lost: the writer committing it panicked");
+        });
+    }
+}
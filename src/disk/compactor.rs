@@ -0,0 +1,117 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::{thread, time};
+
+use crate::disk::engine::{Engine, Usage};
+
+// how often the compactor checks whether the map needs compacting
+const CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// rewriting a small map to get back a few bytes isn't worth it, however many of them are dead
+const MIN_SIZE: usize = 1024 * 1024;
+
+// when the map gets compacted in the background
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    // compact once at least this share of the bytes on disk are dead
+    pub dead_ratio: f64,
+    // compact once the map has grown by this many bytes since it was last compacted, dead or not
+    pub growth: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            dead_ratio: 0.5,
+            growth: 256 * 1024 * 1024,
+        }
+    }
+}
+
+// compacts the map on a thread of its own whenever `Policy` says so. the engine builds the
+// compacted copy while reads and writes carry on, so nobody waits on it for long
+pub struct Compactor {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub fn new(engine: Arc<dyn Engine>, policy: Policy) -> Compactor {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || Compactor::compact_periodically(&*engine, policy, &stopped))
+        };
+
+        Compactor {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    fn compact_periodically(engine: &dyn Engine, policy: Policy, stopped: &(Mutex<bool>, Condvar)) {
+        let (lock, cvar) = stopped;
+        // how big the map was right after it was last compacted. until it is, growth counts from
+        // however big it was when it was opened
+        let mut baseline = None;
+        loop {
+            let Ok(guard) = lock.lock() else { return };
+            let Ok((guard, _)) = cvar.wait_timeout(guard, CHECK_INTERVAL) else {
+                return;
+            };
+            if *guard {
+                return;
+            }
+            drop(guard);
+
+            let usage = match engine.usage() {
+                Ok(usage) => usage,
+                Err(err) => {
+                    eprintln!("error checking whether to compact: {err}");
+                    continue;
+                }
+            };
+            // a compaction somebody asked for shrinks the map without us knowing
+            let since = baseline.get_or_insert(usage.bytes);
+            *since = usage.bytes.min(*since);
+            if !Compactor::due(policy, &usage, *since) {
+                continue;
+            }
+
+            match engine.compact() {
+                Ok(bytes) => {
+                    eprintln!("compacted the map from {} to {bytes} bytes", usage.bytes);
+                    baseline = Some(bytes);
+                }
+                Err(err) => eprintln!("error compacting: {err}"),
+            }
+        }
+    }
+
+    // whether a map using `usage` should be compacted, given it was `baseline` bytes after the
+    // last compaction
+    fn due(policy: Policy, usage: &Usage, baseline: usize) -> bool {
+        if usage.bytes - baseline >= policy.growth {
+            return true;
+        }
+        match usage.dead_bytes {
+            Some(dead_bytes) if usage.bytes >= MIN_SIZE => {
+                dead_bytes as f64 >= usage.bytes as f64 * policy.dead_ratio
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.stopped;
+        if let Ok(mut guard) = lock.lock() {
+            *guard = true;
+        }
+        cvar.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// crc-32 (ieee 802.3), the same checksum zlib and png use. the lookup table is built at compile
+// time so checksumming an entry is one table lookup per byte
+const POLYNOMIAL: u32 = 0xEDB88320;
+const TABLE: [u32; 256] = build_table();
+
+const fn build_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn checksum(bytes: &[u8]) -> u32 {
+    checksum_all(&[bytes])
+}
+
+// the checksum of `parts` one after another, without copying them into one buffer first
+pub fn checksum_all(parts: &[&[u8]]) -> u32 {
+    parts.iter().fold(0, |crc, part| extend(crc, part))
+}
+
+// carries `checksum`, of whatever came before, on over `bytes`. for data that is written a piece
+// at a time, starting from 0
+pub fn extend(checksum: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !checksum;
+    for byte in bytes {
+        crc = TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_standard_check_value() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"123456789"), 0xcbf43926);
+        assert_eq!(
+            checksum(b"The quick brown fox jumps over the lazy dog"),
+            0x414fa339
+        );
+    }
+
+    #[test]
+    fn pieces_add_up_to_the_whole() {
+        let bytes = b"The quick brown fox jumps over the lazy dog";
+        for split in 0..bytes.len() {
+            let (a, b) = bytes.split_at(split);
+            assert_eq!(checksum_all(&[a, b]), checksum(bytes));
+            assert_eq!(extend(checksum(a), b), checksum(bytes));
+        }
+    }
+
+    #[test]
+    fn every_single_bit_flip_is_caught() {
+        let bytes = b"key value".to_vec();
+        let expected = checksum(&bytes);
+        for i in 0..bytes.len() * 8 {
+            let mut flipped = bytes.clone();
+            flipped[i / 8] ^= 1 << (i % 8);
+            assert_ne!(checksum(&flipped), expected);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use nix::{fcntl, fcntl::OFlag, sys::stat::Mode, unistd};
+use std::os::fd::AsFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::{error, fmt, os, str, thread, time};
+
+// how hard a write tries to reach the disk before it is acknowledged. fsyncing on every write
+// means an acknowledged write survives a power loss but every write waits for the disk. on a
+// timer, at most that much time worth of writes can be lost. leaving it to the os is the
+// fastest and loses whatever the kernel hadn't written back yet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    Always,
+    Interval(time::Duration),
+    Never,
+}
+
+impl str::FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Durability, String> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "never" => Ok(Durability::Never),
+            _ => {
+                let err = || format!("expected always, never or <n>ms, got {s}");
+                let ms = s.strip_suffix("ms").ok_or_else(err)?;
+                let ms: u64 = ms.parse().map_err(|_| err())?;
+                if ms == 0 {
+                    return Err(err());
+                }
+                Ok(Durability::Interval(time::Duration::from_millis(ms)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::Always => write!(f, "fsync on every write"),
+            Durability::Interval(interval) => write!(f, "fsync every {}ms", interval.as_millis()),
+            Durability::Never => write!(f, "fsync left to the OS"),
+        }
+    }
+}
+
+// carries out a `Durability` after every write
+pub struct Flusher {
+    durability: Durability,
+    state: Arc<(Mutex<FlusherState>, Condvar)>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct FlusherState {
+    dirty: bool,
+    stopped: bool,
+}
+
+impl Flusher {
+    pub fn new(file_path: &str, durability: Durability) -> Flusher {
+        let state = Arc::new((Mutex::new(FlusherState::default()), Condvar::new()));
+
+        let thread = match durability {
+            Durability::Interval(interval) => {
+                let state = Arc::clone(&state);
+                let file_path = file_path.to_owned();
+                Some(thread::spawn(move || {
+                    Flusher::flush_periodically(&file_path, interval, &state)
+                }))
+            }
+            Durability::Always | Durability::Never => None,
+        };
+
+        Flusher {
+            durability,
+            state,
+            thread,
+        }
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    // call after writing to `fd`, while still holding its lock
+    pub fn wrote(&self, fd: os::fd::BorrowedFd) -> Result<(), Box<dyn error::Error>> {
+        match self.durability {
+            Durability::Always => unistd::fdatasync(fd)?,
+            Durability::Interval(_) => {
+                let (lock, _) = &*self.state;
+                lock.lock().map_err(|e| e.to_string())?.dirty = true;
+            }
+            Durability::Never => {}
+        }
+        Ok(())
+    }
+
+    fn flush_periodically(
+        file_path: &str,
+        interval: time::Duration,
+        state: &(Mutex<FlusherState>, Condvar),
+    ) {
+        let (lock, cvar) = state;
+        loop {
+            let Ok(guard) = lock.lock() else { return };
+            let Ok((mut guard, _)) = cvar.wait_timeout(guard, interval) else {
+                return;
+            };
+            let (dirty, stopped) = (guard.dirty, guard.stopped);
+            guard.dirty = false;
+
+            // don't hold up writers while we wait on the disk
+            drop(guard);
+            if dirty {
+                Flusher::flush(file_path);
+            }
+            if stopped {
+                return;
+            }
+        }
+    }
+
+    fn flush(file_path: &str) {
+        // any fd to the file will do. fsync flushes the file, not just what went through this
+        // particular fd
+        match fcntl::open(file_path, OFlag::O_RDONLY, Mode::empty()) {
+            Ok(fd) => {
+                if let Err(err) = unistd::fdatasync(fd.as_fd()) {
+                    eprintln!("error flushing {file_path}: {err}");
+                }
+            }
+            Err(err) => eprintln!("error opening {file_path} to flush it: {err}"),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.state;
+        if let Ok(mut guard) = lock.lock() {
+            guard.stopped = true;
+        }
+        cvar.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{error, time};
+
+use crate::disk::file::FileInfo;
+use crate::disk::map::{Page, WriteBatch};
+use crate::disk::reader;
+
+// the version and expiry time of a key's latest entry. that's all a batch needs to know to decide
+// whether its ops apply
+#[derive(Clone, Copy)]
+pub struct Stamp {
+    pub version: u64,
+    pub expires_at: u64,
+}
+
+impl Stamp {
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
+
+// what a committed batch reports back to whoever queued it: how many bytes it appended and the
+// last version it handed out
+pub struct Written {
+    pub bytes: usize,
+    pub version: u64,
+}
+
+// how much space a map takes up on disk, and how much of that is taken up by entries that were
+// overwritten, deleted or have expired. `dead_bytes` is `None` for engines that can't tell
+// without reading everything
+pub struct Usage {
+    pub bytes: usize,
+    pub dead_bytes: Option<usize>,
+}
+
+// what the space a map takes up on disk goes to. dead entries are puts that were overwritten,
+// deleted or have expired, and tombstones. dead bytes are everything on disk that isn't a live
+// entry
+#[derive(Default)]
+pub struct Stats {
+    pub live_keys: usize,
+    pub live_bytes: usize,
+    pub key_bytes: usize,
+    pub value_bytes: usize,
+    pub dead_entries: usize,
+    pub dead_bytes: usize,
+}
+
+impl Stats {
+    pub fn count_live(&mut self, entry: &reader::Entry) {
+        self.live_keys += 1;
+        self.live_bytes += entry.len;
+        self.key_bytes += entry.key.len();
+        self.value_bytes += entry.value.len();
+    }
+
+    // the share of the bytes on disk that compacting would get back
+    pub fn fragmentation(&self) -> f64 {
+        Stats::ratio(self.dead_bytes, self.live_bytes + self.dead_bytes)
+    }
+
+    pub fn average_key_size(&self) -> f64 {
+        Stats::ratio(self.key_bytes, self.live_keys)
+    }
+
+    pub fn average_value_size(&self) -> f64 {
+        Stats::ratio(self.value_bytes, self.live_keys)
+    }
+
+    fn ratio(n: usize, total: usize) -> f64 {
+        match total {
+            0 => 0.0,
+            _ => n as f64 / total as f64,
+        }
+    }
+}
+
+// the files a map is kept in, and how many keys it has. keys that expired but haven't been
+// deleted yet are counted too
+pub struct Info {
+    pub files: Vec<FileInfo>,
+    pub entries: usize,
+}
+
+pub type Outcome = Result<Written, Box<dyn error::Error + Send + Sync>>;
+
+// how a map lays out its entries on disk. `DiskMap` checks arguments and runs group commit, so an
+// engine only has to store entries and find them again
+pub trait Engine: Send + Sync {
+    // applies a group of batches in order and returns one outcome per batch. only one group
+    // commit leader calls this at a time
+    fn write_group(&self, batches: Vec<WriteBatch>) -> Vec<Outcome>;
+
+    // the latest entry for `k`, unless it was deleted or has expired by `now`
+    fn get(&self, k: &[u8], now: u64) -> Result<Option<reader::Entry>, Box<dyn error::Error>>;
+
+    // up to `limit` live keys from `start` up to but not including `end`, in order
+    fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        now: u64,
+    ) -> Result<Page, Box<dyn error::Error>>;
+
+    // up to `limit` keys that have expired by `now` but still take up space
+    fn expired_keys(&self, limit: usize, now: u64) -> Result<Vec<Vec<u8>>, Box<dyn error::Error>>;
+
+    fn usage(&self) -> Result<Usage, Box<dyn error::Error>>;
+
+    // reads the whole map to sort live entries from dead ones, as of `now`
+    fn stats(&self, now: u64) -> Result<Stats, Box<dyn error::Error>>;
+
+    // drops everything that isn't live anymore. returns how many bytes are left
+    fn compact(&self) -> Result<usize, Box<dyn error::Error>>;
+
+    fn info(&self) -> Result<Info, Box<dyn error::Error>>;
+
+    // hands every live entry to `visit` in key order, all as of the same moment. writers wait
+    // until it's done, so nothing changes underneath it
+    fn snapshot(&self, now: u64, visit: &mut Visit) -> Result<(), Box<dyn error::Error>>;
+}
+
+pub type Visit<'a> = dyn FnMut(&reader::Entry) -> Result<(), Box<dyn error::Error>> + 'a;
+
+// milliseconds since the unix epoch, which is what expiry times are stored as
+pub fn now() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+pub fn expiry(ttl: time::Duration) -> u64 {
+    now().saturating_add(ttl.as_millis() as u64)
+}
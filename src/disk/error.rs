@@ -0,0 +1,78 @@
+use std::{error, fmt};
+
+#[derive(Debug)]
+pub enum Error {
+    Corrupt {
+        offset: usize,
+        reason: String,
+    },
+    Truncated {
+        offset: usize,
+    },
+    InvalidHeader {
+        reason: String,
+    },
+    NotFound {
+        key: Vec<u8>,
+    },
+    TooLarge {
+        what: &'static str,
+        size: usize,
+        limit: usize,
+    },
+    // a version of 0 means the key doesn't exist
+    VersionMismatch {
+        key: Vec<u8>,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl Error {
+    // errors from decoding a buffer carry offsets relative to that buffer. this moves them to
+    // where the buffer was read from in the file
+    pub fn shifted(self, base: usize) -> Error {
+        match self {
+            Error::Corrupt { offset, reason } => Error::Corrupt {
+                offset: base + offset,
+                reason,
+            },
+            Error::Truncated { offset } => Error::Truncated {
+                offset: base + offset,
+            },
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Corrupt { offset, reason } => {
+                write!(f, "corrupt entry at offset {offset}: {reason}")
+            }
+            Error::Truncated { offset } => {
+                write!(f, "entry at offset {offset} runs past end of data")
+            }
+            Error::InvalidHeader { reason } => write!(f, "invalid header: {reason}"),
+            Error::NotFound { key } => write!(f, "{} not found", String::from_utf8_lossy(key)),
+            Error::TooLarge { what, size, limit } => {
+                write!(f, "{what} too large: {size} bytes, limit is {limit} bytes")
+            }
+            Error::VersionMismatch {
+                key,
+                expected,
+                actual,
+            } => {
+                let key = String::from_utf8_lossy(key);
+                match (expected, actual) {
+                    (0, _) => write!(f, "{key} already exists at version {actual}"),
+                    (_, 0) => write!(f, "{key} does not exist, expected version {expected}"),
+                    _ => write!(f, "{key} is at version {actual}, expected {expected}"),
+                }
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
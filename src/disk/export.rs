@@ -0,0 +1,464 @@
+use std::{error, fmt, io, iter, str};
+
+// how exported entries are laid out. json lines is one `{"key": ..., "value": ...}` object per
+// line. keys and values that aren't utf-8 go in `key_hex` and `value_hex` instead, as hex. csv
+// has a `key,value` header row, then one row per entry, quoted where it has to be. csv fields
+// are bytes, so anything goes in them as it is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    JsonLines,
+    Csv,
+}
+
+impl str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "jsonl" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("expected jsonl or csv, got {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::JsonLines => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+type KeyValue = (Vec<u8>, Vec<u8>);
+
+// whatever has to come before the first entry
+pub fn write_header(format: Format, out: &mut impl io::Write) -> io::Result<()> {
+    match format {
+        Format::JsonLines => Ok(()),
+        Format::Csv => out.write_all(b"key,value\r\n"),
+    }
+}
+
+pub fn write_entry(format: Format, out: &mut impl io::Write, k: &[u8], v: &[u8]) -> io::Result<()> {
+    match format {
+        Format::JsonLines => {
+            let mut line = String::from("{");
+            push_json_field(&mut line, "key", k);
+            line.push_str(", ");
+            push_json_field(&mut line, "value", v);
+            line.push_str("}\n");
+            out.write_all(line.as_bytes())
+        }
+        Format::Csv => {
+            write_csv_field(out, k)?;
+            out.write_all(b",")?;
+            write_csv_field(out, v)?;
+            out.write_all(b"\r\n")
+        }
+    }
+}
+
+fn push_json_field(line: &mut String, name: &str, bytes: &[u8]) {
+    let Ok(s) = str::from_utf8(bytes) else {
+        line.push_str(&format!("\"{name}_hex\": \""));
+        for byte in bytes {
+            line.push_str(&format!("{byte:02x}"));
+        }
+        line.push('"');
+        return;
+    };
+
+    line.push_str(&format!("\"{name}\": \""));
+    for c in s.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => line.push_str(&format!("\\u{:04x}", c as u32)),
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+fn write_csv_field(out: &mut impl io::Write, bytes: &[u8]) -> io::Result<()> {
+    if !bytes
+        .iter()
+        .any(|b| matches!(b, b',' | b'"' | b'\r' | b'\n'))
+    {
+        return out.write_all(bytes);
+    }
+    out.write_all(b"\"")?;
+    for chunk in bytes.split_inclusive(|b| *b == b'"') {
+        out.write_all(chunk)?;
+        if chunk.ends_with(b"\"") {
+            out.write_all(b"\"")?;
+        }
+    }
+    out.write_all(b"\"")
+}
+
+// reads back what `write_entry` wrote, one key and value at a time. errors say which line they
+// are on
+pub struct Reader<R: io::BufRead> {
+    format: Format,
+    input: R,
+    line: usize,
+}
+
+impl<R: io::BufRead> Reader<R> {
+    pub fn new(format: Format, input: R) -> Reader<R> {
+        Reader {
+            format,
+            input,
+            line: 0,
+        }
+    }
+
+    // the next entry, or `None` once the input runs out
+    fn read_entry(&mut self) -> Result<Option<KeyValue>, Box<dyn error::Error>> {
+        loop {
+            let start_line = self.line + 1;
+            let Some(record) = self.read_record()? else {
+                return Ok(None);
+            };
+            if record.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+
+            let entry = match self.format {
+                Format::JsonLines => parse_json_line(&record),
+                Format::Csv => match parse_csv_record(&record) {
+                    // the header row
+                    Ok((k, v)) if start_line == 1 && k == b"key" && v == b"value" => continue,
+                    result => result,
+                },
+            };
+            return entry
+                .map(Some)
+                .map_err(|err| format!("line {start_line}: {err}").into());
+        }
+    }
+
+    // one line, or for csv as many lines as it takes to close every quote. line endings are
+    // left out
+    fn read_record(&mut self) -> Result<Option<Vec<u8>>, Box<dyn error::Error>> {
+        let mut record = Vec::<u8>::new();
+        loop {
+            let n = self.input.read_until(b'\n', &mut record)?;
+            if n == 0 {
+                return match record.is_empty() {
+                    true => Ok(None),
+                    false => Err(format!("line {}: quote is never closed", self.line).into()),
+                };
+            }
+            self.line += 1;
+
+            let quotes = record.iter().filter(|b| **b == b'"').count();
+            if self.format == Format::JsonLines || quotes.is_multiple_of(2) {
+                break;
+            }
+        }
+        if record.ends_with(b"\n") {
+            record.pop();
+        }
+        if record.ends_with(b"\r") {
+            record.pop();
+        }
+        Ok(Some(record))
+    }
+}
+
+impl<R: io::BufRead> Iterator for Reader<R> {
+    type Item = Result<KeyValue, Box<dyn error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+// only takes objects with `key` or `key_hex` and `value` or `value_hex`, all of them strings
+fn parse_json_line(line: &[u8]) -> Result<KeyValue, String> {
+    let line = str::from_utf8(line).map_err(|_| "not utf-8")?;
+    let mut chars = line.chars().peekable();
+
+    let mut key = None;
+    let mut value = None;
+    skip_json_whitespace(&mut chars);
+    if chars.next() != Some('{') {
+        return Err("expected an object".into());
+    }
+    skip_json_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_none() {
+        loop {
+            skip_json_whitespace(&mut chars);
+            let name = parse_json_string(&mut chars)?;
+            skip_json_whitespace(&mut chars);
+            if chars.next() != Some(':') {
+                return Err(format!("expected : after \"{name}\""));
+            }
+            skip_json_whitespace(&mut chars);
+            let field = parse_json_string(&mut chars)?;
+            match name.as_str() {
+                "key" => key = Some(field.into_bytes()),
+                "value" => value = Some(field.into_bytes()),
+                "key_hex" => key = Some(decode_hex(&field)?),
+                "value_hex" => value = Some(decode_hex(&field)?),
+                _ => return Err(format!("unknown field \"{name}\"")),
+            }
+            skip_json_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                _ => return Err("expected , or } after a field".into()),
+            }
+        }
+    }
+    skip_json_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return Err("unexpected text after the object".into());
+    }
+
+    Ok((key.ok_or("missing key")?, value.ok_or("missing value")?))
+}
+
+fn skip_json_whitespace(chars: &mut iter::Peekable<str::Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn parse_json_string(chars: &mut impl Iterator<Item = char>) -> Result<String, String> {
+    if chars.next() != Some('"') {
+        return Err("expected a string".into());
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next().ok_or("string is never closed")? {
+            '"' => return Ok(s),
+            '\\' => match chars.next().ok_or("string is never closed")? {
+                '"' => s.push('"'),
+                '\\' => s.push('\\'),
+                '/' => s.push('/'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'n' => s.push('\n'),
+                'r' => s.push('\r'),
+                't' => s.push('\t'),
+                'u' => {
+                    let high = parse_json_code_unit(chars)?;
+                    // characters outside the basic plane come as a pair of surrogates
+                    let code_point = match high {
+                        0xd800..=0xdbff => {
+                            if chars.next() != Some('\\') || chars.next() != Some('u') {
+                                return Err("unpaired surrogate".into());
+                            }
+                            let low = parse_json_code_unit(chars)?;
+                            if !(0xdc00..=0xdfff).contains(&low) {
+                                return Err("unpaired surrogate".into());
+                            }
+                            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                        }
+                        code_point => code_point,
+                    };
+                    s.push(char::from_u32(code_point).ok_or("unpaired surrogate")?);
+                }
+                c => return Err(format!("unknown escape \\{c}")),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+fn parse_json_code_unit(chars: &mut impl Iterator<Item = char>) -> Result<u32, String> {
+    let hex: String = chars.take(4).collect();
+    match hex.len() == 4 {
+        true => u32::from_str_radix(&hex, 16).map_err(|_| format!("bad escape \\u{hex}")),
+        false => Err("string is never closed".into()),
+    }
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("bad hex {s}"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).map_err(|_| format!("bad hex {s}")))
+        .collect()
+}
+
+// exactly two fields. a quoted field can hold anything, with quotes doubled
+fn parse_csv_record(record: &[u8]) -> Result<KeyValue, String> {
+    let mut fields = Vec::<Vec<u8>>::new();
+    let mut bytes = record.iter().copied().peekable();
+    loop {
+        let mut field = Vec::<u8>::new();
+        if bytes.next_if_eq(&b'"').is_some() {
+            loop {
+                match bytes.next() {
+                    Some(b'"') if bytes.next_if_eq(&b'"').is_some() => field.push(b'"'),
+                    Some(b'"') => break,
+                    Some(byte) => field.push(byte),
+                    None => return Err("quote is never closed".into()),
+                }
+            }
+        } else {
+            while let Some(byte) = bytes.next_if(|b| *b != b',') {
+                if byte == b'"' {
+                    return Err("quote in the middle of an unquoted field".into());
+                }
+                field.push(byte);
+            }
+        }
+        fields.push(field);
+
+        match bytes.next() {
+            Some(b',') => continue,
+            None => break,
+            Some(_) => return Err("expected , after a quoted field".into()),
+        }
+    }
+
+    match <[Vec<u8>; 2]>::try_from(fields) {
+        Ok([k, v]) => Ok((k, v)),
+        Err(fields) => Err(format!("expected 2 fields, got {}", fields.len())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<KeyValue> {
+        vec![
+            (b"plain".to_vec(), b"value".to_vec()),
+            (b"a,b".to_vec(), b"say \"hi\"\r\nbye".to_vec()),
+            (
+                b"tab\there".to_vec(),
+                "\\ \u{1} \u{1f600}".as_bytes().to_vec(),
+            ),
+            (vec![0xff, 0, b'\n'], vec![]),
+            (vec![], vec![0xfe, b'"']),
+        ]
+    }
+
+    fn export(format: Format, entries: &[KeyValue]) -> Vec<u8> {
+        let mut out = Vec::<u8>::new();
+        write_header(format, &mut out).unwrap();
+        for (k, v) in entries {
+            write_entry(format, &mut out, k, v).unwrap();
+        }
+        out
+    }
+
+    fn import(format: Format, input: &[u8]) -> Result<Vec<KeyValue>, Box<dyn error::Error>> {
+        Reader::new(format, input).collect()
+    }
+
+    #[test]
+    fn everything_comes_back() {
+        for format in [Format::JsonLines, Format::Csv] {
+            let out = export(format, &entries());
+            assert_eq!(import(format, &out).unwrap(), entries(), "{format}");
+        }
+    }
+
+    #[test]
+    fn json_lines_use_hex_only_for_what_isnt_utf8() {
+        let out = export(Format::JsonLines, &entries());
+        let lines: Vec<&str> = str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines[0], r#"{"key": "plain", "value": "value"}"#);
+        assert_eq!(lines[1], r#"{"key": "a,b", "value": "say \"hi\"\r\nbye"}"#);
+        assert_eq!(lines[3], r#"{"key_hex": "ff000a", "value": ""}"#);
+        assert_eq!(lines[4], r#"{"key": "", "value_hex": "fe22"}"#);
+    }
+
+    #[test]
+    fn json_lines_written_by_hand_are_read() {
+        let input = concat!(
+            "  { \"value\" : \"\\u00e9\\ud83d\\ude00\\/\" , \"key\":\"k\" }  \n",
+            "\n",
+            "{\"key_hex\": \"00\", \"value\": \"\"}\r\n",
+        );
+        assert_eq!(
+            import(Format::JsonLines, input.as_bytes()).unwrap(),
+            [
+                (b"k".to_vec(), "\u{e9}\u{1f600}/".as_bytes().to_vec()),
+                (vec![0], vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_json_lines_say_where_they_are() {
+        for (input, err) in [
+            ("[]", "expected an object"),
+            ("{\"key\": \"k\"}", "missing value"),
+            (
+                "{\"key\": \"k\", \"value\": \"v\", \"x\": \"\"}",
+                "unknown field",
+            ),
+            ("{\"key\": \"k\", \"value\": 1}", "expected a string"),
+            ("{\"key_hex\": \"f\", \"value\": \"v\"}", "bad hex"),
+            (
+                "{\"key\": \"\\ud800\", \"value\": \"v\"}",
+                "unpaired surrogate",
+            ),
+            ("{\"key\": \"k\", \"value\": \"v\"} x", "unexpected text"),
+            ("{\"key\": \"k", "never closed"),
+        ] {
+            let input = format!("{{\"key\": \"ok\", \"value\": \"ok\"}}\n{input}\n");
+            let err_text = import(Format::JsonLines, input.as_bytes())
+                .unwrap_err()
+                .to_string();
+            assert!(err_text.starts_with("line 2: "), "{err_text}");
+            assert!(err_text.contains(err), "{err_text}");
+        }
+    }
+
+    #[test]
+    fn csv_quotes_only_what_it_has_to() {
+        let out = export(Format::Csv, &entries()[..2]);
+        assert_eq!(
+            out,
+            b"key,value\r\nplain,value\r\n\"a,b\",\"say \"\"hi\"\"\r\nbye\"\r\n"
+        );
+    }
+
+    #[test]
+    fn csv_header_is_optional() {
+        let input = b"k,v\nkey,value\n";
+        assert_eq!(
+            import(Format::Csv, input).unwrap(),
+            [
+                (b"k".to_vec(), b"v".to_vec()),
+                (b"key".to_vec(), b"value".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_csv_says_where_it_is() {
+        for (line, input, err) in [
+            (3, "a,b,c\n", "expected 2 fields, got 3"),
+            (3, "a\n", "expected 2 fields, got 1"),
+            (3, "a\"b\",c\n", "quote in the middle"),
+            (3, "\"a\"b,c\n", "expected , after a quoted field"),
+            // a quote that is never closed only shows once the input runs out
+            (4, "\"a,b\nc\n", "quote is never closed"),
+        ] {
+            let input = format!("key,value\nk,v\n{input}");
+            let err_text = import(Format::Csv, input.as_bytes())
+                .unwrap_err()
+                .to_string();
+            assert!(
+                err_text.starts_with(&format!("line {line}: ")),
+                "{err_text}"
+            );
+            assert!(err_text.contains(err), "{err_text}");
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use nix::{fcntl, fcntl::OFlag, libc, sys::stat, sys::stat::Mode, unistd};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{error, io, os, path};
+
+pub fn mode() -> Mode {
+    Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IROTH
+}
+
+// opens `file_path` and locks it. if someone renamed a new file over `file_path` while we were
+// waiting for the lock (e.g. a compaction), the lock we got is on a file that nobody will read
+// again, so we drop it and try again with the new one
+pub fn open_locked(
+    file_path: &str,
+    arg: fcntl::FlockArg,
+) -> Result<fcntl::Flock<OwnedFd>, Box<dyn error::Error>> {
+    loop {
+        let fd = fcntl::open(file_path, OFlag::O_RDWR | OFlag::O_CREAT, mode())?;
+        let lock = fcntl::Flock::lock(fd, arg).map_err(|(_, e)| e)?;
+
+        let locked = stat::fstat(lock.as_fd())?;
+        match stat::stat(file_path) {
+            Ok(current) if current.st_dev == locked.st_dev && current.st_ino == locked.st_ino => {
+                return Ok(lock);
+            }
+            Ok(_) | Err(nix::errno::Errno::ENOENT) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+pub fn read_at(
+    fd: os::fd::BorrowedFd,
+    offset: usize,
+    len: usize,
+) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    let mut buf = vec![0u8; len];
+    let mut read = 0;
+    while read < buf.len() {
+        let n = unsafe {
+            libc::pread(
+                fd.as_raw_fd(),
+                buf[read..].as_mut_ptr().cast(),
+                buf.len() - read,
+                (offset + read) as i64,
+            )
+        };
+        if n == -1 {
+            return Err(io::Error::last_os_error().into());
+        } else if n == 0 {
+            return Err(format!("unexpected end of file at {}", offset + read).into());
+        }
+        read += n as usize;
+    }
+    Ok(buf)
+}
+
+pub fn write_all(fd: os::fd::BorrowedFd, buf: &[u8]) -> Result<usize, Box<dyn error::Error>> {
+    let mut written = 0;
+    while written < buf.len() {
+        let rest = &buf[written..];
+        let n = unsafe { libc::write(fd.as_raw_fd(), rest.as_ptr().cast(), rest.len()) };
+        if n == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        written += n as usize;
+    }
+    Ok(written)
+}
+
+// appends `buf` to the file `fd` has open, which is `end` bytes long, then calls `flush`. if
+// either fails, whatever part of `buf` made it into the file is cut back off. otherwise the next
+// append would land after a torn record, which opening the file takes for corruption rather than
+// a torn tail. if cutting it off fails too, `torn` is set and every later append refuses to go
+// ahead, until the file is opened again and recovered
+pub fn append(
+    fd: os::fd::BorrowedFd,
+    end: usize,
+    buf: &[u8],
+    torn: &AtomicBool,
+    flush: impl FnOnce() -> Result<(), Box<dyn error::Error>>,
+) -> Result<(), Box<dyn error::Error>> {
+    if torn.load(Ordering::Relaxed) {
+        return Err("an earlier write failed and couldn't be cut back off. reopen the map".into());
+    }
+    let Err(err) = write_all(fd, buf).and_then(|_| flush()) else {
+        return Ok(());
+    };
+    if unsafe { libc::ftruncate(fd.as_raw_fd(), end as i64) } == -1 {
+        torn.store(true, Ordering::Relaxed);
+        let truncate_err = io::Error::last_os_error();
+        return Err(format!("{err}. cutting the write back off failed too: {truncate_err}").into());
+    }
+    Err(err)
+}
+
+// replaces the contents of `file_path` with `buf` in a way that survives a crash at any point:
+// the new contents go to a sibling file, get fsynced, and are renamed over the original. anyone
+// still holding an fd to the original file keeps reading the old contents
+pub fn replace_atomically(file_path: &str, buf: &[u8]) -> Result<usize, Box<dyn error::Error>> {
+    let tmp_path = format!("{file_path}.tmp");
+    let fd = fcntl::open(
+        tmp_path.as_str(),
+        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC,
+        mode(),
+    )?;
+
+    let n = write_all(fd.as_fd(), buf)?;
+    unistd::fsync(fd.as_fd())?;
+    drop(fd);
+
+    fcntl::renameat(
+        fcntl::AT_FDCWD,
+        tmp_path.as_str(),
+        fcntl::AT_FDCWD,
+        file_path,
+    )?;
+    fsync_parent(file_path)?;
+
+    Ok(n)
+}
+
+// a rename is only durable once the directory that holds the file is fsynced too
+pub fn fsync_parent(file_path: &str) -> Result<(), Box<dyn error::Error>> {
+    let dir = match path::Path::new(file_path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => path::Path::new("."),
+    };
+    let fd = fcntl::open(dir, OFlag::O_RDONLY | OFlag::O_DIRECTORY, Mode::empty())?;
+    unistd::fsync(fd.as_fd())?;
+    Ok(())
+}
+
+// reads from the current position of `fd` to the end of the file
+pub fn read_all(fd: os::fd::BorrowedFd) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    let mut buf = [0u8; 1024];
+    let mut v: Vec<u8> = Vec::new();
+    loop {
+        let n = unistd::read(fd, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        v.extend_from_slice(&buf[..n]);
+    }
+    Ok(v)
+}
+
+// what the filesystem says about one of the files a map is kept in
+pub struct FileInfo {
+    pub path: String,
+    pub size: usize,
+    pub inode: u64,
+    // seconds since the unix epoch
+    pub modified: i64,
+    // how many 512-byte blocks are allocated to the file, which for a sparse file is less than
+    // its size needs
+    pub blocks: u64,
+}
+
+impl FileInfo {
+    pub fn new(path: &str, st: stat::FileStat) -> FileInfo {
+        FileInfo {
+            path: String::from(path),
+            size: st.st_size as usize,
+            inode: st.st_ino,
+            modified: st.st_mtime,
+            blocks: st.st_blocks as u64,
+        }
+    }
+}
+
+// an empty directory of its own for the test called `name`
+#[cfg(test)]
+pub fn test_dir(name: &str) -> String {
+    let dir = format!(
+        "{}/diskmap-test-{}-{name}",
+        std::env::temp_dir().display(),
+        std::process::id()
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_appends_are_cut_back_off() {
+        let file_path = format!("{}/file", test_dir("failed-appends"));
+        let flags = OFlag::O_CREAT | OFlag::O_RDWR | OFlag::O_APPEND;
+        let fd = fcntl::open(file_path.as_str(), flags, mode()).unwrap();
+        let torn = AtomicBool::new(false);
+
+        append(fd.as_fd(), 0, b"kept", &torn, || Ok(())).unwrap();
+        let failed = append(fd.as_fd(), 4, b"cut off", &torn, || {
+            Err("flush failed".into())
+        });
+        assert_eq!(failed.unwrap_err().to_string(), "flush failed");
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"kept");
+        assert!(!torn.load(Ordering::Relaxed));
+
+        // nothing more is appended once a torn write couldn't be cut off
+        torn.store(true, Ordering::Relaxed);
+        assert!(append(fd.as_fd(), 4, b"refused", &torn, || Ok(())).is_err());
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"kept");
+    }
+}
@@ -0,0 +1,502 @@
+use nix::{errno, fcntl, fcntl::OFlag, libc, sys::stat, sys::stat::Mode, unistd};
+use std::collections::HashMap;
+use std::os::fd::AsFd;
+use std::{error, fmt};
+
+use crate::disk::hint::HintFile;
+use crate::disk::{engine, error::Error, file, header, reader};
+
+// what became of a record, going by everything after it in the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    // the latest put or tombstone for its key
+    Live,
+    // a later put or tombstone for the same key replaced it
+    Overwritten,
+    // the latest put for its key, but past its expiry time
+    Expired,
+    // marked dead in place, which deletes did before format version 6
+    Dead,
+    // part of a batch that never committed
+    Uncommitted,
+    // its sizes add up but its checksum doesn't
+    Corrupt,
+    // batch markers and sequence entries, which aren't about any one key
+    Marker,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Live => write!(f, "live"),
+            Status::Overwritten => write!(f, "overwritten"),
+            Status::Expired => write!(f, "expired"),
+            Status::Dead => write!(f, "dead"),
+            Status::Uncommitted => write!(f, "uncommitted"),
+            Status::Corrupt => write!(f, "corrupt"),
+            Status::Marker => write!(f, "marker"),
+        }
+    }
+}
+
+// one record of a map file, as found at `offset`. a corrupt record has no kind, key or value
+// that can be trusted, only a length
+pub struct Record {
+    pub offset: usize,
+    pub kind: &'static str,
+    pub status: Status,
+    pub len: usize,
+    pub key: Vec<u8>,
+    pub value_len: usize,
+    pub version: u64,
+    pub expires_at: u64,
+    entry: Option<reader::Entry>,
+}
+
+impl Record {
+    fn new(entry: reader::Entry, status: Status) -> Record {
+        let kind = match entry.kind {
+            reader::Kind::Dead => "dead",
+            reader::Kind::Put => "put",
+            reader::Kind::Tombstone => "tombstone",
+            reader::Kind::BatchBegin => "batch begin",
+            reader::Kind::BatchCommit => "batch commit",
+            reader::Kind::Sequence => "sequence",
+        };
+        Record {
+            offset: entry.offset,
+            kind,
+            status,
+            len: entry.len,
+            key: entry.key.clone(),
+            value_len: entry.value.len(),
+            version: entry.version,
+            expires_at: entry.expires_at,
+            entry: Some(entry),
+        }
+    }
+
+    fn corrupt(offset: usize, len: usize) -> Record {
+        Record {
+            offset,
+            kind: "?",
+            status: Status::Corrupt,
+            len,
+            key: Vec::new(),
+            value_len: 0,
+            version: 0,
+            expires_at: 0,
+            entry: None,
+        }
+    }
+}
+
+// everything `check` found out about a file
+pub struct Report {
+    pub format_version: u16,
+    pub records: Vec<Record>,
+    // everything wrong with the file besides a torn tail
+    pub problems: Vec<String>,
+    // where a write that never finished starts, and why it counts as one. opening the map cuts
+    // the file off there
+    pub torn_tail: Option<(usize, String)>,
+}
+
+// reads the map file at `path` without opening the map, so it works on files the map refuses
+// to open and doesn't need the server stopped. a sealed segment's hint file is checked against
+// the segment too
+pub fn check(path: &str) -> Result<Report, Box<dyn error::Error>> {
+    if stat::stat(path)?.st_mode & libc::S_IFMT == libc::S_IFDIR {
+        return Err(format!(
+            "{path} is a directory. point at a log segment, or the wal of an lsm map"
+        )
+        .into());
+    }
+    let fd = fcntl::open(path, OFlag::O_RDONLY, Mode::empty())?;
+    let data = file::read_all(fd.as_fd())?;
+    let (format_version, start) = match header::Header::from_bytes(&data)? {
+        Some(h) => (h.version, header::HEADER_LEN),
+        None => (0, 0),
+    };
+
+    let mut report = Report {
+        format_version,
+        records: Vec::new(),
+        problems: Vec::new(),
+        torn_tail: None,
+    };
+    report.read_records(&data, start);
+    report.resolve(engine::now());
+    report.check_hints(path, data.len());
+    Ok(report)
+}
+
+impl Report {
+    // whether there was nothing wrong with the file at all
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty() && self.torn_tail.is_none()
+    }
+
+    // writes what is live in the file to a new file at `path`, in the current format, for it to
+    // take the checked file's place. what's corrupt, torn or never committed is left out
+    pub fn repair(&self, path: &str) -> Result<usize, Box<dyn error::Error>> {
+        let entries = self
+            .records
+            .iter()
+            .filter_map(|record| record.entry.as_ref());
+        let mut last_version = entries.map(|entry| entry.version).max().unwrap_or(0);
+
+        // entries too old to have a version get one, like they do when the map is migrated
+        let mut live = Vec::<reader::Entry>::new();
+        for record in &self.records {
+            if let (Status::Live, Some(entry)) = (record.status, &record.entry) {
+                let mut entry = entry.clone();
+                if entry.version == 0 {
+                    last_version += 1;
+                    entry.version = last_version;
+                }
+                live.push(entry);
+            }
+        }
+
+        // the highest version goes first, like it does in a merged segment
+        let mut buf = Vec::<u8>::from(header::Header::new().to_bytes());
+        buf.extend_from_slice(&reader::Entry::sequence(last_version).to_bytes());
+        for entry in live {
+            buf.extend_from_slice(&entry.to_bytes());
+        }
+
+        // never write over anything, least of all the file being repaired
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL;
+        let fd = match fcntl::open(path, flags, file::mode()) {
+            Ok(fd) => fd,
+            Err(errno::Errno::EEXIST) => return Err(format!("{path} already exists").into()),
+            Err(err) => return Err(err.into()),
+        };
+        file::write_all(fd.as_fd(), &buf)?;
+        unistd::fsync(fd.as_fd())?;
+        Ok(buf.len())
+    }
+
+    // walks every record from `start`. unlike `ReadResult`, it doesn't stop at a corrupt entry
+    // as long as the entry's sizes say where the next one starts
+    fn read_records(&mut self, data: &[u8], start: usize) {
+        let mut offset = start;
+        // where the batch being read began, and its records. they count as uncommitted until
+        // its commit marker
+        let mut batch: Option<(usize, Vec<usize>)> = None;
+        while offset < data.len() {
+            let entry = match reader::Entry::from_versioned_bytes(data, offset, self.format_version)
+            {
+                Ok(entry) => entry,
+                Err(err @ Error::Truncated { .. }) => {
+                    let start = batch.as_ref().map_or(offset, |(start, _)| *start);
+                    self.torn_tail = Some((start, err.to_string()));
+                    return;
+                }
+                Err(err @ Error::Corrupt { .. }) => {
+                    match reader::Entry::claimed_len(data, offset, self.format_version) {
+                        Some(len) if offset + len == data.len() => {
+                            let start = batch.as_ref().map_or(offset, |(start, _)| *start);
+                            self.torn_tail = Some((start, err.to_string()));
+                            return;
+                        }
+                        Some(len) => {
+                            self.problems.push(err.to_string());
+                            self.records.push(Record::corrupt(offset, len));
+                            offset += len;
+                            continue;
+                        }
+                        None => {
+                            self.problems.push(format!(
+                                "{err}. nothing says where the next entry starts, so the rest \
+                                 of the file is unchecked"
+                            ));
+                            return;
+                        }
+                    }
+                }
+                Err(err) => {
+                    self.problems.push(err.to_string());
+                    return;
+                }
+            };
+            offset += entry.len;
+
+            let status = match entry.kind {
+                reader::Kind::Dead => Status::Dead,
+                reader::Kind::Put | reader::Kind::Tombstone => match &mut batch {
+                    Some((_, records)) => {
+                        records.push(self.records.len());
+                        Status::Uncommitted
+                    }
+                    None => Status::Live,
+                },
+                reader::Kind::BatchBegin => {
+                    // a batch that begins before the last one committed never will
+                    if let Some((start, _)) = batch.replace((entry.offset, Vec::new())) {
+                        self.problems
+                            .push(format!("batch at offset {start} was never committed"));
+                    }
+                    Status::Marker
+                }
+                reader::Kind::BatchCommit => {
+                    match batch.take() {
+                        Some((_, records)) => {
+                            for i in records {
+                                self.records[i].status = Status::Live;
+                            }
+                        }
+                        None => self.problems.push(format!(
+                            "commit marker at offset {} has no batch to commit",
+                            entry.offset
+                        )),
+                    }
+                    Status::Marker
+                }
+                reader::Kind::Sequence => Status::Marker,
+            };
+            self.records.push(Record::new(entry, status));
+        }
+
+        if let Some((start, _)) = batch {
+            self.torn_tail = Some((
+                start,
+                format!("batch at offset {start} was never committed"),
+            ));
+        }
+    }
+
+    // only the last committed put or tombstone of each key stays live
+    fn resolve(&mut self, now: u64) {
+        let mut latest = HashMap::<&[u8], usize>::new();
+        let mut overwritten = Vec::<usize>::new();
+        for (i, record) in self.records.iter().enumerate() {
+            if record.status == Status::Live
+                && let Some(earlier) = latest.insert(&record.key, i)
+            {
+                overwritten.push(earlier);
+            }
+        }
+        let expired: Vec<usize> = latest
+            .into_values()
+            .filter(|i| {
+                let record = &self.records[*i];
+                record.kind == "put" && record.expires_at != 0 && record.expires_at <= now
+            })
+            .collect();
+
+        for i in overwritten {
+            self.records[i].status = Status::Overwritten;
+        }
+        for i in expired {
+            self.records[i].status = Status::Expired;
+        }
+    }
+
+    // a hint has to point at the start of an entry for the same key, and no two hints can
+    // cover the same bytes
+    fn check_hints(&mut self, path: &str, size: usize) {
+        let hint_path = format!("{path}.hint");
+        let hint_file = match HintFile::load(&hint_path) {
+            Ok(Some(hint_file)) => hint_file,
+            Ok(None) => return,
+            Err(err) => {
+                self.problems.push(format!("{hint_path}: {err}"));
+                return;
+            }
+        };
+
+        if hint_file.segment_size != size {
+            self.problems.push(format!(
+                "{hint_path} is for a segment of {} bytes, but {path} is {size} bytes",
+                hint_file.segment_size
+            ));
+        }
+
+        let by_offset: HashMap<usize, &Record> = self
+            .records
+            .iter()
+            .map(|record| (record.offset, record))
+            .collect();
+        let mut hints = hint_file.hints;
+        hints.sort_by_key(|hint| hint.offset);
+        let mut problems = Vec::<String>::new();
+        for (i, hint) in hints.iter().enumerate() {
+            match by_offset.get(&hint.offset) {
+                Some(record) if record.key == hint.key && record.len == hint.len => {}
+                Some(_) => problems.push(format!(
+                    "{hint_path}: hint at offset {} doesn't match the entry there",
+                    hint.offset
+                )),
+                None => problems.push(format!(
+                    "{hint_path}: hint at offset {} doesn't point at the start of an entry",
+                    hint.offset
+                )),
+            }
+            if let Some(next) = hints.get(i + 1)
+                && hint.offset + hint.len > next.offset
+            {
+                problems.push(format!(
+                    "{hint_path}: hints at offsets {} and {} overlap",
+                    hint.offset, next.offset
+                ));
+            }
+        }
+        self.problems.extend(problems);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::durability::Durability;
+    use crate::disk::engine::Engine;
+    use crate::disk::hint::Hint;
+    use crate::disk::log::LogEngine;
+    use std::fs;
+
+    // a put that was overwritten, a key that was deleted, one that expired and two that are
+    // still there
+    fn entries() -> Vec<reader::Entry> {
+        vec![
+            reader::Entry::new(b"a", b"1", 1, 0),
+            reader::Entry::new(b"b", b"2", 2, 0),
+            reader::Entry::new(b"a", b"3", 3, 0),
+            reader::Entry::tombstone(b"b", 4),
+            reader::Entry::new(b"c", b"5", 5, 1),
+            reader::Entry::new(b"d", b"6", 6, 0),
+        ]
+    }
+
+    fn map_file(entries: &[reader::Entry]) -> Vec<u8> {
+        let mut data = header::Header::new().to_bytes().to_vec();
+        for entry in entries {
+            data.extend_from_slice(&entry.to_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn records_are_listed_with_what_became_of_them() {
+        let path = format!("{}/map", file::test_dir("fsck-records"));
+        let entries = entries();
+        fs::write(&path, map_file(&entries)).unwrap();
+
+        let report = check(&path).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.format_version, header::VERSION);
+        let statuses = [
+            Status::Overwritten,
+            Status::Overwritten,
+            Status::Live,
+            Status::Live,
+            Status::Expired,
+            Status::Live,
+        ];
+        assert_eq!(report.records.len(), entries.len());
+        let mut offset = header::HEADER_LEN;
+        for ((record, entry), status) in report.records.iter().zip(&entries).zip(statuses) {
+            assert_eq!(record.offset, offset);
+            assert_eq!(record.status, status);
+            assert_eq!(record.len, entry.len);
+            assert_eq!(record.key, entry.key);
+            assert_eq!(record.version, entry.version);
+            offset += entry.len;
+        }
+    }
+
+    #[test]
+    fn torn_tail_is_reported_where_it_starts() {
+        let path = format!("{}/map", file::test_dir("fsck-torn"));
+        let mut data = map_file(&entries());
+        let end = data.len();
+        let torn = reader::Entry::new(b"e", b"torn", 7, 0).to_bytes();
+        data.extend_from_slice(&torn[..torn.len() / 2]);
+        fs::write(&path, data).unwrap();
+
+        let report = check(&path).unwrap();
+        assert!(!report.is_clean());
+        assert!(report.problems.is_empty());
+        assert_eq!(report.torn_tail.map(|(offset, _)| offset), Some(end));
+        assert_eq!(report.records.len(), entries().len());
+    }
+
+    #[test]
+    fn corrupt_record_mid_file_is_skipped_over() {
+        let path = format!("{}/map", file::test_dir("fsck-corrupt"));
+        let entries = entries();
+        let mut data = map_file(&entries);
+        data[header::HEADER_LEN + entries[0].len - 1] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let report = check(&path).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.torn_tail.is_none());
+        assert_eq!(report.records.len(), entries.len());
+        assert_eq!(report.records[0].status, Status::Corrupt);
+        assert_eq!(report.records[0].len, entries[0].len);
+        // with the first put gone, the second one is the latest for its key
+        assert_eq!(report.records[2].status, Status::Live);
+    }
+
+    #[test]
+    fn overlapping_hints_are_reported() {
+        let path = format!("{}/map.000001", file::test_dir("fsck-hints"));
+        let entries = entries();
+        let data = map_file(&entries);
+        fs::write(&path, &data).unwrap();
+        let hint = |i: usize, len: usize| Hint {
+            key: entries[i].key.clone(),
+            offset: header::HEADER_LEN + entries[..i].iter().map(|e| e.len).sum::<usize>(),
+            len,
+            version: entries[i].version,
+            expires_at: entries[i].expires_at,
+        };
+        let first = hint(2, entries[2].len + 1);
+        let second = hint(3, entries[3].len);
+        let overlap = format!(
+            "{path}.hint: hints at offsets {} and {} overlap",
+            first.offset, second.offset
+        );
+        let hint_file = HintFile {
+            segment_size: data.len(),
+            last_version: 6,
+            hints: vec![second, first],
+        };
+        hint_file.save(&format!("{path}.hint")).unwrap();
+
+        let report = check(&path).unwrap();
+        assert!(report.problems.contains(&overlap));
+    }
+
+    #[test]
+    fn repaired_copy_holds_exactly_the_live_keys() {
+        let dir = file::test_dir("fsck-repair");
+        let path = format!("{dir}/map");
+        let mut data = map_file(&entries());
+        let torn = reader::Entry::new(b"e", b"torn", 7, 0).to_bytes();
+        data.extend_from_slice(&torn[..torn.len() / 2]);
+        fs::write(&path, data).unwrap();
+
+        let repaired_path = format!("{dir}/repaired");
+        check(&path).unwrap().repair(&repaired_path).unwrap();
+        assert!(check(&path).unwrap().repair(&repaired_path).is_err());
+        assert!(check(&repaired_path).unwrap().is_clean());
+
+        let log = LogEngine::open(&repaired_path, Durability::Always).unwrap();
+        let mut live = Vec::<(Vec<u8>, Vec<u8>)>::new();
+        log.snapshot(engine::now(), &mut |entry| {
+            live.push((entry.key.clone(), entry.value.clone()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            live,
+            [
+                (b"a".to_vec(), b"3".to_vec()),
+                (b"d".to_vec(), b"6".to_vec())
+            ]
+        );
+    }
+}
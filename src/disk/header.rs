@@ -0,0 +1,167 @@
+use crate::disk::{crc, error::Error};
+
+// every map file starts with these bytes, so we never mistake some other file for a map
+pub const MAGIC: [u8; 8] = *b"DISKMAP\0";
+
+// version 0 is the original headerless layout: live byte, 2-byte key length, 2-byte value
+// length, key, value. version 1 adds the header and a checksum to every entry. version 2 stores
+// the key and value lengths as varints. version 3 adds tombstones and batch markers as kinds of
+// entry next to puts. version 4 gives every entry a version, and adds sequence entries. version
+// 5 gives every entry an expiry time. version 6 checksums the kind byte too, now that deletes
+// append a tombstone instead of flipping that byte in place
+pub const VERSION: u16 = 6;
+
+// magic, version, flags and a checksum of the three
+pub const HEADER_LEN: usize = MAGIC.len() + 2 + 2 + 4;
+
+// no flags are defined yet. a file with a flag we don't know about was written by a newer
+// version of diskmap, and we can't promise to read it correctly
+const KNOWN_FLAGS: u16 = 0;
+
+pub struct Header {
+    pub version: u16,
+    pub flags: u16,
+}
+
+impl Header {
+    pub fn new() -> Header {
+        Header {
+            version: VERSION,
+            flags: 0,
+        }
+    }
+
+    // returns `None` if `bytes` doesn't start with the magic bytes at all
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<Header>, Error> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            return Ok(None);
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::Truncated { offset: 0 });
+        }
+
+        let mut offset = MAGIC.len();
+        let version = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        offset += 2;
+        let flags = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        offset += 2;
+
+        let stored_checksum = u32::from_be_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ]);
+        let actual_checksum = crc::checksum(&bytes[..offset]);
+        if stored_checksum != actual_checksum {
+            return Err(Error::Corrupt {
+                offset: 0,
+                reason: format!(
+                    "header checksum mismatch: stored {stored_checksum:08x}, computed {actual_checksum:08x}"
+                ),
+            });
+        }
+
+        if version > VERSION {
+            return Err(Error::InvalidHeader {
+                reason: format!("format version {version} is newer than supported {VERSION}"),
+            });
+        }
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::InvalidHeader {
+                reason: format!("unknown flags {flags:04x}"),
+            });
+        }
+
+        Ok(Some(Header { version, flags }))
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        let mut offset = 0;
+        buf[offset..(offset + MAGIC.len())].copy_from_slice(&MAGIC);
+        offset += MAGIC.len();
+        buf[offset..(offset + 2)].copy_from_slice(&self.version.to_be_bytes());
+        offset += 2;
+        buf[offset..(offset + 2)].copy_from_slice(&self.flags.to_be_bytes());
+        offset += 2;
+
+        let checksum = crc::checksum(&buf[..offset]);
+        buf[offset..].copy_from_slice(&checksum.to_be_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let bytes = Header::new().to_bytes();
+        assert_eq!(&bytes[..MAGIC.len()], b"DISKMAP\0");
+        let header = Header::from_bytes(&bytes).unwrap().unwrap();
+        assert_eq!((header.version, header.flags), (VERSION, 0));
+    }
+
+    #[test]
+    fn older_versions_are_read() {
+        let bytes = Header {
+            version: 1,
+            flags: 0,
+        }
+        .to_bytes();
+        assert_eq!(Header::from_bytes(&bytes).unwrap().unwrap().version, 1);
+    }
+
+    #[test]
+    fn no_magic_is_no_header() {
+        assert!(Header::from_bytes(b"").unwrap().is_none());
+        assert!(Header::from_bytes(b"DISKMA").unwrap().is_none());
+        assert!(
+            Header::from_bytes(&[1, 0, 1, 0, 1, b'k', b'v'])
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn short_header_is_truncated() {
+        let bytes = Header::new().to_bytes();
+        for len in MAGIC.len()..HEADER_LEN {
+            assert!(matches!(
+                Header::from_bytes(&bytes[..len]),
+                Err(Error::Truncated { offset: 0 })
+            ));
+        }
+    }
+
+    #[test]
+    fn bad_checksum_is_corrupt() {
+        let mut bytes = Header::new().to_bytes();
+        bytes[HEADER_LEN - 1] ^= 1;
+        assert!(matches!(
+            Header::from_bytes(&bytes),
+            Err(Error::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn newer_versions_and_unknown_flags_are_refused() {
+        for header in [
+            Header {
+                version: VERSION + 1,
+                flags: 0,
+            },
+            Header {
+                version: VERSION,
+                flags: 1,
+            },
+        ] {
+            assert!(matches!(
+                Header::from_bytes(&header.to_bytes()),
+                Err(Error::InvalidHeader { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use nix::{errno, fcntl, fcntl::OFlag, sys::stat::Mode};
+use std::error;
+use std::os::fd::AsFd;
+
+use crate::disk::{crc, file, varint};
+
+// hint files start with these bytes instead of a map header, so that neither is ever mistaken
+// for the other
+const MAGIC: [u8; 8] = *b"DMHINT\0\0";
+
+// where one live entry of a segment is, and enough about it to index it without reading it
+pub struct Hint {
+    pub key: Vec<u8>,
+    pub offset: usize,
+    pub len: usize,
+    pub version: u64,
+    pub expires_at: u64,
+}
+
+// everything opening the map needs to know about a merged segment, so that it doesn't have to
+// read the segment itself. laid out as the magic bytes, the size of the segment, the highest
+// version when it was written, one hint per live entry, and a checksum of all of that
+pub struct HintFile {
+    pub segment_size: usize,
+    pub last_version: u64,
+    pub hints: Vec<Hint>,
+}
+
+impl HintFile {
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn error::Error>> {
+        let mut buf = Vec::<u8>::from(MAGIC);
+        varint::encode(self.segment_size as u64, &mut buf);
+        varint::encode(self.last_version, &mut buf);
+        for hint in &self.hints {
+            varint::encode(hint.key.len() as u64, &mut buf);
+            buf.extend_from_slice(&hint.key);
+            varint::encode(hint.offset as u64, &mut buf);
+            varint::encode(hint.len as u64, &mut buf);
+            varint::encode(hint.version, &mut buf);
+            varint::encode(hint.expires_at, &mut buf);
+        }
+        let checksum = crc::checksum(&buf);
+        buf.extend_from_slice(&checksum.to_be_bytes());
+
+        file::replace_atomically(path, &buf)?;
+        Ok(())
+    }
+
+    // `None` if there is no hint file at `path`. one that is there but doesn't check out is an
+    // error
+    pub fn load(path: &str) -> Result<Option<HintFile>, Box<dyn error::Error>> {
+        let fd = match fcntl::open(path, OFlag::O_RDONLY, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(errno::Errno::ENOENT) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let buf = file::read_all(fd.as_fd())?;
+
+        if buf.len() < MAGIC.len() + 4 || buf[..MAGIC.len()] != MAGIC {
+            return Err("not a hint file".into());
+        }
+        let (body, stored_checksum) = buf.split_at(buf.len() - 4);
+        let stored_checksum = u32::from_be_bytes([
+            stored_checksum[0],
+            stored_checksum[1],
+            stored_checksum[2],
+            stored_checksum[3],
+        ]);
+        let actual_checksum = crc::checksum(body);
+        if stored_checksum != actual_checksum {
+            return Err(format!(
+                "checksum mismatch: stored {stored_checksum:08x}, computed {actual_checksum:08x}"
+            )
+            .into());
+        }
+
+        let mut offset = MAGIC.len();
+        let segment_size = HintFile::next_varint(body, &mut offset)? as usize;
+        let last_version = HintFile::next_varint(body, &mut offset)?;
+        let mut hints = Vec::<Hint>::new();
+        while offset < body.len() {
+            let key_len = HintFile::next_varint(body, &mut offset)? as usize;
+            let key = body
+                .get(offset..(offset + key_len))
+                .ok_or("hint file is truncated")?
+                .to_vec();
+            offset += key_len;
+            hints.push(Hint {
+                key,
+                offset: HintFile::next_varint(body, &mut offset)? as usize,
+                len: HintFile::next_varint(body, &mut offset)? as usize,
+                version: HintFile::next_varint(body, &mut offset)?,
+                expires_at: HintFile::next_varint(body, &mut offset)?,
+            });
+        }
+
+        Ok(Some(HintFile {
+            segment_size,
+            last_version,
+            hints,
+        }))
+    }
+
+    fn next_varint(bytes: &[u8], offset: &mut usize) -> Result<u64, Box<dyn error::Error>> {
+        match varint::decode(bytes, *offset) {
+            Ok((n, len)) => {
+                *offset += len;
+                Ok(n)
+            }
+            Err(varint::DecodeError::Truncated) => Err("hint file is truncated".into()),
+            Err(varint::DecodeError::Overlong) => Err("hint file has an overlong number".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn hint_file() -> HintFile {
+        HintFile {
+            segment_size: 4096,
+            last_version: 9,
+            hints: vec![
+                Hint {
+                    key: b"a".to_vec(),
+                    offset: 24,
+                    len: 40,
+                    version: 3,
+                    expires_at: 0,
+                },
+                Hint {
+                    key: vec![0xff; 300],
+                    offset: 64,
+                    len: 1000,
+                    version: 9,
+                    expires_at: 1_700_000_000_000,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn saved_hints_load_back() {
+        let path = format!("{}/map.000001.hint", file::test_dir("hint-round-trip"));
+        let saved = hint_file();
+        saved.save(&path).unwrap();
+
+        let loaded = HintFile::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.segment_size, saved.segment_size);
+        assert_eq!(loaded.last_version, saved.last_version);
+        assert_eq!(loaded.hints.len(), saved.hints.len());
+        for (loaded, saved) in loaded.hints.iter().zip(&saved.hints) {
+            assert_eq!(loaded.key, saved.key);
+            assert_eq!(loaded.offset, saved.offset);
+            assert_eq!(loaded.len, saved.len);
+            assert_eq!(loaded.version, saved.version);
+            assert_eq!(loaded.expires_at, saved.expires_at);
+        }
+    }
+
+    #[test]
+    fn missing_hint_file_is_none() {
+        let path = format!("{}/map.000001.hint", file::test_dir("hint-missing"));
+        assert!(HintFile::load(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn flipped_byte_is_refused() {
+        let path = format!("{}/map.000001.hint", file::test_dir("hint-flipped"));
+        hint_file().save(&path).unwrap();
+        let mut buf = fs::read(&path).unwrap();
+        buf[MAGIC.len() + 3] ^= 1;
+        fs::write(&path, buf).unwrap();
+
+        let err = HintFile::load(&path).err().unwrap();
+        assert!(err.to_string().starts_with("checksum mismatch"));
+    }
+
+    #[test]
+    fn truncated_hint_file_is_refused() {
+        let path = format!("{}/map.000001.hint", file::test_dir("hint-truncated"));
+        hint_file().save(&path).unwrap();
+        let buf = fs::read(&path).unwrap();
+        for len in [0, MAGIC.len(), buf.len() / 2, buf.len() - 1] {
+            fs::write(&path, &buf[..len]).unwrap();
+            assert!(HintFile::load(&path).is_err(), "{len} bytes");
+        }
+    }
+}
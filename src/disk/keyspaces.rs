@@ -0,0 +1,311 @@
+use nix::{errno, libc, sys::stat, sys::stat::Mode, unistd};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::{error, fs};
+
+use crate::disk::file;
+use crate::disk::store::KvStore;
+
+// the keyspace every connection starts out in. it's the store the server was started with, so
+// it can't be created or dropped
+pub const DEFAULT: &str = "default";
+
+const MAX_NAME_LEN: usize = 64;
+
+// what a dropped keyspace's directory is renamed to before it's deleted. keyspace names can't
+// start with a dot, so it's never mistaken for one
+const TOMBSTONE_PREFIX: &str = ".dropped-";
+
+type Open<S> = Box<dyn Fn(&str) -> Result<S, Box<dyn error::Error>>>;
+type New<S> = Box<dyn Fn() -> S>;
+
+// where keyspaces other than the default one are kept
+enum Backing<S> {
+    // each one in a directory of its own under `dir`, which holds its store in a file or
+    // directory called `map`. `open` opens the store at the path it's given
+    Disk { dir: String, open: Open<S> },
+    // nowhere. `new` makes an empty store
+    Memory { new: New<S> },
+}
+
+// a named store. connections hold on to the keyspace they use, and once it's dropped every
+// command they send it fails, rather than touching files that are gone
+pub struct Keyspace<S: KvStore> {
+    name: String,
+    store: S,
+    dropped: AtomicBool,
+}
+
+impl<S: KvStore> Keyspace<S> {
+    fn new(name: &str, store: S) -> Arc<Keyspace<S>> {
+        Arc::new(Keyspace {
+            name: String::from(name),
+            store,
+            dropped: AtomicBool::new(false),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn store(&self) -> Result<&S, Box<dyn error::Error>> {
+        if self.dropped.load(Ordering::Acquire) {
+            return Err(format!("keyspace {} was dropped. use another one", self.name).into());
+        }
+        Ok(&self.store)
+    }
+}
+
+// named stores that share a server
+pub struct Keyspaces<S: KvStore> {
+    backing: Backing<S>,
+    default: Arc<Keyspace<S>>,
+    named: RwLock<BTreeMap<String, Arc<Keyspace<S>>>>,
+    // how many keyspaces were dropped, so that every one gets a tombstone of its own
+    tombstones: AtomicU64,
+}
+
+impl<S: KvStore> Keyspaces<S> {
+    // opens every keyspace in `dir` with `open`, next to the `default` store that is already
+    // open. `dir` is created if it isn't there yet
+    pub fn open(
+        default: S,
+        dir: &str,
+        open: impl Fn(&str) -> Result<S, Box<dyn error::Error>> + 'static,
+    ) -> Result<Keyspaces<S>, Box<dyn error::Error>> {
+        match stat::stat(dir) {
+            Ok(st) if st.st_mode & libc::S_IFMT == libc::S_IFDIR => {}
+            Ok(_) => return Err(format!("{dir} is not a directory").into()),
+            Err(errno::Errno::ENOENT) => {
+                unistd::mkdir(dir, dir_mode())?;
+                file::fsync_parent(dir)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut named = BTreeMap::<String, Arc<Keyspace<S>>>::new();
+        for dir_entry in fs::read_dir(dir)? {
+            let dir_entry = dir_entry?;
+            let Some(name) = dir_entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            // a keyspace that was dropped without its files being deleted
+            if name.starts_with(TOMBSTONE_PREFIX) && dir_entry.file_type()?.is_dir() {
+                let tombstone = keyspace_dir(dir, &name);
+                fs::remove_dir_all(&tombstone)
+                    .map_err(|err| format!("error deleting {tombstone}: {err}"))?;
+                continue;
+            }
+            // anything else in there isn't ours
+            if !dir_entry.file_type()?.is_dir() || check_name(&name).is_err() {
+                continue;
+            }
+            let store = open(&map_path(dir, &name))
+                .map_err(|err| format!("error opening keyspace {name}: {err}"))?;
+            named.insert(name.clone(), Keyspace::new(&name, store));
+        }
+
+        Ok(Keyspaces {
+            backing: Backing::Disk {
+                dir: String::from(dir),
+                open: Box::new(open),
+            },
+            default: Keyspace::new(DEFAULT, default),
+            named: RwLock::new(named),
+            tombstones: AtomicU64::new(0),
+        })
+    }
+
+    // keyspaces that are gone once the server stops. `new` makes the store of each one
+    pub fn in_memory(default: S, new: impl Fn() -> S + 'static) -> Keyspaces<S> {
+        Keyspaces {
+            backing: Backing::Memory { new: Box::new(new) },
+            default: Keyspace::new(DEFAULT, default),
+            named: RwLock::new(BTreeMap::new()),
+            tombstones: AtomicU64::new(0),
+        }
+    }
+
+    pub fn default(&self) -> Arc<Keyspace<S>> {
+        Arc::clone(&self.default)
+    }
+
+    // the default keyspace's store, which is never dropped
+    pub fn default_store(&self) -> &S {
+        &self.default.store
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<Keyspace<S>>, Box<dyn error::Error>> {
+        if name == DEFAULT {
+            return Ok(self.default());
+        }
+        let named = self.named.read().map_err(|e| e.to_string())?;
+        let keyspace = named.get(name).ok_or(format!("no keyspace named {name}"))?;
+        Ok(Arc::clone(keyspace))
+    }
+
+    pub fn create(&self, name: &str) -> Result<(), Box<dyn error::Error>> {
+        check_name(name)?;
+        let mut named = self.named.write().map_err(|e| e.to_string())?;
+        if name == DEFAULT || named.contains_key(name) {
+            return Err(format!("keyspace {name} already exists").into());
+        }
+
+        let store = match &self.backing {
+            Backing::Memory { new } => new(),
+            Backing::Disk { dir, open } => {
+                let keyspace_dir = keyspace_dir(dir, name);
+                unistd::mkdir(keyspace_dir.as_str(), dir_mode())?;
+                file::fsync_parent(&keyspace_dir)?;
+                match open(&map_path(dir, name)) {
+                    Ok(store) => store,
+                    Err(err) => {
+                        let _ = fs::remove_dir_all(&keyspace_dir);
+                        return Err(err);
+                    }
+                }
+            }
+        };
+        named.insert(String::from(name), Keyspace::new(name, store));
+        Ok(())
+    }
+
+    // unregisters the keyspace and deletes everything in it. connections still using it get an
+    // error for every command until they switch to another one. its directory is renamed out of
+    // the way first, so nothing is unregistered unless that worked, and a keyspace of the same
+    // name can be created straight away. if deleting the renamed directory fails, opening the
+    // keyspaces next time deletes it
+    pub fn remove(&self, name: &str) -> Result<(), Box<dyn error::Error>> {
+        if name == DEFAULT {
+            return Err(format!("keyspace {DEFAULT} can't be dropped").into());
+        }
+        let mut named = self.named.write().map_err(|e| e.to_string())?;
+        if !named.contains_key(name) {
+            return Err(format!("no keyspace named {name}").into());
+        }
+
+        let tombstone = match &self.backing {
+            Backing::Memory { .. } => None,
+            Backing::Disk { dir, .. } => {
+                let n = self.tombstones.fetch_add(1, Ordering::Relaxed);
+                let tombstone = keyspace_dir(dir, &format!("{TOMBSTONE_PREFIX}{n}-{name}"));
+                fs::rename(keyspace_dir(dir, name), &tombstone)
+                    .map_err(|err| format!("error dropping keyspace {name}: {err}"))?;
+                file::fsync_parent(&tombstone)?;
+                Some(tombstone)
+            }
+        };
+        if let Some(keyspace) = named.remove(name) {
+            keyspace.dropped.store(true, Ordering::Release);
+        }
+        drop(named);
+
+        if let Some(tombstone) = tombstone {
+            fs::remove_dir_all(&tombstone).map_err(|err| {
+                format!("dropped keyspace {name}, but deleting {tombstone} failed: {err}")
+            })?;
+            file::fsync_parent(&tombstone)?;
+        }
+        Ok(())
+    }
+
+    // every keyspace's name, the default one first
+    pub fn names(&self) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let named = self.named.read().map_err(|e| e.to_string())?;
+        let mut names = vec![String::from(DEFAULT)];
+        names.extend(named.keys().cloned());
+        Ok(names)
+    }
+
+    // every keyspace, the default one first
+    pub fn all(&self) -> Result<Vec<Arc<Keyspace<S>>>, Box<dyn error::Error>> {
+        let named = self.named.read().map_err(|e| e.to_string())?;
+        let mut keyspaces = vec![self.default()];
+        keyspaces.extend(named.values().cloned());
+        Ok(keyspaces)
+    }
+
+    // `None` if keyspaces only live in memory
+    pub fn dir(&self) -> Option<&str> {
+        match &self.backing {
+            Backing::Disk { dir, .. } => Some(dir),
+            Backing::Memory { .. } => None,
+        }
+    }
+}
+
+fn keyspace_dir(dir: &str, name: &str) -> String {
+    format!("{dir}/{name}")
+}
+
+fn map_path(dir: &str, name: &str) -> String {
+    format!("{}/map", keyspace_dir(dir, name))
+}
+
+fn dir_mode() -> Mode {
+    Mode::S_IRWXU | Mode::S_IRGRP | Mode::S_IXGRP
+}
+
+// names end up in paths, so they're kept to letters, digits, dashes and underscores
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "keyspace names are 1 to {MAX_NAME_LEN} characters long"
+        ));
+    }
+    if !name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return Err(format!(
+            "keyspace name {name} can only have letters, digits, - and _"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::memory::MemoryStore;
+
+    fn open(dir: &str) -> Keyspaces<MemoryStore> {
+        Keyspaces::open(MemoryStore::new(16, 16), dir, |_| {
+            Ok(MemoryStore::new(16, 16))
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn dropped_keyspace_is_deleted_while_still_in_use() {
+        let dir = file::test_dir("keyspaces-drop");
+        let keyspaces = open(&dir);
+        keyspaces.create("other").unwrap();
+        let in_use = keyspaces.get("other").unwrap();
+
+        keyspaces.remove("other").unwrap();
+        assert!(in_use.store().is_err());
+        assert!(keyspaces.get("other").is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        keyspaces.create("other").unwrap();
+        assert!(keyspaces.get("other").unwrap().store().is_ok());
+    }
+
+    #[test]
+    fn tombstones_left_behind_are_deleted_on_open() {
+        let dir = file::test_dir("keyspaces-tombstones");
+        fs::create_dir_all(format!("{dir}/{TOMBSTONE_PREFIX}0-other/map")).unwrap();
+        fs::create_dir_all(format!("{dir}/kept")).unwrap();
+
+        let keyspaces = open(&dir);
+        assert_eq!(keyspaces.names().unwrap(), [DEFAULT, "kept"]);
+        let left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(left, ["kept"]);
+    }
+}
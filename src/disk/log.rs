@@ -0,0 +1,1339 @@
+use nix::{errno, fcntl, fcntl::OFlag, libc, sys::stat, sys::stat::Mode, unistd};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::{error, fs, io, ops, os, path};
+
+use crate::disk::durability::{Durability, Flusher};
+use crate::disk::engine::{self, Engine, Info, Outcome, Stamp, Stats, Usage, Visit, Written};
+use crate::disk::file::{self, FileInfo};
+use crate::disk::hint::{Hint, HintFile};
+use crate::disk::map::{Op, Page, WriteBatch};
+use crate::disk::{error::Error, header, reader};
+
+// once the active segment is this big, it's sealed and a new one takes its place
+const SEGMENT_SIZE: usize = 64 * 1024 * 1024;
+
+// location of a live entry on disk. this is what the in-memory index maps every key to so that a
+// lookup is a single positioned read instead of a scan of every segment
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    segment: u64,
+    offset: usize,
+    len: usize,
+    version: u64,
+    expires_at: u64,
+}
+
+impl IndexEntry {
+    fn stamp(&self) -> Stamp {
+        Stamp {
+            version: self.version,
+            expires_at: self.expires_at,
+        }
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        self.stamp().expired(now)
+    }
+
+    fn same_place(&self, other: &IndexEntry) -> bool {
+        self.segment == other.segment && self.offset == other.offset
+    }
+}
+
+// one batch, encoded and ready to be appended along with the rest of its group
+struct EncodedBatch<'a> {
+    buf: Vec<u8>,
+    changes: HashMap<&'a [u8], Option<IndexEntry>>,
+    last_version: u64,
+}
+
+struct Segment {
+    fd: OwnedFd,
+    // sealed segments keep the format they were written in, which can be older than the one
+    // the active segment was migrated to
+    format_version: u16,
+}
+
+struct State {
+    // sorted, so that ranges of keys can be read in order
+    index: BTreeMap<Vec<u8>, IndexEntry>,
+    // how much of the segments the entries in the index take up. the rest is dead
+    live_bytes: usize,
+    // every key in the index that expires, soonest first, so that finding the ones that have
+    // expired doesn't take going through the whole index
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+    // every segment by id, the active one included. reads go through these instead of opening
+    // files by path, since the file at a path changes when a segment is sealed or merged
+    segments: BTreeMap<u64, Segment>,
+    // the id the active segment gets once it is sealed. every sealed segment has a lower one
+    active: u64,
+}
+
+// the original engine: an append-only log, and an index in memory of where every key's latest
+// entry is. nothing is ever changed once written: overwriting a key appends a new entry and
+// deleting one appends a tombstone, and the last entry for a key wins. the log is split into
+// segments: new entries go to the active segment at `file_path`, which is sealed as
+// `<file_path>.<id>` once it is full. compaction merges the sealed segments into one without
+// what was overwritten or deleted while writes carry on in the active one, and leaves a hint
+// file `<file_path>.<id>.hint` next to the segment it writes, so that opening the map can index
+// it without reading it. `<file_path>.lock` is held for as long as the map is open
+pub struct LogEngine {
+    file_path: String,
+    flusher: Flusher,
+    state: RwLock<State>,
+    // the highest version handed out so far. only changes while the state is write-locked
+    last_version: AtomicU64,
+    // held for the whole of a merge, so that only one runs at a time
+    merging: Mutex<()>,
+    // set once a failed write couldn't be cut back off the active segment
+    torn: AtomicBool,
+    // held for as long as the map is open. we keep the index and the segments' fds in memory,
+    // and another process rolling over, merging or compacting the map would invalidate them
+    _lock: fcntl::Flock<OwnedFd>,
+}
+
+impl LogEngine {
+    pub fn open(
+        file_path: &str,
+        durability: Durability,
+    ) -> Result<LogEngine, Box<dyn error::Error>> {
+        let lock_path = format!("{file_path}.lock");
+        let lock_fd = fcntl::open(
+            lock_path.as_str(),
+            OFlag::O_RDWR | OFlag::O_CREAT,
+            file::mode(),
+        )?;
+        let lifetime_lock = fcntl::Flock::lock(lock_fd, fcntl::FlockArg::LockExclusiveNonblock)
+            .map_err(|(_, e)| format!("{file_path} is in use by another process ({e})"))?;
+
+        let log = LogEngine {
+            file_path: String::from(file_path),
+            flusher: Flusher::new(file_path, durability),
+            state: RwLock::new(State {
+                index: BTreeMap::new(),
+                live_bytes: 0,
+                expiring: BTreeSet::new(),
+                segments: BTreeMap::new(),
+                active: 0,
+            }),
+            last_version: AtomicU64::new(0),
+            merging: Mutex::new(()),
+            torn: AtomicBool::new(false),
+            _lock: lifetime_lock,
+        };
+
+        // acquire exclusive lock, since recovery and migrations might need to rewrite the file
+        let lock = log.open_locked(fcntl::FlockArg::LockExclusive)?;
+
+        // brand new files get a header before anything else
+        let mut data = file::read_all(lock.as_fd())?;
+        if data.is_empty() {
+            data.extend_from_slice(&header::Header::new().to_bytes());
+            file::write_all(lock.as_fd(), &data)?;
+            unistd::fsync(lock.as_fd())?;
+        }
+
+        // bring older files up to the current format. only the active segment can be that old,
+        // since sealed segments came after it
+        let mut last_version = match header::Header::from_bytes(&data)? {
+            Some(h) if h.version == header::VERSION => {
+                LogEngine::recover(file_path, lock.as_fd(), &mut data)?
+            }
+            Some(h) => {
+                let last_version;
+                (data, last_version) = log.migrate(&data, h.version)?;
+                last_version
+            }
+            None => {
+                let last_version;
+                (data, last_version) = log.migrate(&data, 0)?;
+                last_version
+            }
+        };
+
+        // build index from whatever is already on disk, oldest segment first. a segment with a
+        // hint file only needs the hint file read, everything else gets read in full
+        let mut index = BTreeMap::<Vec<u8>, IndexEntry>::new();
+        let mut segments = BTreeMap::<u64, Segment>::new();
+        for id in LogEngine::sealed_ids(file_path)? {
+            let path = log.segment_path(id);
+            let fd = fcntl::open(path.as_str(), OFlag::O_RDONLY, Mode::empty())?;
+            let segment_size = stat::fstat(fd.as_fd())?.st_size as usize;
+            let format_version = LogEngine::segment_version(fd.as_fd())?;
+            let hint_path = log.hint_path(id);
+            let hint_file = match HintFile::load(&hint_path) {
+                Ok(Some(hint_file)) if hint_file.segment_size == segment_size => Some(hint_file),
+                Ok(Some(_)) => {
+                    eprintln!("ignoring {hint_path}: it doesn't match the size of {path}");
+                    None
+                }
+                Ok(None) => None,
+                Err(err) => {
+                    eprintln!("ignoring {hint_path}: {err}");
+                    None
+                }
+            };
+
+            let segment_version = match hint_file {
+                Some(hint_file) => LogEngine::index_hints(&mut index, id, hint_file),
+                None => {
+                    let (segment_data, _) = LogEngine::read_segment(fd.as_fd())?;
+                    let read_result = reader::ReadResult::with_version(
+                        header::HEADER_LEN,
+                        segment_data,
+                        format_version,
+                    );
+                    LogEngine::index_segment(&mut index, id, read_result)?
+                }
+            };
+            last_version = last_version.max(segment_version);
+            segments.insert(id, Segment { fd, format_version });
+        }
+        let active = segments.keys().next_back().map_or(1, |id| id + 1);
+        let read_result = reader::ReadResult::new(header::HEADER_LEN, data);
+        LogEngine::index_segment(&mut index, active, read_result)?;
+
+        // migrations replace the file, so the fd we locked might not be the active segment
+        let active_fd = fcntl::open(file_path, OFlag::O_RDWR, Mode::empty())?;
+        segments.insert(
+            active,
+            Segment {
+                fd: active_fd,
+                format_version: header::VERSION,
+            },
+        );
+
+        // release lock
+        let _ = lock.unlock().map_err(|(_, e)| e)?;
+
+        *log.state.write().map_err(|e| e.to_string())? = State {
+            live_bytes: index.values().map(|index_entry| index_entry.len).sum(),
+            expiring: index
+                .iter()
+                .filter(|(_, index_entry)| index_entry.expires_at != 0)
+                .map(|(key, index_entry)| (index_entry.expires_at, key.clone()))
+                .collect(),
+            index,
+            segments,
+            active,
+        };
+        log.last_version.store(last_version, Ordering::Relaxed);
+        Ok(log)
+    }
+
+    fn open_locked(
+        &self,
+        arg: fcntl::FlockArg,
+    ) -> Result<fcntl::Flock<OwnedFd>, Box<dyn error::Error>> {
+        file::open_locked(&self.file_path, arg)
+    }
+
+    fn segment_path(&self, id: u64) -> String {
+        format!("{}.{id:06}", self.file_path)
+    }
+
+    fn hint_path(&self, id: u64) -> String {
+        format!("{}.hint", self.segment_path(id))
+    }
+
+    fn remove_hint(&self, id: u64) -> Result<(), Box<dyn error::Error>> {
+        match unistd::unlink(self.hint_path(id).as_str()) {
+            Ok(()) | Err(errno::Errno::ENOENT) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // the ids of the sealed segments next to `file_path`, oldest first
+    fn sealed_ids(file_path: &str) -> Result<Vec<u64>, Box<dyn error::Error>> {
+        let file_path = path::Path::new(file_path);
+        let dir = match file_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => path::Path::new("."),
+        };
+        let name = file_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("file path has no file name")?;
+        let prefix = format!("{name}.");
+
+        let mut ids = Vec::<u64>::new();
+        for dir_entry in fs::read_dir(dir)? {
+            let file_name = dir_entry?.file_name();
+            let Some(suffix) = file_name.to_str().and_then(|n| n.strip_prefix(&prefix)) else {
+                continue;
+            };
+            if !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()) {
+                ids.push(suffix.parse()?);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    // the whole of a segment and the format it was written in
+    fn read_segment(fd: os::fd::BorrowedFd) -> Result<(Vec<u8>, u16), Box<dyn error::Error>> {
+        let size = stat::fstat(fd)?.st_size as usize;
+        let data = file::read_at(fd, 0, size)?;
+        match header::Header::from_bytes(&data)? {
+            Some(h) => Ok((data, h.version)),
+            None => Err("segment is missing its header".into()),
+        }
+    }
+
+    // the format a segment was written in, without reading any more of it than the header
+    fn segment_version(fd: os::fd::BorrowedFd) -> Result<u16, Box<dyn error::Error>> {
+        let data = file::read_at(fd, 0, header::HEADER_LEN)?;
+        match header::Header::from_bytes(&data)? {
+            Some(h) => Ok(h.version),
+            None => Err("segment is missing its header".into()),
+        }
+    }
+
+    fn end(fd: os::fd::BorrowedFd) -> Result<usize, Box<dyn error::Error>> {
+        let end = unsafe { libc::lseek(fd.as_raw_fd(), 0, libc::SEEK_END) };
+        if end == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(end as usize)
+    }
+
+    // a crash in the middle of a write leaves a partially written entry, or a batch without its
+    // commit marker, at the end of the file. neither was acknowledged, so they are moved to a
+    // quarantine file and cut off. a bad entry anywhere before the tail is real corruption and
+    // is left alone for a human to look at. returns the highest version of any entry kept, dead
+    // or alive, so that no version is ever handed out twice
+    pub fn recover(
+        file_path: &str,
+        fd: os::fd::BorrowedFd,
+        data: &mut Vec<u8>,
+    ) -> Result<u64, Box<dyn error::Error>> {
+        let mut offset = header::HEADER_LEN;
+        let mut batch_start = None;
+        let mut last_version = 0;
+        let (offset, reason) = loop {
+            if offset >= data.len() {
+                match batch_start {
+                    Some(start) => {
+                        break (
+                            start,
+                            format!("batch at offset {start} was never committed"),
+                        );
+                    }
+                    None => return Ok(last_version),
+                }
+            }
+
+            match reader::Entry::from_bytes(data, offset) {
+                Ok(entry) => {
+                    match entry.kind {
+                        reader::Kind::BatchBegin => batch_start = Some(offset),
+                        reader::Kind::BatchCommit => batch_start = None,
+                        _ => {}
+                    }
+                    last_version = last_version.max(entry.version);
+                    offset += entry.len;
+                }
+                Err(err @ Error::Truncated { .. }) => {
+                    break (batch_start.unwrap_or(offset), err.to_string());
+                }
+                Err(err @ Error::Corrupt { .. }) => {
+                    match reader::Entry::claimed_len(data, offset, header::VERSION) {
+                        Some(len) if offset + len == data.len() => {
+                            break (batch_start.unwrap_or(offset), err.to_string());
+                        }
+                        _ => return Err(err.into()),
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        // save the torn tail before getting rid of it
+        let quarantine_path = format!("{}.torn-{}", file_path, offset);
+        let quarantine_fd = fcntl::open(
+            quarantine_path.as_str(),
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC,
+            file::mode(),
+        )?;
+        file::write_all(quarantine_fd.as_fd(), &data[offset..])?;
+        unistd::fsync(quarantine_fd.as_fd())?;
+
+        // truncate everything after the last valid entry
+        if unsafe { libc::ftruncate(fd.as_raw_fd(), offset as i64) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        unistd::fsync(fd)?;
+
+        eprintln!(
+            "recovered {}: discarded {} bytes at offset {} ({}). saved them to {}",
+            file_path,
+            data.len() - offset,
+            offset,
+            reason,
+            quarantine_path
+        );
+        data.truncate(offset);
+
+        Ok(last_version)
+    }
+
+    // rewrites a file of an older format `version` in the current format. files from before
+    // there was a header only count as map files if every entry in them decodes. entries keep
+    // their versions, or get numbered from the highest one if they are too old to have one. the
+    // last version handed out is returned with the new file
+    fn migrate(&self, data: &[u8], version: u16) -> Result<(Vec<u8>, u64), Box<dyn error::Error>> {
+        let offset = match version {
+            0 => 0,
+            _ => header::HEADER_LEN,
+        };
+
+        let mut m = BTreeMap::<Vec<u8>, reader::Entry>::new();
+        let mut last_version = 0;
+        for entry in reader::ReadResult::with_version(offset, data.to_vec(), version) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) if version == 0 => {
+                    return Err(Error::InvalidHeader {
+                        reason: format!("missing magic bytes and not a legacy map file ({err})"),
+                    }
+                    .into());
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            last_version = last_version.max(entry.version);
+            // tombstones are kept, since what they delete can be in a sealed segment
+            match entry.kind {
+                reader::Kind::Put | reader::Kind::Tombstone => {
+                    m.insert(entry.key.clone(), entry);
+                }
+                _ => continue,
+            };
+        }
+
+        let mut new_buf = Vec::<u8>::from(header::Header::new().to_bytes());
+        for (_, mut entry) in m {
+            if entry.version == 0 {
+                last_version += 1;
+                entry.version = last_version;
+            }
+            new_buf.extend_from_slice(&entry.to_bytes());
+        }
+        new_buf.extend_from_slice(&reader::Entry::sequence(last_version).to_bytes());
+
+        file::replace_atomically(&self.file_path, &new_buf)?;
+        eprintln!(
+            "migrated {} from format version {} to {}",
+            self.file_path,
+            version,
+            header::VERSION
+        );
+
+        Ok((new_buf, last_version))
+    }
+
+    // applies the entries of one segment to `index`, on top of every older segment. returns the
+    // highest version in the segment
+    fn index_segment(
+        index: &mut BTreeMap<Vec<u8>, IndexEntry>,
+        segment: u64,
+        read_result: reader::ReadResult,
+    ) -> Result<u64, Box<dyn error::Error>> {
+        let now = engine::now();
+        let mut last_version = 0;
+        for entry in read_result {
+            let entry = entry?;
+            last_version = last_version.max(entry.version);
+            match entry.kind {
+                reader::Kind::Put => {}
+                reader::Kind::Tombstone => {
+                    index.remove(&entry.key);
+                    continue;
+                }
+                _ => continue,
+            }
+
+            let index_entry = IndexEntry {
+                segment,
+                offset: entry.offset,
+                len: entry.len,
+                version: entry.version,
+                expires_at: entry.expires_at,
+            };
+            if index_entry.expired(now) {
+                index.remove(&entry.key);
+                continue;
+            }
+            index.insert(entry.key, index_entry);
+        }
+        Ok(last_version)
+    }
+
+    // indexes a merged segment from its hint file. the only tombstones in merged segments cover
+    // up segments that were gone before the hint file was written, so every hint is a put.
+    // returns the highest version when the segment was written
+    fn index_hints(
+        index: &mut BTreeMap<Vec<u8>, IndexEntry>,
+        segment: u64,
+        hint_file: HintFile,
+    ) -> u64 {
+        let now = engine::now();
+        for hint in hint_file.hints {
+            let index_entry = IndexEntry {
+                segment,
+                offset: hint.offset,
+                len: hint.len,
+                version: hint.version,
+                expires_at: hint.expires_at,
+            };
+            if index_entry.expired(now) {
+                index.remove(&hint.key);
+                continue;
+            }
+            index.insert(hint.key, index_entry);
+        }
+        hint_file.last_version
+    }
+
+    // seals the active segment, whose locked fd is `fd`, and starts a new one. the new segment
+    // starts with the highest version so far, since the entry that had it might get merged away
+    fn roll_over(
+        &self,
+        state: &mut State,
+        fd: os::fd::BorrowedFd,
+    ) -> Result<(), Box<dyn error::Error>> {
+        // whatever the flusher hasn't gotten to yet has to be on disk before the file is renamed
+        // out from under it
+        unistd::fsync(fd)?;
+        fcntl::renameat(
+            fcntl::AT_FDCWD,
+            self.file_path.as_str(),
+            fcntl::AT_FDCWD,
+            self.segment_path(state.active).as_str(),
+        )?;
+
+        // if we crash before the new segment is in place, opening the map creates an empty one
+        let mut buf = Vec::<u8>::from(header::Header::new().to_bytes());
+        let last_version = self.last_version.load(Ordering::Relaxed);
+        buf.extend_from_slice(&reader::Entry::sequence(last_version).to_bytes());
+        file::replace_atomically(&self.file_path, &buf)?;
+
+        state.active += 1;
+        let active_fd = fcntl::open(self.file_path.as_str(), OFlag::O_RDWR, Mode::empty())?;
+        state.segments.insert(
+            state.active,
+            Segment {
+                fd: active_fd,
+                format_version: header::VERSION,
+            },
+        );
+        Ok(())
+    }
+
+    // rewrites the sealed segments `ids` as a single segment with only their live entries, and
+    // the tombstones that still have something to cover up until the old segments are gone. the
+    // segments are read and written without holding up writers, and only swapped in under the
+    // write lock. the new segment takes the newest id of the old ones, so it still sorts before
+    // everything written since. returns its size
+    fn merge_segments(&self, ids: &[u64]) -> Result<usize, Box<dyn error::Error>> {
+        let Some(&target) = ids.last() else {
+            return Ok(0);
+        };
+
+        let mut fds = Vec::<(u64, OwnedFd)>::with_capacity(ids.len());
+        {
+            let state = self.state.read().map_err(|e| e.to_string())?;
+            for id in ids {
+                let segment = state.segments.get(id).ok_or("merging a missing segment")?;
+                fds.push((*id, segment.fd.try_clone()?));
+            }
+        }
+
+        // sealed segments never change, so they can be read without any lock
+        let mut entries = Vec::<(u64, reader::Entry)>::new();
+        for (id, fd) in fds {
+            let (data, format_version) = LogEngine::read_segment(fd.as_fd())?;
+            let read_result =
+                reader::ReadResult::with_version(header::HEADER_LEN, data, format_version);
+            for entry in read_result {
+                let entry = entry?;
+                if matches!(entry.kind, reader::Kind::Put | reader::Kind::Tombstone) {
+                    entries.push((id, entry));
+                }
+            }
+        }
+
+        // what the index says about every key in them, copied out so that encoding the new
+        // segment holds up nobody. whatever changes meanwhile is sorted out when it's swapped in
+        let index: HashMap<Vec<u8>, IndexEntry> = {
+            let state = self.state.read().map_err(|e| e.to_string())?;
+            entries
+                .iter()
+                .filter_map(|(_, entry)| {
+                    let current = state.index.get(&entry.key)?;
+                    Some((entry.key.clone(), *current))
+                })
+                .collect()
+        };
+
+        // keep only the entries the index points to. the highest version goes first, since
+        // whatever entry had it might not be kept
+        let mut buf = Vec::<u8>::from(header::Header::new().to_bytes());
+        let last_version = self.last_version.load(Ordering::Relaxed);
+        buf.extend_from_slice(&reader::Entry::sequence(last_version).to_bytes());
+        let mut moved = Vec::<(Vec<u8>, IndexEntry, IndexEntry)>::new();
+        let mut hints = Vec::<Hint>::new();
+        let now = engine::now();
+        // keys that were deleted, expired or overwritten in any segment but the oldest, with the
+        // version they had there. until the older segments are gone, a crash leaves them behind
+        // the new segment, so it needs a tombstone for each of these keys that is neither kept
+        // nor written since. the oldest segment has nothing older to cover up
+        let mut dropped = BTreeMap::<Vec<u8>, u64>::new();
+        for (id, entry) in entries {
+            if id != ids[0] {
+                dropped.insert(entry.key.clone(), entry.version);
+            }
+            if entry.kind != reader::Kind::Put {
+                continue;
+            }
+            let old = match index.get(&entry.key) {
+                Some(old) if old.segment == id && old.offset == entry.offset => *old,
+                _ => continue,
+            };
+            if old.expired(now) {
+                continue;
+            }
+            dropped.remove(&entry.key);
+
+            let entry_bytes = entry.to_bytes();
+            let new = IndexEntry {
+                segment: target,
+                offset: buf.len(),
+                len: entry_bytes.len(),
+                ..old
+            };
+            buf.extend_from_slice(&entry_bytes);
+            hints.push(Hint {
+                key: entry.key.clone(),
+                offset: new.offset,
+                len: new.len,
+                version: new.version,
+                expires_at: new.expires_at,
+            });
+            moved.push((entry.key, old, new));
+        }
+
+        for (key, version) in dropped {
+            if index.get(&key).is_some_and(|e| e.segment > target) {
+                continue;
+            }
+            buf.extend_from_slice(&reader::Entry::tombstone(&key, version).to_bytes());
+        }
+
+        // write the new segment next to the one it replaces
+        let target_path = self.segment_path(target);
+        let tmp_path = format!("{target_path}.tmp");
+        let fd = fcntl::open(
+            tmp_path.as_str(),
+            OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_TRUNC,
+            file::mode(),
+        )?;
+        file::write_all(fd.as_fd(), &buf)?;
+        unistd::fsync(fd.as_fd())?;
+
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+
+        // a hint file left over for the old segment would describe the wrong one
+        self.remove_hint(target)?;
+        fcntl::renameat(
+            fcntl::AT_FDCWD,
+            tmp_path.as_str(),
+            fcntl::AT_FDCWD,
+            target_path.as_str(),
+        )?;
+        file::fsync_parent(&target_path)?;
+
+        // keys written or deleted since we looked at the index keep their new entries. the
+        // copies in the new segment are stale, but whatever replaced them is in a newer segment
+        // and wins over them when the map is opened
+        for (key, old, new) in moved {
+            if let Some(current) = state.index.get_mut(&key)
+                && current.same_place(&old)
+            {
+                *current = new;
+                state.live_bytes = state.live_bytes - old.len + new.len;
+            }
+        }
+        state.segments.insert(
+            target,
+            Segment {
+                fd,
+                format_version: header::VERSION,
+            },
+        );
+
+        // if we crash before these are gone, they are indexed before the new segment, which
+        // has a newer id. its puts and tombstones win over anything in them
+        for id in &ids[..ids.len() - 1] {
+            state.segments.remove(id);
+            self.remove_hint(*id)?;
+            unistd::unlink(self.segment_path(*id).as_str())?;
+        }
+        file::fsync_parent(&target_path)?;
+        drop(state);
+
+        // the stale copies are in the hints too, which is fine for the same reason. the
+        // tombstones aren't, since a hint file only ever shows up once the segments they cover
+        // are gone
+        let hint_file = HintFile {
+            segment_size: buf.len(),
+            last_version,
+            hints,
+        };
+        hint_file.save(&self.hint_path(target))?;
+
+        Ok(buf.len())
+    }
+
+    fn sealed(state: &State) -> Vec<u64> {
+        let sealed = state.segments.range(..state.active);
+        sealed.map(|(id, _)| *id).collect()
+    }
+
+    fn try_write_group(
+        &self,
+        batches: &[WriteBatch],
+    ) -> Result<Vec<Outcome>, Box<dyn error::Error>> {
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+
+        // acquire exclusive lock
+        let mut lock = self.open_locked(fcntl::FlockArg::LockExclusive)?;
+
+        // seek to end
+        let mut end = LogEngine::end(lock.as_fd())?;
+
+        // seal the active segment once it's full, so merges can get at its entries
+        if end >= SEGMENT_SIZE {
+            self.roll_over(&mut state, lock.as_fd())?;
+            lock = self.open_locked(fcntl::FlockArg::LockExclusive)?;
+            end = LogEngine::end(lock.as_fd())?;
+        }
+
+        // encode every batch into one buffer. `changes` tracks what the index will look like
+        // once this group is written, so that later batches in the group see earlier ones
+        let mut buf = Vec::<u8>::new();
+        let mut changes = HashMap::<&[u8], Option<IndexEntry>>::new();
+        let mut last_version = self.last_version.load(Ordering::Relaxed);
+        let now = engine::now();
+        let mut outcomes = Vec::<Outcome>::with_capacity(batches.len());
+        for batch in batches {
+            let lookup = |key: &[u8]| match changes.get(key) {
+                Some(change) => *change,
+                None => state.index.get(key).copied(),
+            };
+            let offset = end + buf.len();
+            match LogEngine::encode_batch(batch, state.active, offset, last_version, now, lookup) {
+                Ok(encoded) => {
+                    outcomes.push(Ok(Written {
+                        bytes: encoded.buf.len(),
+                        version: encoded.last_version,
+                    }));
+                    buf.extend_from_slice(&encoded.buf);
+                    changes.extend(encoded.changes);
+                    last_version = encoded.last_version;
+                }
+                // only this batch fails. the rest of the group never depended on it
+                Err(err) => outcomes.push(Err(err.into())),
+            }
+        }
+
+        file::append(lock.as_fd(), end, &buf, &self.torn, || {
+            self.flusher.wrote(lock.as_fd())
+        })?;
+
+        for (key, change) in changes {
+            let old = match change {
+                Some(index_entry) => {
+                    state.live_bytes += index_entry.len;
+                    if index_entry.expires_at != 0 {
+                        state
+                            .expiring
+                            .insert((index_entry.expires_at, key.to_vec()));
+                    }
+                    state.index.insert(key.to_vec(), index_entry)
+                }
+                None => state.index.remove(key),
+            };
+            if let Some(old) = old {
+                state.live_bytes -= old.len;
+                if old.expires_at != 0 && change.is_none_or(|new| new.expires_at != old.expires_at)
+                {
+                    state.expiring.remove(&(old.expires_at, key.to_vec()));
+                }
+            }
+        }
+        self.last_version.store(last_version, Ordering::Relaxed);
+
+        // release lock
+        let _ = lock.unlock().map_err(|(_, e)| e)?;
+
+        Ok(outcomes)
+    }
+
+    // encodes `batch` to be appended to `segment` at `offset`, numbering its entries after
+    // `last_version`. `lookup` says where a key's entry will be once everything before this batch
+    // is written. if a version check doesn't hold, nothing of the batch is encoded
+    fn encode_batch<'a>(
+        batch: &'a WriteBatch,
+        segment: u64,
+        offset: usize,
+        mut last_version: u64,
+        now: u64,
+        lookup: impl Fn(&[u8]) -> Option<IndexEntry>,
+    ) -> Result<EncodedBatch<'a>, Error> {
+        // a batch of one is atomic without any markers
+        let atomic = batch.len() > 1;
+        let mut buf = Vec::<u8>::new();
+        let mut changes = HashMap::<&[u8], Option<IndexEntry>>::new();
+        if atomic {
+            buf.extend_from_slice(&reader::Entry::marker(reader::Kind::BatchBegin).to_bytes());
+        }
+
+        for op in batch.ops() {
+            let current = match changes.get(op.key()) {
+                Some(change) => *change,
+                None => lookup(op.key()),
+            };
+            if !op.applies(current.map(|index_entry| index_entry.stamp()), now)? {
+                continue;
+            }
+
+            match op {
+                Op::Set {
+                    key,
+                    value,
+                    expires_at,
+                    ..
+                } => {
+                    last_version += 1;
+                    let entry = reader::Entry::new(key, value, last_version, *expires_at);
+                    let entry_bytes = entry.to_bytes();
+                    let index_entry = IndexEntry {
+                        segment,
+                        offset: offset + buf.len(),
+                        len: entry_bytes.len(),
+                        version: last_version,
+                        expires_at: *expires_at,
+                    };
+                    buf.extend_from_slice(&entry_bytes);
+                    changes.insert(key, Some(index_entry));
+                }
+                Op::Delete { key } | Op::DeleteExpired { key } => {
+                    last_version += 1;
+                    let tombstone = reader::Entry::tombstone(key, last_version);
+                    buf.extend_from_slice(&tombstone.to_bytes());
+                    changes.insert(key, None);
+                }
+            }
+        }
+
+        if atomic {
+            buf.extend_from_slice(&reader::Entry::marker(reader::Kind::BatchCommit).to_bytes());
+        }
+        Ok(EncodedBatch {
+            buf,
+            changes,
+            last_version,
+        })
+    }
+
+    fn read_entry(
+        state: &State,
+        index_entry: &IndexEntry,
+    ) -> Result<reader::Entry, Box<dyn error::Error>> {
+        let segment = state
+            .segments
+            .get(&index_entry.segment)
+            .ok_or("missing segment")?;
+        let buf = file::read_at(segment.fd.as_fd(), index_entry.offset, index_entry.len)?;
+        let mut entry = reader::Entry::from_versioned_bytes(&buf, 0, segment.format_version)
+            .map_err(|err| err.shifted(index_entry.offset))?;
+        entry.offset = index_entry.offset;
+        Ok(entry)
+    }
+}
+
+impl Engine for LogEngine {
+    // runs as the leader of a group commit. if writing fails, every batch in the group fails
+    fn write_group(&self, batches: Vec<WriteBatch>) -> Vec<Outcome> {
+        match self.try_write_group(&batches) {
+            Ok(outcomes) => outcomes,
+            Err(err) => {
+                let err = err.to_string();
+                batches.iter().map(|_| Err(err.clone().into())).collect()
+            }
+        }
+    }
+
+    // entries are only ever appended, and segments only swapped, while the state is
+    // write-locked, so holding the read lock is enough to read them
+    fn get(&self, k: &[u8], now: u64) -> Result<Option<reader::Entry>, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let index_entry = match state.index.get(k) {
+            Some(index_entry) if !index_entry.expired(now) => index_entry,
+            _ => return Ok(None),
+        };
+
+        let entry = LogEngine::read_entry(&state, index_entry)?;
+        Ok(Some(entry))
+    }
+
+    fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        now: u64,
+    ) -> Result<Page, Box<dyn error::Error>> {
+        // `range` panics on a range that ends before it starts
+        if let Some(end) = end
+            && start >= end
+        {
+            return Ok(Page::default());
+        }
+        let end = match end {
+            Some(end) => ops::Bound::Excluded(end),
+            None => ops::Bound::Unbounded,
+        };
+        let state = self.state.read().map_err(|e| e.to_string())?;
+
+        let mut live = state
+            .index
+            .range::<[u8], _>((ops::Bound::Included(start), end))
+            .filter(|(_, e)| !e.expired(now));
+        let mut entries = Vec::<(Vec<u8>, Vec<u8>)>::new();
+        for (_, index_entry) in live.by_ref().take(limit) {
+            let entry = LogEngine::read_entry(&state, index_entry)?;
+            entries.push((entry.key, entry.value));
+        }
+        let next = live.next().map(|(key, _)| key.clone());
+
+        Ok(Page { entries, next })
+    }
+
+    fn expired_keys(&self, limit: usize, now: u64) -> Result<Vec<Vec<u8>>, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let expired = state
+            .expiring
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .take(limit);
+        Ok(expired.map(|(_, key)| key.clone()).collect())
+    }
+
+    fn usage(&self) -> Result<Usage, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let mut bytes = 0;
+        for segment in state.segments.values() {
+            bytes += stat::fstat(segment.fd.as_fd())?.st_size as usize;
+        }
+        Ok(Usage {
+            bytes,
+            dead_bytes: Some(bytes.saturating_sub(state.live_bytes)),
+        })
+    }
+
+    // holds up writers for as long as reading every segment takes, like a full scan does
+    fn stats(&self, now: u64) -> Result<Stats, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let mut stats = Stats::default();
+        let mut bytes = 0;
+        for (id, segment) in &state.segments {
+            let (data, format_version) = LogEngine::read_segment(segment.fd.as_fd())?;
+            bytes += data.len();
+            let read_result =
+                reader::ReadResult::with_version(header::HEADER_LEN, data, format_version)
+                    .keeping_dead();
+            for entry in read_result {
+                let entry = entry?;
+                let live = entry.kind == reader::Kind::Put
+                    && state.index.get(&entry.key).is_some_and(|index_entry| {
+                        index_entry.segment == *id
+                            && index_entry.offset == entry.offset
+                            && !index_entry.expired(now)
+                    });
+                match entry.kind {
+                    _ if live => stats.count_live(&entry),
+                    reader::Kind::Put | reader::Kind::Tombstone | reader::Kind::Dead => {
+                        stats.dead_entries += 1
+                    }
+                    _ => {}
+                }
+            }
+        }
+        stats.dead_bytes = bytes - stats.live_bytes;
+        Ok(stats)
+    }
+
+    // seals the active segment, however small, and merges every sealed segment into one
+    fn compact(&self) -> Result<usize, Box<dyn error::Error>> {
+        let _merging = self.merging.lock().map_err(|e| e.to_string())?;
+        let sealed = {
+            let mut state = self.state.write().map_err(|e| e.to_string())?;
+
+            // acquire exclusive lock
+            let lock = self.open_locked(fcntl::FlockArg::LockExclusive)?;
+            self.roll_over(&mut state, lock.as_fd())?;
+
+            // release lock
+            let _ = lock.unlock().map_err(|(_, e)| e)?;
+
+            LogEngine::sealed(&state)
+        };
+
+        let n = self.merge_segments(&sealed)?;
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let active = state.segments.get(&state.active).ok_or("missing segment")?;
+        Ok(n + stat::fstat(active.fd.as_fd())?.st_size as usize)
+    }
+
+    fn info(&self) -> Result<Info, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let mut files = Vec::<FileInfo>::with_capacity(state.segments.len());
+        for (id, segment) in &state.segments {
+            let path = match *id == state.active {
+                true => self.file_path.clone(),
+                false => self.segment_path(*id),
+            };
+            files.push(FileInfo::new(&path, stat::fstat(segment.fd.as_fd())?));
+        }
+        Ok(Info {
+            files,
+            entries: state.index.len(),
+        })
+    }
+
+    fn snapshot(&self, now: u64, visit: &mut Visit) -> Result<(), Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        for index_entry in state.index.values().filter(|e| !e.expired(now)) {
+            visit(&LogEngine::read_entry(&state, index_entry)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{crc, varint};
+    use std::{thread, time};
+
+    fn write(log: &LogEngine, batch: WriteBatch) {
+        for outcome in log.write_group(vec![batch]) {
+            outcome.unwrap();
+        }
+    }
+
+    fn put(log: &LogEngine, k: &[u8], v: &[u8]) {
+        let mut batch = WriteBatch::new();
+        batch.put(k, v);
+        write(log, batch);
+    }
+
+    fn delete(log: &LogEngine, k: &[u8]) {
+        let mut batch = WriteBatch::new();
+        batch.delete(k);
+        write(log, batch);
+    }
+
+    #[test]
+    fn merge_keeps_deletes_while_older_segments_are_around() {
+        let path = format!("{}/map", file::test_dir("merge-crash"));
+        let log = LogEngine::open(&path, Durability::Always).unwrap();
+        put(&log, b"deleted", b"1");
+        put(&log, b"kept", b"2");
+        log.compact().unwrap();
+        let oldest = *LogEngine::sealed(&log.state.read().unwrap())
+            .first()
+            .unwrap();
+        let oldest_path = log.segment_path(oldest);
+        let oldest_bytes = fs::read(&oldest_path).unwrap();
+
+        delete(&log, b"deleted");
+        log.compact().unwrap();
+        let merged = *LogEngine::sealed(&log.state.read().unwrap())
+            .last()
+            .unwrap();
+        assert!(fs::metadata(&oldest_path).is_err());
+        drop(log);
+
+        // a crash after the merged segment was renamed into place, but before the segment it
+        // replaced was unlinked or the hint file written
+        fs::write(&oldest_path, oldest_bytes).unwrap();
+        fs::remove_file(format!("{path}.{merged:06}.hint")).unwrap();
+
+        let log = LogEngine::open(&path, Durability::Always).unwrap();
+        let now = engine::now();
+        assert!(log.get(b"deleted", now).unwrap().is_none());
+        assert_eq!(log.get(b"kept", now).unwrap().unwrap().value, b"2");
+    }
+
+    #[test]
+    fn corrupt_hint_file_falls_back_to_reading_the_segment() {
+        let path = format!("{}/map", file::test_dir("corrupt-hint"));
+        let log = LogEngine::open(&path, Durability::Always).unwrap();
+        put(&log, b"a", b"1");
+        put(&log, b"b", b"2");
+        put(&log, b"c", b"3");
+        delete(&log, b"b");
+        log.compact().unwrap();
+        let merged = *LogEngine::sealed(&log.state.read().unwrap())
+            .last()
+            .unwrap();
+        put(&log, b"d", b"4");
+        drop(log);
+
+        let hint_path = format!("{path}.{merged:06}.hint");
+        let mut hint = fs::read(&hint_path).unwrap();
+        let last = hint.len() - 1;
+        hint[last] ^= 0xff;
+        fs::write(&hint_path, hint).unwrap();
+
+        let log = LogEngine::open(&path, Durability::Always).unwrap();
+        assert_eq!(value(&log, b"a").unwrap(), b"1");
+        assert!(value(&log, b"b").is_none());
+        assert_eq!(value(&log, b"c").unwrap(), b"3");
+        assert_eq!(value(&log, b"d").unwrap(), b"4");
+    }
+
+    fn open_with(path: &str, data: &[u8]) -> Result<LogEngine, Box<dyn error::Error>> {
+        fs::write(path, data).unwrap();
+        LogEngine::open(path, Durability::Always)
+    }
+
+    fn value(log: &LogEngine, k: &[u8]) -> Option<Vec<u8>> {
+        let entry = log.get(k, engine::now()).unwrap();
+        entry.map(|entry| entry.value)
+    }
+
+    fn map_file(entries: &[reader::Entry]) -> Vec<u8> {
+        let mut data = header::Header::new().to_bytes().to_vec();
+        for entry in entries {
+            data.extend_from_slice(&entry.to_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn torn_tail_is_quarantined_and_cut_off() {
+        let path = format!("{}/map", file::test_dir("torn-tail"));
+        let whole = map_file(&[
+            reader::Entry::new(b"a", b"1", 1, 0),
+            reader::Entry::new(b"b", b"2", 2, 0),
+        ]);
+        let torn = reader::Entry::new(b"c", b"3", 3, 0).to_bytes();
+        let mut data = whole.clone();
+        data.extend_from_slice(&torn[..torn.len() - 1]);
+
+        let log = open_with(&path, &data).unwrap();
+        assert_eq!(value(&log, b"a"), Some(b"1".to_vec()));
+        assert_eq!(value(&log, b"b"), Some(b"2".to_vec()));
+        assert_eq!(value(&log, b"c"), None);
+        assert_eq!(fs::read(&path).unwrap(), whole);
+        let quarantined = fs::read(format!("{path}.torn-{}", whole.len())).unwrap();
+        assert_eq!(quarantined, torn[..torn.len() - 1]);
+
+        // the next write goes where the torn one was
+        put(&log, b"c", b"3");
+        assert_eq!(value(&log, b"c"), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn half_written_last_entry_is_cut_off() {
+        let path = format!("{}/map", file::test_dir("bad-last-entry"));
+        let whole = map_file(&[reader::Entry::new(b"a", b"1", 1, 0)]);
+        let mut data = whole.clone();
+        let mut last = reader::Entry::new(b"b", b"2", 2, 0).to_bytes();
+        *last.last_mut().unwrap() ^= 0xff;
+        data.extend_from_slice(&last);
+
+        let log = open_with(&path, &data).unwrap();
+        assert_eq!(value(&log, b"a"), Some(b"1".to_vec()));
+        assert_eq!(value(&log, b"b"), None);
+        assert_eq!(fs::read(&path).unwrap(), whole);
+    }
+
+    #[test]
+    fn unfinished_batch_is_cut_off() {
+        let path = format!("{}/map", file::test_dir("unfinished-batch"));
+        let whole = map_file(&[reader::Entry::new(b"a", b"1", 1, 0)]);
+        let mut data = whole.clone();
+        data.extend_from_slice(&reader::Entry::marker(reader::Kind::BatchBegin).to_bytes());
+        data.extend_from_slice(&reader::Entry::new(b"b", b"2", 2, 0).to_bytes());
+
+        let log = open_with(&path, &data).unwrap();
+        assert_eq!(value(&log, b"b"), None);
+        assert_eq!(fs::read(&path).unwrap(), whole);
+        assert!(fs::metadata(format!("{path}.torn-{}", whole.len())).is_ok());
+    }
+
+    #[test]
+    fn corruption_before_the_tail_is_left_alone() {
+        let path = format!("{}/map", file::test_dir("corrupt-middle"));
+        let mut data = map_file(&[
+            reader::Entry::new(b"a", b"1", 1, 0),
+            reader::Entry::new(b"b", b"2", 2, 0),
+        ]);
+        data[header::HEADER_LEN + 1] ^= 0xff;
+
+        let err = open_with(&path, &data).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(Error::Corrupt { .. })));
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    // an entry the way format version `format_version` lays it out
+    fn old_entry(
+        format_version: u16,
+        kind: reader::Kind,
+        key: &[u8],
+        value: &[u8],
+        version: u64,
+    ) -> Vec<u8> {
+        let mut body = Vec::<u8>::new();
+        if format_version >= 4 {
+            varint::encode(version, &mut body);
+        }
+        if format_version >= 5 {
+            varint::encode(0, &mut body);
+        }
+        if format_version >= 2 {
+            varint::encode(key.len() as u64, &mut body);
+            varint::encode(value.len() as u64, &mut body);
+        } else {
+            body.extend_from_slice(&(key.len() as u16).to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        }
+        body.extend_from_slice(key);
+        body.extend_from_slice(value);
+
+        let mut bytes = vec![kind as u8];
+        if format_version >= 1 {
+            bytes.extend_from_slice(&crc::checksum(&body).to_be_bytes());
+        }
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    fn old_map_file(format_version: u16, entries: &[(reader::Kind, &[u8], &[u8])]) -> Vec<u8> {
+        let mut data = match format_version {
+            0 => Vec::new(),
+            version => header::Header { version, flags: 0 }.to_bytes().to_vec(),
+        };
+        for (i, (kind, key, value)) in entries.iter().enumerate() {
+            data.extend_from_slice(&old_entry(format_version, *kind, key, value, i as u64 + 1));
+        }
+        data
+    }
+
+    #[test]
+    fn every_older_format_is_migrated() {
+        use reader::Kind::{Dead, Put, Tombstone};
+
+        for format_version in 0..header::VERSION {
+            let path = format!(
+                "{}/map",
+                file::test_dir(&format!("migrate-{format_version}"))
+            );
+            let deleted = match format_version {
+                0..3 => Dead,
+                _ => Tombstone,
+            };
+            let mut entries: Vec<(reader::Kind, &[u8], &[u8])> = vec![
+                (Put, b"a", b"1"),
+                (Put, b"b", b"2"),
+                (Put, b"a", b"3"),
+                (Put, b"c", &[0xff; 300]),
+            ];
+            match deleted {
+                // before tombstones, a delete flipped the put's kind byte in place
+                Dead => entries[1].0 = Dead,
+                _ => entries.push((Tombstone, b"b", b"")),
+            }
+
+            let log = open_with(&path, &old_map_file(format_version, &entries)).unwrap();
+            assert_eq!(value(&log, b"a"), Some(b"3".to_vec()));
+            assert_eq!(value(&log, b"b"), None);
+            assert_eq!(value(&log, b"c"), Some(vec![0xff; 300]));
+
+            let data = fs::read(&path).unwrap();
+            let header = header::Header::from_bytes(&data).unwrap().unwrap();
+            assert_eq!(header.version, header::VERSION);
+
+            // versions are never handed out twice, even to entries that were too old to have one
+            let a = log.get(b"a", engine::now()).unwrap().unwrap().version;
+            let c = log.get(b"c", engine::now()).unwrap().unwrap().version;
+            assert!(a > 0 && c > 0 && a != c);
+            put(&log, b"d", b"4");
+            let d = log.get(b"d", engine::now()).unwrap().unwrap().version;
+            assert!(d > a && d > c);
+            drop(log);
+
+            let log = LogEngine::open(&path, Durability::Always).unwrap();
+            assert_eq!(value(&log, b"a"), Some(b"3".to_vec()));
+            assert_eq!(value(&log, b"b"), None);
+            assert_eq!(value(&log, b"d"), Some(b"4".to_vec()));
+        }
+    }
+
+    #[test]
+    fn garbage_without_a_header_is_not_a_map() {
+        let path = format!("{}/map", file::test_dir("not-a-map"));
+        let err = open_with(&path, b"hello, world\n").err().unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::InvalidHeader { .. })
+        ));
+        assert_eq!(fs::read(&path).unwrap(), b"hello, world\n");
+    }
+
+    #[test]
+    fn expired_keys_come_from_what_is_live() {
+        let path = format!("{}/map", file::test_dir("expired-keys"));
+        let log = LogEngine::open(&path, Durability::Always).unwrap();
+        let mut batch = WriteBatch::new();
+        for key in [b"a", b"b", b"c"] {
+            batch.put_with_ttl(key, b"1", time::Duration::from_millis(1));
+        }
+        batch.put_with_ttl(b"d", b"1", time::Duration::from_secs(100));
+        write(&log, batch);
+        put(&log, b"b", b"2");
+        delete(&log, b"c");
+        thread::sleep(time::Duration::from_millis(5));
+
+        let now = engine::now();
+        assert_eq!(log.expired_keys(10, now).unwrap(), [b"a"]);
+        drop(log);
+
+        // opening leaves out whatever has expired already, but keeps track of what will
+        let log = LogEngine::open(&path, Durability::Always).unwrap();
+        assert!(log.expired_keys(10, now).unwrap().is_empty());
+        assert_eq!(log.expired_keys(10, u64::MAX).unwrap(), [b"d"]);
+    }
+
+    #[test]
+    fn only_one_process_opens_a_map() {
+        let path = format!("{}/map", file::test_dir("lifetime-lock"));
+        let log = LogEngine::open(&path, Durability::Always).unwrap();
+        put(&log, b"a", b"1");
+        // a second open fd is as good as another process, since flock locks belong to the fd
+        let err = LogEngine::open(&path, Durability::Always).err().unwrap();
+        assert!(err.to_string().contains("in use"), "{err}");
+
+        drop(log);
+        let log = LogEngine::open(&path, Durability::Always).unwrap();
+        assert_eq!(value(&log, b"a"), Some(b"1".to_vec()));
+    }
+}
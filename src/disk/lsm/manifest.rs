@@ -0,0 +1,103 @@
+use nix::{errno, fcntl, fcntl::OFlag, sys::stat::Mode};
+use std::error;
+use std::os::fd::AsFd;
+
+use crate::disk::file;
+
+const MAGIC_LINE: &str = "diskmap-lsm 1";
+
+// which tables make up the map, newest first, and the counters that have to survive a restart.
+// it's small, so it's kept as text and rewritten whole every time a table comes or goes
+#[derive(Default)]
+pub struct Manifest {
+    pub next_id: u64,
+    pub last_version: u64,
+    pub tables: Vec<u64>,
+}
+
+impl Manifest {
+    // a map that was never flushed has no manifest yet
+    pub fn load(path: &str) -> Result<Manifest, Box<dyn error::Error>> {
+        let fd = match fcntl::open(path, OFlag::O_RDONLY, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(errno::Errno::ENOENT) => return Ok(Manifest::default()),
+            Err(err) => return Err(err.into()),
+        };
+        let text = String::from_utf8(file::read_all(fd.as_fd())?)?;
+
+        let mut lines = text.lines();
+        if lines.next() != Some(MAGIC_LINE) {
+            return Err(format!("{path} is not a manifest").into());
+        }
+        let mut manifest = Manifest::default();
+        for line in lines {
+            let bad_line = || format!("bad line in {path}: {line}");
+            let (name, n) = line.split_once(' ').ok_or_else(bad_line)?;
+            let n: u64 = n.parse().map_err(|_| bad_line())?;
+            match name {
+                "next_id" => manifest.next_id = n,
+                "last_version" => manifest.last_version = n,
+                "table" => manifest.tables.push(n),
+                _ => return Err(bad_line().into()),
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn error::Error>> {
+        let mut text = format!(
+            "{MAGIC_LINE}\nnext_id {}\nlast_version {}\n",
+            self.next_id, self.last_version
+        );
+        for id in &self.tables {
+            text.push_str(&format!("table {id}\n"));
+        }
+        file::replace_atomically(path, text.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_manifest_loads_back() {
+        let path = format!("{}/MANIFEST", file::test_dir("manifest-round-trip"));
+        let manifest = Manifest {
+            next_id: 7,
+            last_version: 42,
+            tables: vec![6, 4, 1],
+        };
+        manifest.save(&path).unwrap();
+
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.next_id, 7);
+        assert_eq!(loaded.last_version, 42);
+        assert_eq!(loaded.tables, [6, 4, 1]);
+    }
+
+    #[test]
+    fn missing_manifest_is_empty() {
+        let path = format!("{}/MANIFEST", file::test_dir("manifest-missing"));
+        let manifest = Manifest::load(&path).unwrap();
+        assert_eq!(manifest.next_id, 0);
+        assert_eq!(manifest.last_version, 0);
+        assert!(manifest.tables.is_empty());
+    }
+
+    #[test]
+    fn anything_else_is_refused() {
+        let dir = file::test_dir("manifest-bad");
+        for text in [
+            "not a manifest\n",
+            "diskmap-lsm 1\nnext_id\n",
+            "diskmap-lsm 1\nnext_id x\n",
+            "diskmap-lsm 1\ntables 1\n",
+        ] {
+            let path = format!("{dir}/MANIFEST");
+            file::replace_atomically(&path, text.as_bytes()).unwrap();
+            assert!(Manifest::load(&path).is_err(), "{text}");
+        }
+    }
+}
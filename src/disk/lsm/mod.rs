@@ -0,0 +1,1085 @@
+use nix::{errno, fcntl, fcntl::OFlag, libc, sys::stat, sys::stat::Mode, unistd};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::os::fd::{AsFd, OwnedFd};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::{error, fs, mem, ops, thread, time};
+
+use crate::disk::durability::{Durability, Flusher};
+use crate::disk::engine::{self, Engine, Info, Outcome, Stamp, Stats, Usage, Visit, Written};
+use crate::disk::file::{self, FileInfo};
+use crate::disk::log::LogEngine;
+use crate::disk::map::{Op, Page, WriteBatch};
+use crate::disk::{error::Error, header, reader};
+
+mod manifest;
+mod sstable;
+
+use manifest::Manifest;
+use sstable::SsTable;
+
+// once the memtable holds this many bytes of entries, it gets frozen and written out as a table
+const MEMTABLE_LIMIT: usize = 4 * 1024 * 1024;
+
+// tables are grouped into tiers by size, each tier this many times the size of the one below.
+// once this many tables in a row are in the same tier, they are merged into one
+const FANOUT: usize = 4;
+
+// how often the merger looks for a frozen memtable without being told about one, which is how
+// one it failed to write out gets tried again
+const RETRY_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+type Source<'a> = Box<dyn Iterator<Item = Result<reader::Entry, Box<dyn error::Error>>> + 'a>;
+
+// the latest put or tombstone of every key, sorted by key
+type Memtable = BTreeMap<Vec<u8>, reader::Entry>;
+
+// one batch, encoded and ready to be appended to the wal along with the rest of its group
+struct EncodedBatch<'a> {
+    buf: Vec<u8>,
+    entries: Vec<reader::Entry>,
+    changes: HashMap<&'a [u8], Option<Stamp>>,
+    last_version: u64,
+}
+
+// what was read back from a wal
+struct Replayed {
+    memtable: Memtable,
+    memtable_bytes: usize,
+    last_version: u64,
+    format_version: u16,
+}
+
+struct State {
+    // everything written since the memtable was last frozen
+    memtable: Memtable,
+    // how many bytes of entries the memtable holds. an overwritten entry stops counting
+    memtable_bytes: usize,
+    // opened for appending. replaced along with the wal every time the memtable is frozen
+    wal: OwnedFd,
+    // the memtable before this one, while the merger writes it out as a table. it's still read
+    // from until that table replaces it, and its entries are in the frozen wal until then
+    frozen: Option<Arc<Memtable>>,
+    // newest first, so the first one with an entry for a key has its latest entry
+    tables: Vec<Arc<SsTable>>,
+    // only changes while `merging` is held
+    next_id: u64,
+    last_version: u64,
+}
+
+impl State {
+    // the latest entry for `k`, which might be a tombstone or have expired
+    fn latest(&self, k: &[u8]) -> Result<Option<reader::Entry>, Box<dyn error::Error>> {
+        if let Some(entry) = self.memtable.get(k) {
+            return Ok(Some(entry.clone()));
+        }
+        if let Some(entry) = self.frozen.as_ref().and_then(|frozen| frozen.get(k)) {
+            return Ok(Some(entry.clone()));
+        }
+        for table in &self.tables {
+            if let Some(entry) = table.get(k)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    fn stamp(&self, k: &[u8]) -> Result<Option<Stamp>, Box<dyn error::Error>> {
+        Ok(self
+            .latest(k)?
+            .filter(|entry| entry.kind == reader::Kind::Put)
+            .map(|entry| Stamp {
+                version: entry.version,
+                expires_at: entry.expires_at,
+            }))
+    }
+}
+
+// what the merger has been asked to do
+struct Work {
+    // a memtable was frozen and is waiting to be written out
+    frozen: bool,
+    stopped: bool,
+    // whether a merger is running at all. without one, writers write out what they freeze
+    merger: bool,
+}
+
+// a log-structured merge tree. writes go to a write-ahead log and a sorted memtable in memory.
+// a full memtable is frozen, and a `Merger` writes it out as an immutable sorted table and
+// merges tables of about the same size into bigger ones, while reads and writes carry on.
+// without a merger, writers do that themselves. the map is a directory of:
+//
+//   LOCK       held for as long as the map is open
+//   MANIFEST   which tables are live
+//   wal        everything written since the memtable was last frozen
+//   wal.frozen everything in the frozen memtable, until it's written out
+//   NNNNNN.sst the tables
+pub struct LsmEngine {
+    dir: String,
+    wal_path: String,
+    frozen_wal_path: String,
+    manifest_path: String,
+    flusher: Flusher,
+    state: RwLock<State>,
+    // held for as long as the tables are being written, so that only one flush or merge runs at
+    // a time and the tables only ever change under whoever holds it
+    merging: Mutex<()>,
+    work: (Mutex<Work>, Condvar),
+    // set once a failed write couldn't be cut back off the wal
+    torn: AtomicBool,
+    _lock: fcntl::Flock<OwnedFd>,
+}
+
+impl LsmEngine {
+    pub fn open(dir: &str, durability: Durability) -> Result<LsmEngine, Box<dyn error::Error>> {
+        match stat::stat(dir) {
+            Ok(st) if st.st_mode & libc::S_IFMT == libc::S_IFDIR => {}
+            Ok(_) => {
+                return Err(format!(
+                    "{dir} is not a directory. the lsm engine keeps a map in a directory"
+                )
+                .into());
+            }
+            Err(errno::Errno::ENOENT) => {
+                unistd::mkdir(dir, Mode::S_IRWXU | Mode::S_IRGRP | Mode::S_IXGRP)?;
+                file::fsync_parent(dir)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        // one process at a time. unlike the log engine, we keep state in memory that another
+        // process writing to the same directory would invalidate
+        let lock_path = format!("{dir}/LOCK");
+        let lock_fd = fcntl::open(
+            lock_path.as_str(),
+            OFlag::O_RDWR | OFlag::O_CREAT,
+            file::mode(),
+        )?;
+        let lock = fcntl::Flock::lock(lock_fd, fcntl::FlockArg::LockExclusiveNonblock)
+            .map_err(|(_, e)| format!("{dir} is in use by another process ({e})"))?;
+
+        let wal_path = format!("{dir}/wal");
+        let frozen_wal_path = format!("{dir}/wal.frozen");
+        let manifest_path = format!("{dir}/MANIFEST");
+        let manifest = Manifest::load(&manifest_path)?;
+        LsmEngine::remove_unreferenced(dir, &manifest)?;
+        let mut tables = Vec::<Arc<SsTable>>::with_capacity(manifest.tables.len());
+        for id in &manifest.tables {
+            tables.push(Arc::new(SsTable::open(
+                &LsmEngine::table_path(dir, *id),
+                *id,
+            )?));
+        }
+
+        // replay whatever was written since the last flush, the frozen wal first since it's older
+        let frozen = match fcntl::open(frozen_wal_path.as_str(), OFlag::O_RDWR, Mode::empty()) {
+            Ok(fd) => Some(LsmEngine::replay(&frozen_wal_path, fd)?),
+            Err(errno::Errno::ENOENT) => None,
+            Err(err) => return Err(err.into()),
+        };
+        let fd = fcntl::open(
+            wal_path.as_str(),
+            OFlag::O_RDWR | OFlag::O_CREAT,
+            file::mode(),
+        )?;
+        if stat::fstat(fd.as_fd())?.st_size == 0 {
+            file::write_all(fd.as_fd(), &header::Header::new().to_bytes())?;
+            unistd::fsync(fd.as_fd())?;
+            file::fsync_parent(&wal_path)?;
+        }
+        let replayed = LsmEngine::replay(&wal_path, fd)?;
+        let last_version = [manifest.last_version, replayed.last_version]
+            .into_iter()
+            .chain(frozen.as_ref().map(|frozen| frozen.last_version))
+            .max()
+            .unwrap_or_default();
+        let frozen = frozen.map(|frozen| Arc::new(frozen.memtable));
+
+        let work = Work {
+            frozen: frozen.is_some(),
+            stopped: false,
+            merger: false,
+        };
+        let lsm = LsmEngine {
+            dir: String::from(dir),
+            flusher: Flusher::new(&wal_path, durability),
+            state: RwLock::new(State {
+                memtable: replayed.memtable,
+                memtable_bytes: replayed.memtable_bytes,
+                wal: LsmEngine::open_wal(&wal_path)?,
+                frozen,
+                tables,
+                next_id: manifest.next_id,
+                last_version,
+            }),
+            wal_path,
+            frozen_wal_path,
+            manifest_path,
+            merging: Mutex::new(()),
+            work: (Mutex::new(work), Condvar::new()),
+            torn: AtomicBool::new(false),
+            _lock: lock,
+        };
+
+        // a wal in an older format gets flushed right away, so that new entries never get
+        // appended to it in the current one
+        if replayed.format_version != header::VERSION {
+            let _merging = lsm.merging.lock().map_err(|e| e.to_string())?;
+            lsm.flush_all()?;
+            eprintln!(
+                "migrated {} from format version {} to {}",
+                lsm.wal_path,
+                replayed.format_version,
+                header::VERSION
+            );
+        }
+
+        Ok(lsm)
+    }
+
+    // reads the wal that `fd` has open into a memtable, cutting off a torn tail first
+    fn replay(wal_path: &str, fd: OwnedFd) -> Result<Replayed, Box<dyn error::Error>> {
+        let size = stat::fstat(fd.as_fd())?.st_size as usize;
+        let mut data = file::read_at(fd.as_fd(), 0, size)?;
+        let format_version = match header::Header::from_bytes(&data)? {
+            Some(h) => h.version,
+            None => return Err(format!("{wal_path} is missing its header").into()),
+        };
+        let mut last_version = 0;
+        if format_version == header::VERSION {
+            last_version = LogEngine::recover(wal_path, fd.as_fd(), &mut data)?;
+        }
+        drop(fd);
+
+        let mut memtable = Memtable::new();
+        let mut memtable_bytes = 0;
+        for entry in reader::ReadResult::with_version(header::HEADER_LEN, data, format_version) {
+            let entry = entry?;
+            last_version = last_version.max(entry.version);
+            if matches!(entry.kind, reader::Kind::Put | reader::Kind::Tombstone) {
+                memtable_bytes += entry.len;
+                if let Some(old) = memtable.insert(entry.key.clone(), entry) {
+                    memtable_bytes -= old.len;
+                }
+            }
+        }
+        Ok(Replayed {
+            memtable,
+            memtable_bytes,
+            last_version,
+            format_version,
+        })
+    }
+
+    // tables that were written but never made it into the manifest, because we crashed or saving
+    // it failed, and files that were being replaced when we crashed. nothing reads any of them
+    fn remove_unreferenced(dir: &str, manifest: &Manifest) -> Result<(), Box<dyn error::Error>> {
+        let live: HashSet<u64> = manifest.tables.iter().copied().collect();
+        for dir_entry in fs::read_dir(dir)? {
+            let file_name = dir_entry?.file_name();
+            let Some(name) = file_name.to_str() else {
+                continue;
+            };
+            let unreferenced = match name.strip_suffix(".sst") {
+                Some(id) => id.parse().is_ok_and(|id| !live.contains(&id)),
+                None => name.ends_with(".tmp"),
+            };
+            if unreferenced {
+                let path = format!("{dir}/{name}");
+                unistd::unlink(path.as_str())?;
+                eprintln!("removed {path}, which the manifest doesn't list");
+            }
+        }
+        file::fsync_parent(&format!("{dir}/MANIFEST"))
+    }
+
+    fn open_wal(wal_path: &str) -> Result<OwnedFd, Box<dyn error::Error>> {
+        Ok(fcntl::open(
+            wal_path,
+            OFlag::O_WRONLY | OFlag::O_APPEND,
+            Mode::empty(),
+        )?)
+    }
+
+    fn table_path(dir: &str, id: u64) -> String {
+        format!("{dir}/{id:06}.sst")
+    }
+
+    fn try_write_group(
+        &self,
+        batches: &[WriteBatch],
+    ) -> Result<Vec<Outcome>, Box<dyn error::Error>> {
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+
+        // look up every key the group touches up front, since reading a table can fail and a
+        // failed read fails the whole group, not just one batch
+        let mut current = HashMap::<&[u8], Option<Stamp>>::new();
+        for op in batches.iter().flat_map(|batch| batch.ops()) {
+            if !current.contains_key(op.key()) {
+                current.insert(op.key(), state.stamp(op.key())?);
+            }
+        }
+
+        // encode every batch into one buffer, with later batches seeing earlier ones
+        let mut buf = Vec::<u8>::new();
+        let mut entries = Vec::<reader::Entry>::new();
+        let mut last_version = state.last_version;
+        let now = engine::now();
+        let mut outcomes = Vec::<Outcome>::with_capacity(batches.len());
+        for batch in batches {
+            let lookup = |key: &[u8]| current.get(key).copied().flatten();
+            match LsmEngine::encode_batch(batch, last_version, now, lookup) {
+                Ok(encoded) => {
+                    outcomes.push(Ok(Written {
+                        bytes: encoded.buf.len(),
+                        version: encoded.last_version,
+                    }));
+                    buf.extend_from_slice(&encoded.buf);
+                    entries.extend(encoded.entries);
+                    current.extend(encoded.changes);
+                    last_version = encoded.last_version;
+                }
+                // only this batch fails. the rest of the group never depended on it
+                Err(err) => outcomes.push(Err(err.into())),
+            }
+        }
+
+        let end = stat::fstat(state.wal.as_fd())?.st_size as usize;
+        file::append(state.wal.as_fd(), end, &buf, &self.torn, || {
+            self.flusher.wrote(state.wal.as_fd())
+        })?;
+
+        for entry in entries {
+            state.memtable_bytes += entry.len;
+            if let Some(old) = state.memtable.insert(entry.key.clone(), entry) {
+                state.memtable_bytes -= old.len;
+            }
+        }
+        state.last_version = last_version;
+
+        // while the last frozen memtable is still being written out, this one keeps growing. the
+        // group is already safe in the wal, so failing to freeze doesn't fail it, and the next
+        // write tries again
+        let full = state.memtable_bytes >= MEMTABLE_LIMIT;
+        if full && state.frozen.is_none() {
+            match self.freeze(&mut state) {
+                Ok(()) => self.wake_merger(),
+                Err(err) => eprintln!("error freezing the memtable of {}: {err}", self.dir),
+            }
+        }
+        drop(state);
+
+        // with no merger to hand it to, whoever fills the memtable writes out what is frozen
+        if full
+            && !self.has_merger()
+            && let Err(err) = self.flush_and_merge()
+        {
+            eprintln!("error writing out the memtable of {}: {err}", self.dir);
+        }
+
+        Ok(outcomes)
+    }
+
+    // encodes `batch`, numbering its entries after `last_version`. `lookup` says what a key's
+    // latest put will be once everything before this batch is written. if a version check
+    // doesn't hold, nothing of the batch is encoded
+    fn encode_batch<'a>(
+        batch: &'a WriteBatch,
+        mut last_version: u64,
+        now: u64,
+        lookup: impl Fn(&[u8]) -> Option<Stamp>,
+    ) -> Result<EncodedBatch<'a>, Error> {
+        // a batch of one is atomic without any markers
+        let atomic = batch.len() > 1;
+        let mut buf = Vec::<u8>::new();
+        let mut entries = Vec::<reader::Entry>::new();
+        let mut changes = HashMap::<&[u8], Option<Stamp>>::new();
+        if atomic {
+            buf.extend_from_slice(&reader::Entry::marker(reader::Kind::BatchBegin).to_bytes());
+        }
+
+        for op in batch.ops() {
+            let current = match changes.get(op.key()) {
+                Some(change) => *change,
+                None => lookup(op.key()),
+            };
+            if !op.applies(current, now)? {
+                continue;
+            }
+
+            // a delete always needs a tombstone here, since older tables might still have the key
+            last_version += 1;
+            let entry = match op {
+                Op::Set {
+                    key,
+                    value,
+                    expires_at,
+                    ..
+                } => {
+                    changes.insert(
+                        key,
+                        Some(Stamp {
+                            version: last_version,
+                            expires_at: *expires_at,
+                        }),
+                    );
+                    reader::Entry::new(key, value, last_version, *expires_at)
+                }
+                Op::Delete { key } | Op::DeleteExpired { key } => {
+                    changes.insert(key, None);
+                    reader::Entry::tombstone(key, last_version)
+                }
+            };
+            buf.extend_from_slice(&entry.to_bytes());
+            entries.push(entry);
+        }
+
+        if atomic {
+            buf.extend_from_slice(&reader::Entry::marker(reader::Kind::BatchCommit).to_bytes());
+        }
+        Ok(EncodedBatch {
+            buf,
+            entries,
+            changes,
+            last_version,
+        })
+    }
+
+    // sets the memtable aside for the merger to write out, along with its wal, and starts fresh
+    // ones. only called while nothing else is frozen
+    fn freeze(&self, state: &mut State) -> Result<(), Box<dyn error::Error>> {
+        // the flusher only knows the wal by its path, so whatever it hasn't synced yet would be
+        // left behind in the frozen one
+        unistd::fsync(state.wal.as_fd())?;
+        fcntl::renameat(
+            fcntl::AT_FDCWD,
+            self.wal_path.as_str(),
+            fcntl::AT_FDCWD,
+            self.frozen_wal_path.as_str(),
+        )?;
+        // fsyncs the directory, which makes the rename durable too. if we crash before the new
+        // wal is in place, opening the map starts one
+        file::replace_atomically(&self.wal_path, &header::Header::new().to_bytes())?;
+        state.wal = LsmEngine::open_wal(&self.wal_path)?;
+        state.frozen = Some(Arc::new(mem::take(&mut state.memtable)));
+        state.memtable_bytes = 0;
+        Ok(())
+    }
+
+    fn wake_merger(&self) {
+        let (lock, cvar) = &self.work;
+        if let Ok(mut work) = lock.lock() {
+            work.frozen = true;
+        }
+        cvar.notify_all();
+    }
+
+    fn has_merger(&self) -> bool {
+        let (lock, _) = &self.work;
+        lock.lock().is_ok_and(|work| work.merger)
+    }
+
+    // writes out the frozen memtable, and then merges whatever tiers that fills up. runs on the
+    // merger's thread, or on a writer's if there is no merger
+    fn flush_and_merge(&self) -> Result<(), Box<dyn error::Error>> {
+        let _merging = self.merging.lock().map_err(|e| e.to_string())?;
+        self.flush_frozen()?;
+        self.merge_tiers()
+    }
+
+    // writes out both the frozen memtable and the current one. only called while `merging` is
+    // held
+    fn flush_all(&self) -> Result<(), Box<dyn error::Error>> {
+        self.flush_frozen()?;
+        {
+            let mut state = self.state.write().map_err(|e| e.to_string())?;
+            // a writer might have frozen the memtable since, which the next flush writes out
+            if state.frozen.is_none() {
+                self.freeze(&mut state)?;
+            }
+        }
+        self.flush_frozen()
+    }
+
+    // writes the frozen memtable out as a new table, without holding up reads or writes while it
+    // does, and swaps the table in for it. only called while `merging` is held
+    fn flush_frozen(&self) -> Result<(), Box<dyn error::Error>> {
+        let (frozen, id) = {
+            let state = self.state.read().map_err(|e| e.to_string())?;
+            match &state.frozen {
+                Some(frozen) => (Arc::clone(frozen), state.next_id),
+                None => return Ok(()),
+            }
+        };
+        let entries = frozen.values().cloned().map(Ok);
+        let table = SsTable::write(&LsmEngine::table_path(&self.dir, id), id, entries)?;
+
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+        let next_id = if table.is_some() { id + 1 } else { id };
+        let mut tables = state.tables.clone();
+        tables.splice(0..0, table.map(Arc::new));
+        self.save_manifest(next_id, state.last_version, &tables)?;
+        state.tables = tables;
+        state.next_id = next_id;
+        state.frozen = None;
+
+        // the new table is in the manifest, so the frozen wal doesn't need to hold its entries
+        // anymore. if we crash before it's gone, replaying it writes the same entries again. it
+        // goes while the state is still locked, before anyone can freeze another one
+        unistd::unlink(self.frozen_wal_path.as_str())?;
+        file::fsync_parent(&self.frozen_wal_path)
+    }
+
+    // merges tables of about the same size, so that a lookup never has to look through more than
+    // a few tables per tier. only called while `merging` is held
+    fn merge_tiers(&self) -> Result<(), Box<dyn error::Error>> {
+        loop {
+            let run = {
+                let state = self.state.read().map_err(|e| e.to_string())?;
+                let tiers: Vec<usize> = state
+                    .tables
+                    .iter()
+                    .map(|t| LsmEngine::tier(t.size))
+                    .collect();
+                tiers
+                    .windows(FANOUT)
+                    .position(|window| window.iter().all(|tier| *tier == window[0]))
+            };
+            match run {
+                Some(start) => self.merge(start..(start + FANOUT))?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn tier(size: usize) -> usize {
+        let mut tier = 0;
+        let mut limit = MEMTABLE_LIMIT.saturating_mul(FANOUT);
+        while size >= limit && limit != usize::MAX {
+            tier += 1;
+            limit = limit.saturating_mul(FANOUT);
+        }
+        tier
+    }
+
+    // replaces the tables in `range`, which are next to each other, with a single table. if
+    // there are no older tables than these, tombstones and expired puts have nothing left to
+    // hide and are dropped. the merged table is written without holding up reads or writes, which
+    // never change the tables, and swapped in once it's done. only called while `merging` is
+    // held
+    fn merge(&self, range: ops::Range<usize>) -> Result<(), Box<dyn error::Error>> {
+        let (old, bottom, id) = {
+            let state = self.state.read().map_err(|e| e.to_string())?;
+            let bottom = range.end == state.tables.len();
+            (state.tables[range.clone()].to_vec(), bottom, state.next_id)
+        };
+        let now = engine::now();
+
+        let sources: Vec<Source> = old
+            .iter()
+            .map(|table| Box::new(table.range(&[], None)) as Source)
+            .collect();
+        let merged = Merge::new(sources).filter(|entry| match entry {
+            Ok(entry) if bottom => LsmEngine::visible(entry, now),
+            _ => true,
+        });
+        let table = SsTable::write(&LsmEngine::table_path(&self.dir, id), id, merged)?;
+
+        {
+            let mut state = self.state.write().map_err(|e| e.to_string())?;
+            let mut tables = state.tables.clone();
+            tables.splice(range, table.map(Arc::new));
+            self.save_manifest(id + 1, state.last_version, &tables)?;
+            state.tables = tables;
+            state.next_id = id + 1;
+        }
+        // readers that still have one of these open keep reading it until they're done
+        for table in old {
+            unistd::unlink(LsmEngine::table_path(&self.dir, table.id).as_str())?;
+        }
+        Ok(())
+    }
+
+    // every entry in the map, newest source first
+    fn sources(state: &State) -> Vec<Source<'_>> {
+        let memtable = state.memtable.values().map(|entry| Ok(entry.clone()));
+        let mut sources: Vec<Source> = vec![Box::new(memtable)];
+        if let Some(frozen) = &state.frozen {
+            sources.push(Box::new(frozen.values().map(|entry| Ok(entry.clone()))));
+        }
+        for table in &state.tables {
+            sources.push(Box::new(table.range(&[], None)));
+        }
+        sources
+    }
+
+    // everything the map takes up on disk
+    fn bytes(&self, state: &State) -> Result<usize, Box<dyn error::Error>> {
+        let mut bytes = stat::fstat(state.wal.as_fd())?.st_size as usize;
+        if state.frozen.is_some() {
+            bytes += stat::stat(self.frozen_wal_path.as_str()).map_or(0, |st| st.st_size as usize);
+        }
+        bytes += stat::stat(self.manifest_path.as_str()).map_or(0, |st| st.st_size as usize);
+        bytes += state.tables.iter().map(|table| table.size).sum::<usize>();
+        Ok(bytes)
+    }
+
+    fn save_manifest(
+        &self,
+        next_id: u64,
+        last_version: u64,
+        tables: &[Arc<SsTable>],
+    ) -> Result<(), Box<dyn error::Error>> {
+        let manifest = Manifest {
+            next_id,
+            last_version,
+            tables: tables.iter().map(|table| table.id).collect(),
+        };
+        manifest.save(&self.manifest_path)
+    }
+
+    // every entry in the wal at `wal_path`, markers aside
+    fn count_wal_entries(wal_path: &str) -> Result<usize, Box<dyn error::Error>> {
+        // the wal is only open for appending
+        let wal_fd = fcntl::open(wal_path, OFlag::O_RDONLY, Mode::empty())?;
+        let wal = file::read_all(wal_fd.as_fd())?;
+        let mut entries = 0;
+        for entry in reader::ReadResult::new(header::HEADER_LEN, wal) {
+            if entry?.kind != reader::Kind::Sequence {
+                entries += 1;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn visible(entry: &reader::Entry, now: u64) -> bool {
+        entry.kind == reader::Kind::Put
+            && !Stamp {
+                version: entry.version,
+                expires_at: entry.expires_at,
+            }
+            .expired(now)
+    }
+}
+
+impl Engine for LsmEngine {
+    // runs as the leader of a group commit. if writing fails, every batch in the group fails
+    fn write_group(&self, batches: Vec<WriteBatch>) -> Vec<Outcome> {
+        match self.try_write_group(&batches) {
+            Ok(outcomes) => outcomes,
+            Err(err) => {
+                let err = err.to_string();
+                batches.iter().map(|_| Err(err.clone().into())).collect()
+            }
+        }
+    }
+
+    fn get(&self, k: &[u8], now: u64) -> Result<Option<reader::Entry>, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let entry = state.latest(k)?;
+        Ok(entry.filter(|entry| LsmEngine::visible(entry, now)))
+    }
+
+    fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        now: u64,
+    ) -> Result<Page, Box<dyn error::Error>> {
+        // `range` panics on a range that ends before it starts
+        if let Some(end) = end
+            && start >= end
+        {
+            return Ok(Page::default());
+        }
+        let state = self.state.read().map_err(|e| e.to_string())?;
+
+        let bounds = || {
+            let end_bound = match end {
+                Some(end) => ops::Bound::Excluded(end),
+                None => ops::Bound::Unbounded,
+            };
+            (ops::Bound::Included(start), end_bound)
+        };
+        let memtable = state
+            .memtable
+            .range::<[u8], _>(bounds())
+            .map(|(_, entry)| Ok(entry.clone()));
+        let mut sources: Vec<Source> = vec![Box::new(memtable)];
+        if let Some(frozen) = &state.frozen {
+            let frozen = frozen
+                .range::<[u8], _>(bounds())
+                .map(|(_, entry)| Ok(entry.clone()));
+            sources.push(Box::new(frozen));
+        }
+        for table in &state.tables {
+            sources.push(Box::new(table.range(start, end)));
+        }
+
+        let mut live = Merge::new(sources).filter(|entry| match entry {
+            Ok(entry) => LsmEngine::visible(entry, now),
+            Err(_) => true,
+        });
+        let mut entries = Vec::<(Vec<u8>, Vec<u8>)>::new();
+        for entry in live.by_ref().take(limit) {
+            let entry = entry?;
+            entries.push((entry.key, entry.value));
+        }
+        let next = live.next().transpose()?.map(|entry| entry.key);
+
+        Ok(Page { entries, next })
+    }
+
+    // only looks through the memtable. expired keys that made it into a table are dropped when
+    // the oldest tables get merged
+    fn expired_keys(&self, limit: usize, now: u64) -> Result<Vec<Vec<u8>>, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let expired = state
+            .memtable
+            .values()
+            .filter(|entry| entry.kind == reader::Kind::Put && !LsmEngine::visible(entry, now))
+            .take(limit);
+        Ok(expired.map(|entry| entry.key.clone()).collect())
+    }
+
+    // which entries in the tables are dead only shows when they're merged
+    fn usage(&self) -> Result<Usage, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        Ok(Usage {
+            bytes: self.bytes(&state)?,
+            dead_bytes: None,
+        })
+    }
+
+    // holds up writers for as long as reading every table takes, like a full scan does. the
+    // tables are read twice: once to count every entry, and once more merged to find the live
+    // ones. entries are counted in the wals rather than the memtables, which only keep the latest
+    // one for each key
+    fn stats(&self, now: u64) -> Result<Stats, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let mut entries = LsmEngine::count_wal_entries(&self.wal_path)?;
+        if state.frozen.is_some() {
+            entries += LsmEngine::count_wal_entries(&self.frozen_wal_path)?;
+        }
+        for table in &state.tables {
+            for entry in table.range(&[], None) {
+                entry?;
+                entries += 1;
+            }
+        }
+
+        let mut stats = Stats::default();
+        for entry in Merge::new(LsmEngine::sources(&state)) {
+            let entry = entry?;
+            if LsmEngine::visible(&entry, now) {
+                stats.count_live(&entry);
+            }
+        }
+        stats.dead_entries = entries - stats.live_keys;
+        stats.dead_bytes = self.bytes(&state)?.saturating_sub(stats.live_bytes);
+        Ok(stats)
+    }
+
+    // flushes both memtables and merges every table into one, dropping everything that isn't
+    // live. reads and writes carry on while it does
+    fn compact(&self) -> Result<usize, Box<dyn error::Error>> {
+        let _merging = self.merging.lock().map_err(|e| e.to_string())?;
+        self.flush_all()?;
+        let all = 0..self.state.read().map_err(|e| e.to_string())?.tables.len();
+        if !all.is_empty() {
+            self.merge(all)?;
+        }
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        Ok(state.tables.iter().map(|table| table.size).sum())
+    }
+
+    // counting the keys means reading every table, merged
+    fn info(&self) -> Result<Info, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let mut files = vec![FileInfo::new(
+            &self.wal_path,
+            stat::fstat(state.wal.as_fd())?,
+        )];
+        if state.frozen.is_some()
+            && let Ok(st) = stat::stat(self.frozen_wal_path.as_str())
+        {
+            files.push(FileInfo::new(&self.frozen_wal_path, st));
+        }
+        if let Ok(st) = stat::stat(self.manifest_path.as_str()) {
+            files.push(FileInfo::new(&self.manifest_path, st));
+        }
+        for table in &state.tables {
+            let path = LsmEngine::table_path(&self.dir, table.id);
+            files.push(FileInfo::new(&path, stat::stat(path.as_str())?));
+        }
+
+        let mut entries = 0;
+        for entry in Merge::new(LsmEngine::sources(&state)) {
+            if entry?.kind == reader::Kind::Put {
+                entries += 1;
+            }
+        }
+        Ok(Info { files, entries })
+    }
+
+    fn snapshot(&self, now: u64, visit: &mut Visit) -> Result<(), Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        for entry in Merge::new(LsmEngine::sources(&state)) {
+            let entry = entry?;
+            if LsmEngine::visible(&entry, now) {
+                visit(&entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// writes frozen memtables out as tables on a thread of its own, and merges the tables that fills
+// up, so that a writer never waits on more than freezing the memtable. in an engine nobody runs
+// a merger for, writers wait on all of it
+pub struct Merger {
+    engine: Arc<LsmEngine>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Merger {
+    pub fn new(engine: Arc<LsmEngine>) -> Merger {
+        if let Ok(mut work) = engine.work.0.lock() {
+            work.merger = true;
+        }
+        let thread = {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || Merger::merge_when_frozen(&engine))
+        };
+
+        Merger {
+            engine,
+            thread: Some(thread),
+        }
+    }
+
+    fn merge_when_frozen(engine: &LsmEngine) {
+        let (lock, cvar) = &engine.work;
+        loop {
+            let Ok(work) = lock.lock() else { return };
+            let Ok((mut work, _)) =
+                cvar.wait_timeout_while(work, RETRY_INTERVAL, |work| !work.frozen && !work.stopped)
+            else {
+                return;
+            };
+            if work.stopped {
+                return;
+            }
+            work.frozen = false;
+            drop(work);
+
+            // whatever is frozen stays in the frozen wal, and gets tried again
+            if let Err(err) = engine.flush_and_merge() {
+                eprintln!("error writing out the memtable of {}: {err}", engine.dir);
+            }
+        }
+    }
+}
+
+impl Drop for Merger {
+    fn drop(&mut self) {
+        let (lock, cvar) = &self.engine.work;
+        if let Ok(mut work) = lock.lock() {
+            work.stopped = true;
+            work.merger = false;
+        }
+        cvar.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// merges sorted sources into one sorted stream with one entry per key. sources are given newest
+// first, and where several have an entry for the same key, the newest one wins
+struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    // the next entry of every source, or `None` once it has run out
+    heads: Vec<Option<reader::Entry>>,
+    started: bool,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Source<'a>>) -> Merge<'a> {
+        let heads = sources.iter().map(|_| None).collect();
+        Merge {
+            sources,
+            heads,
+            started: false,
+        }
+    }
+
+    fn advance(&mut self, i: usize) -> Result<(), Box<dyn error::Error>> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<reader::Entry, Box<dyn error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            for i in 0..self.sources.len() {
+                if let Err(err) = self.advance(i) {
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        // the smallest key, from the newest source that has it
+        let mut newest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some(entry) = head {
+                match newest.and_then(|j| self.heads[j].as_ref()) {
+                    Some(min) if min.key <= entry.key => {}
+                    _ => newest = Some(i),
+                }
+            }
+        }
+        let entry = self.heads[newest?].take()?;
+
+        // older entries for the same key are hidden by this one
+        for i in 0..self.sources.len() {
+            let same_key = self.heads[i].as_ref().is_some_and(|e| e.key == entry.key);
+            if (i == newest? || same_key)
+                && let Err(err) = self.advance(i)
+            {
+                return Some(Err(err));
+            }
+        }
+        Some(Ok(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(lsm: &LsmEngine, k: &[u8], v: &[u8]) {
+        let mut batch = WriteBatch::new();
+        batch.put(k, v);
+        for outcome in lsm.write_group(vec![batch]) {
+            outcome.unwrap();
+        }
+    }
+
+    fn value(lsm: &LsmEngine, k: &[u8]) -> Option<Vec<u8>> {
+        let entry = lsm.get(k, engine::now()).unwrap();
+        entry.map(|entry| entry.value)
+    }
+
+    fn freeze(lsm: &LsmEngine) {
+        let mut state = lsm.state.write().unwrap();
+        lsm.freeze(&mut state).unwrap();
+    }
+
+    #[test]
+    fn overwrites_only_count_once() {
+        let dir = file::test_dir("lsm-overwrite");
+        let lsm = LsmEngine::open(&dir, Durability::Always).unwrap();
+        for i in 0..100u8 {
+            put(&lsm, b"k", &[i; 100]);
+        }
+        let state = lsm.state.read().unwrap();
+        assert_eq!(state.memtable_bytes, state.memtable[&b"k".to_vec()].len);
+        drop(state);
+        drop(lsm);
+
+        let lsm = LsmEngine::open(&dir, Durability::Always).unwrap();
+        let state = lsm.state.read().unwrap();
+        assert_eq!(state.memtable_bytes, state.memtable[&b"k".to_vec()].len);
+    }
+
+    #[test]
+    fn frozen_memtable_is_read_until_written_out() {
+        let dir = file::test_dir("lsm-frozen");
+        let lsm = LsmEngine::open(&dir, Durability::Always).unwrap();
+        put(&lsm, b"a", b"1");
+        put(&lsm, b"b", b"2");
+        freeze(&lsm);
+        put(&lsm, b"a", b"3");
+        assert_eq!(value(&lsm, b"a"), Some(b"3".to_vec()));
+        assert_eq!(value(&lsm, b"b"), Some(b"2".to_vec()));
+        let page = lsm.scan(&[], None, 10, engine::now()).unwrap();
+        assert_eq!(page.entries.len(), 2);
+
+        // the frozen wal brings it back after a restart
+        drop(lsm);
+        let lsm = LsmEngine::open(&dir, Durability::Always).unwrap();
+        assert!(lsm.state.read().unwrap().frozen.is_some());
+        assert_eq!(value(&lsm, b"a"), Some(b"3".to_vec()));
+        assert_eq!(value(&lsm, b"b"), Some(b"2".to_vec()));
+
+        lsm.flush_and_merge().unwrap();
+        let state = lsm.state.read().unwrap();
+        assert!(state.frozen.is_none());
+        assert_eq!(state.tables.len(), 1);
+        drop(state);
+        assert!(stat::stat(lsm.frozen_wal_path.as_str()).is_err());
+        assert_eq!(value(&lsm, b"a"), Some(b"3".to_vec()));
+        assert_eq!(value(&lsm, b"b"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn merger_writes_out_what_was_frozen() {
+        let dir = file::test_dir("lsm-merger");
+        let lsm = Arc::new(LsmEngine::open(&dir, Durability::Always).unwrap());
+        let _merger = Merger::new(Arc::clone(&lsm));
+        put(&lsm, b"a", b"1");
+        freeze(&lsm);
+        lsm.wake_merger();
+
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while lsm.state.read().unwrap().frozen.is_some() {
+            assert!(time::Instant::now() < deadline);
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(lsm.state.read().unwrap().tables.len(), 1);
+        assert_eq!(value(&lsm, b"a"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn tables_the_manifest_doesnt_list_are_removed() {
+        let dir = file::test_dir("lsm-unreferenced");
+        let lsm = LsmEngine::open(&dir, Durability::Always).unwrap();
+        put(&lsm, b"a", b"1");
+        lsm.compact().unwrap();
+        let live = LsmEngine::table_path(&dir, lsm.state.read().unwrap().tables[0].id);
+        drop(lsm);
+
+        let orphan = LsmEngine::table_path(&dir, 99);
+        let tmp = format!("{live}.tmp");
+        fs::copy(&live, &orphan).unwrap();
+        fs::copy(&live, &tmp).unwrap();
+
+        let lsm = LsmEngine::open(&dir, Durability::Always).unwrap();
+        assert!(stat::stat(live.as_str()).is_ok());
+        assert!(stat::stat(orphan.as_str()).is_err());
+        assert!(stat::stat(tmp.as_str()).is_err());
+        assert_eq!(value(&lsm, b"a"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn without_a_merger_writers_write_out_full_memtables() {
+        let dir = file::test_dir("lsm-no-merger");
+        let lsm = LsmEngine::open(&dir, Durability::Never).unwrap();
+        let big = vec![7; 64 * 1024];
+        for i in 0..(MEMTABLE_LIMIT / big.len() + 1) as u32 {
+            put(&lsm, &i.to_be_bytes(), &big);
+        }
+        let state = lsm.state.read().unwrap();
+        assert!(state.frozen.is_none());
+        assert_eq!(state.tables.len(), 1);
+        drop(state);
+        assert_eq!(value(&lsm, &0u32.to_be_bytes()), Some(big));
+    }
+}
@@ -0,0 +1,231 @@
+use nix::{fcntl, fcntl::OFlag, sys::stat, sys::stat::Mode};
+use std::collections::VecDeque;
+use std::error;
+use std::os::fd::{AsFd, OwnedFd};
+
+use crate::disk::{crc, file, header, reader};
+
+// entries are read a block at a time. a block ends at the first entry that takes it past this
+// size, so a single big entry makes a block of its own
+const BLOCK_SIZE: usize = 4096;
+
+// index offset, index length and a checksum of the two
+const FOOTER_LEN: usize = 8 + 8 + 4;
+
+// an immutable file of entries sorted by key, at most one per key. the file is laid out as:
+//
+//   header
+//   blocks of entries
+//   index: one entry per block, keyed by the block's first key, with the block's offset and
+//          length as its value
+//   footer
+//
+// so opening a table only reads the index, and a lookup reads one block
+pub struct SsTable {
+    pub id: u64,
+    pub size: usize,
+    fd: OwnedFd,
+    format_version: u16,
+    blocks: Vec<Block>,
+}
+
+struct Block {
+    first_key: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl SsTable {
+    // writes `entries`, which have to be sorted by key, to a new table at `path`. returns `None`
+    // without writing anything if there are no entries
+    pub fn write(
+        path: &str,
+        id: u64,
+        entries: impl Iterator<Item = Result<reader::Entry, Box<dyn error::Error>>>,
+    ) -> Result<Option<SsTable>, Box<dyn error::Error>> {
+        let mut buf = Vec::<u8>::from(header::Header::new().to_bytes());
+        let mut blocks = Vec::<Block>::new();
+        for entry in entries {
+            let entry = entry?;
+            match blocks.last_mut() {
+                Some(block) if block.len < BLOCK_SIZE => {}
+                _ => blocks.push(Block {
+                    first_key: entry.key.clone(),
+                    offset: buf.len(),
+                    len: 0,
+                }),
+            }
+
+            let entry_bytes = entry.to_bytes();
+            buf.extend_from_slice(&entry_bytes);
+            if let Some(block) = blocks.last_mut() {
+                block.len += entry_bytes.len();
+            }
+        }
+        if blocks.is_empty() {
+            return Ok(None);
+        }
+
+        let index_offset = buf.len();
+        for block in &blocks {
+            let mut handle = Vec::<u8>::with_capacity(16);
+            handle.extend_from_slice(&(block.offset as u64).to_be_bytes());
+            handle.extend_from_slice(&(block.len as u64).to_be_bytes());
+            buf.extend_from_slice(&reader::Entry::new(&block.first_key, &handle, 0, 0).to_bytes());
+        }
+        let index_len = buf.len() - index_offset;
+
+        let mut footer = Vec::<u8>::with_capacity(FOOTER_LEN);
+        footer.extend_from_slice(&(index_offset as u64).to_be_bytes());
+        footer.extend_from_slice(&(index_len as u64).to_be_bytes());
+        footer.extend_from_slice(&crc::checksum(&footer).to_be_bytes());
+        buf.extend_from_slice(&footer);
+
+        file::replace_atomically(path, &buf)?;
+        SsTable::open(path, id).map(Some)
+    }
+
+    pub fn open(path: &str, id: u64) -> Result<SsTable, Box<dyn error::Error>> {
+        let fd = fcntl::open(path, OFlag::O_RDONLY, Mode::empty())?;
+        let size = stat::fstat(fd.as_fd())?.st_size as usize;
+        let corrupt = |reason: &str| -> Box<dyn error::Error> {
+            format!("table {path} is corrupt: {reason}").into()
+        };
+        if size < header::HEADER_LEN + FOOTER_LEN {
+            return Err(corrupt("too short"));
+        }
+
+        let header_bytes = file::read_at(fd.as_fd(), 0, header::HEADER_LEN)?;
+        let format_version = match header::Header::from_bytes(&header_bytes)? {
+            Some(h) => h.version,
+            None => return Err(corrupt("missing magic bytes")),
+        };
+
+        let footer = file::read_at(fd.as_fd(), size - FOOTER_LEN, FOOTER_LEN)?;
+        let stored_checksum = u32::from_be_bytes([footer[16], footer[17], footer[18], footer[19]]);
+        if crc::checksum(&footer[..16]) != stored_checksum {
+            return Err(corrupt("footer checksum mismatch"));
+        }
+        let index_offset = SsTable::read_u64(&footer[..8]) as usize;
+        let index_len = SsTable::read_u64(&footer[8..16]) as usize;
+        if index_offset + index_len > size - FOOTER_LEN {
+            return Err(corrupt("index runs past the footer"));
+        }
+
+        let index = file::read_at(fd.as_fd(), index_offset, index_len)?;
+        let mut blocks = Vec::<Block>::new();
+        let mut offset = 0;
+        while offset < index.len() {
+            let entry = reader::Entry::from_versioned_bytes(&index, offset, format_version)
+                .map_err(|err| err.shifted(index_offset))?;
+            if entry.value.len() != 16 {
+                return Err(corrupt("bad block handle in index"));
+            }
+            blocks.push(Block {
+                first_key: entry.key,
+                offset: SsTable::read_u64(&entry.value[..8]) as usize,
+                len: SsTable::read_u64(&entry.value[8..]) as usize,
+            });
+            offset += entry.len;
+        }
+
+        Ok(SsTable {
+            id,
+            size,
+            fd,
+            format_version,
+            blocks,
+        })
+    }
+
+    // the entry for `k`, which might be a tombstone
+    pub fn get(&self, k: &[u8]) -> Result<Option<reader::Entry>, Box<dyn error::Error>> {
+        // the last block that starts at or before `k`
+        let i = self
+            .blocks
+            .partition_point(|block| block.first_key.as_slice() <= k);
+        if i == 0 {
+            return Ok(None);
+        }
+
+        let entries = self.read_block(i - 1)?;
+        Ok(entries.into_iter().find(|entry| entry.key == k))
+    }
+
+    // the entries from `start` up to but not including `end`, in order
+    pub fn range(&self, start: &[u8], end: Option<&[u8]>) -> Range<'_> {
+        let block = self
+            .blocks
+            .partition_point(|block| block.first_key.as_slice() <= start)
+            .saturating_sub(1);
+        Range {
+            table: self,
+            next_block: block,
+            entries: VecDeque::new(),
+            start: start.to_vec(),
+            end: end.map(|end| end.to_vec()),
+        }
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<reader::Entry>, Box<dyn error::Error>> {
+        let block = &self.blocks[i];
+        let buf = file::read_at(self.fd.as_fd(), block.offset, block.len)?;
+
+        let mut entries = Vec::<reader::Entry>::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            let entry = reader::Entry::from_versioned_bytes(&buf, offset, self.format_version)
+                .map_err(|err| err.shifted(block.offset))?;
+            offset += entry.len;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn read_u64(bytes: &[u8]) -> u64 {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(bytes);
+        u64::from_be_bytes(buf)
+    }
+}
+
+// reads a range of a table one block at a time
+pub struct Range<'a> {
+    table: &'a SsTable,
+    next_block: usize,
+    entries: VecDeque<reader::Entry>,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+}
+
+impl Iterator for Range<'_> {
+    type Item = Result<reader::Entry, Box<dyn error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.pop_front() {
+                if entry.key < self.start {
+                    continue;
+                }
+                if self.end.as_ref().is_some_and(|end| &entry.key >= end) {
+                    self.entries.clear();
+                    self.next_block = self.table.blocks.len();
+                    return None;
+                }
+                return Some(Ok(entry));
+            }
+
+            if self.next_block >= self.table.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries.extend(entries),
+                Err(err) => {
+                    self.next_block = self.table.blocks.len();
+                    return Some(Err(err));
+                }
+            }
+            self.next_block += 1;
+        }
+    }
+}
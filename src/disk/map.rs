@@ -1,256 +1,623 @@
-use nix::{fcntl, fcntl::OFlag, libc, sys, sys::stat::Mode, unistd};
-use std::collections::{self, HashMap};
-use std::ops::Deref;
-use std::os::fd::{AsFd, AsRawFd};
-use std::{error, ffi, io, os, process, thread, time};
+use std::sync::Arc;
+use std::{error, fmt, io, mem, str, time};
+
+use crate::disk::commit::CommitQueue;
+use crate::disk::compactor::{self, Compactor};
+use crate::disk::durability::Durability;
+use crate::disk::engine::{self, Engine, Info, Outcome, Stamp, Stats, Written};
+use crate::disk::error::Error;
+use crate::disk::export::{self, Format};
+use crate::disk::log::LogEngine;
+use crate::disk::lsm::{LsmEngine, Merger};
+use crate::disk::snapshot;
+use crate::disk::store::KvStore;
+
+// how many entries export reads at a time, and import and restore write in one batch
+const TRANSFER_CHUNK: usize = 1000;
+
+// one change a batch makes to a key
+pub enum Op {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // only write if the key is still at this version. 0 means the key must not exist
+        expected: Option<u64>,
+        expires_at: u64,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    // deletes the key only if it has expired by the time the batch is written, since it might
+    // have been set again in the meantime
+    DeleteExpired {
+        key: Vec<u8>,
+    },
+}
 
-use crate::disk::reader;
+impl Op {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Op::Set { key, .. } | Op::Delete { key } | Op::DeleteExpired { key } => key,
+        }
+    }
 
-pub struct DiskMap {
-    file_path: String,
+    // whether the op does anything to a key whose latest entry is `current`, which counts as
+    // gone if it has expired by `now`. a version check that doesn't hold is an error, and fails
+    // the whole batch
+    pub fn applies(&self, current: Option<Stamp>, now: u64) -> Result<bool, Error> {
+        let live = current.filter(|stamp| !stamp.expired(now));
+        match self {
+            Op::Set {
+                key,
+                expected: Some(expected),
+                ..
+            } => {
+                let actual = live.map_or(0, |stamp| stamp.version);
+                if *expected != actual {
+                    return Err(Error::VersionMismatch {
+                        key: key.clone(),
+                        expected: *expected,
+                        actual,
+                    });
+                }
+                Ok(true)
+            }
+            Op::Set { .. } => Ok(true),
+            Op::Delete { .. } => Ok(current.is_some()),
+            Op::DeleteExpired { .. } => Ok(current.is_some() && live.is_none()),
+        }
+    }
 }
 
-impl DiskMap {
-    pub fn new(file_path: &str) -> Result<DiskMap, Box<dyn error::Error>> {
-        Ok(DiskMap {
-            file_path: String::from(file_path),
-        })
+// puts and deletes that are written all together or not at all. a single `set` or `delete` is a
+// batch of one
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<Op>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
     }
 
-    pub fn set(&self, k: &str, v: &str) -> Result<isize, Box<dyn error::Error>> {
-        // open file
-        let fd = fcntl::open(
-            self.file_path.deref(),
-            OFlag::O_RDWR | OFlag::O_CREAT,
-            Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IROTH,
-        )?;
+    pub fn put(&mut self, k: &[u8], v: &[u8]) {
+        self.push_set(k, v, None, 0);
+    }
 
-        // acquire exclusive lock
-        let lock = fcntl::Flock::lock(fd, fcntl::FlockArg::LockExclusive).map_err(|(_, e)| e)?;
+    // like `put`, but if `k` isn't at version `expected` by the time the batch is written, the
+    // whole batch fails
+    pub fn put_if(&mut self, k: &[u8], v: &[u8], expected: u64) {
+        self.push_set(k, v, Some(expected), 0);
+    }
 
-        thread::sleep(time::Duration::from_secs(10));
+    // like `put`, but `k` disappears once `ttl` has passed
+    pub fn put_with_ttl(&mut self, k: &[u8], v: &[u8], ttl: time::Duration) {
+        self.push_set(k, v, None, engine::expiry(ttl));
+    }
 
-        // delete pre-existing key (if exists)
-        self._delete(lock.as_fd(), k)?;
+    fn push_set(&mut self, k: &[u8], v: &[u8], expected: Option<u64>, expires_at: u64) {
+        self.ops.push(Op::Set {
+            key: k.to_vec(),
+            value: v.to_vec(),
+            expected,
+            expires_at,
+        });
+    }
 
-        // append key
-        let size = DiskMap::append_key(lock.as_fd(), k, v)?;
+    pub fn delete(&mut self, k: &[u8]) {
+        self.ops.push(Op::Delete { key: k.to_vec() });
+    }
 
-        // release lock
-        let _ = lock.unlock().map_err(|(_, e)| e)?;
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
 
-        Ok(size)
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
     }
 
-    pub fn get(&self, k: &str) -> Result<String, Box<dyn error::Error>> {
-        Ok(self
-            .read()?
-            .find(|x| x.key == k)
-            .ok_or(format!("{k} not found"))?
-            .value)
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
     }
 
-    pub fn dump(&self) -> Result<HashMap<String, String>, Box<dyn error::Error>> {
-        let read_result = self.read()?;
+    // fails on the first key or value being set that is over its limit
+    pub fn check_sizes(&self, max_key_size: usize, max_value_size: usize) -> Result<(), Error> {
+        for op in &self.ops {
+            if let Op::Set { key, value, .. } = op {
+                DiskMap::check_size("key", key.len(), max_key_size)?;
+                DiskMap::check_size("value", value.len(), max_value_size)?;
+            }
+        }
+        Ok(())
+    }
+}
 
-        let mut m = HashMap::<String, String>::new();
-        for entry in read_result {
-            m.insert(entry.key, entry.value);
+// one page of a scan, in key order. if there is more to read, `next` is the key to start the next
+// page at
+#[derive(Default)]
+pub struct Page {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    pub next: Option<Vec<u8>>,
+}
+
+// which engine stores the map. `Log` keeps everything in one file, `Lsm` keeps a directory of
+// sorted tables that suits write-heavy workloads better
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Log,
+    Lsm,
+}
+
+impl str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "log" => Ok(Backend::Log),
+            "lsm" => Ok(Backend::Lsm),
+            _ => Err(format!("expected log or lsm, got {s}")),
         }
-        Ok(m)
-    }
-
-    pub fn size(&self) -> Result<String, Box<dyn error::Error>> {
-        let (r, w) = unistd::pipe()?;
-        match unsafe { unistd::fork() } {
-            Ok(unistd::ForkResult::Parent { child, .. }) => {
-                // we don't need to write as a parent, just read
-                unistd::close(w)?;
-                // wait for our child to terminate
-                sys::wait::waitpid(child, None)?;
-                // read from our child
-                let mut buf = [0u8; 1024];
-                let mut v: Vec<u8> = Vec::new();
-
-                loop {
-                    let n = unistd::read(&r, &mut buf)?;
-                    if n == 0 {
-                        break;
-                    }
-
-                    v.extend_from_slice(&buf[..n]);
-                }
-                Ok(String::from_utf8(v)?.trim().to_string())
-            }
-            Ok(unistd::ForkResult::Child) => {
-                // we don't need to read as a child, just write
-                unistd::close(r)?;
-                // make a copy our writing fd. it should be given the same fd as stdout. so when
-                // someone writes to stdout, it will write to the same destination as `w`
-                unistd::dup2_stdout(w)?;
-                // execv
-                let path = ffi::CString::new("/usr/bin/wc")?;
-                let arg1 = ffi::CString::new("-c")?;
-                let arg2 = ffi::CString::new(self.file_path.clone())?;
-                let argv = [&path, &arg1, &arg2];
-                let Err(err) = unistd::execv(&path, &argv);
-                // if we're here, it means execv failed. if it succeeded then the code from this process would
-                // have been replaced by now
-                eprintln!("execv failed: {}", err);
-                process::exit(1);
-            }
-            Err(err_no) => Err(err_no.into()),
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Log => write!(f, "log"),
+            Backend::Lsm => write!(f, "lsm"),
         }
     }
+}
 
-    pub fn delete(&self, k: &str) -> Result<(), Box<dyn error::Error>> {
-        // open file
-        let fd = fcntl::open(
-            self.file_path.deref(),
-            OFlag::O_RDWR | OFlag::O_CREAT,
-            Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IROTH,
-        )?;
+#[derive(Clone)]
+pub struct Options {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    pub durability: Durability,
+    pub backend: Backend,
+    pub compaction: compactor::Policy,
+}
 
-        // acquire exclusive lock
-        let lock = fcntl::Flock::lock(fd, fcntl::FlockArg::LockExclusive).map_err(|(_, e)| e)?;
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            max_key_size: 64 * 1024,
+            max_value_size: 64 * 1024 * 1024,
+            durability: Durability::Always,
+            backend: Backend::Log,
+            compaction: compactor::Policy::default(),
+        }
+    }
+}
 
-        // delete pre-existing key (if exists)
-        self._delete(lock.as_fd(), k)?;
+pub struct DiskMap {
+    options: Options,
+    commit_queue: CommitQueue<WriteBatch, Outcome>,
+    engine: Arc<dyn Engine>,
+    // only the log engine needs a compactor, and only the lsm engine a merger, which writes out
+    // its memtables and merges its tables
+    _compactor: Option<Compactor>,
+    _merger: Option<Merger>,
+}
 
-        // release lock
-        let _ = lock.unlock().map_err(|(_, e)| e)?;
+impl DiskMap {
+    // opens the map at `file_path`, which is the active segment for the log engine and a
+    // directory for the lsm engine
+    pub fn new(file_path: &str, options: Options) -> Result<DiskMap, Box<dyn error::Error>> {
+        DiskMap::open(file_path, options, true)
+    }
 
-        Ok(())
+    // opens the map for a single command that runs and exits, like an export or an import.
+    // nothing runs in the background: the log engine is never compacted, and the lsm engine
+    // writes out and merges its tables as writes fill them
+    pub fn new_for_command(
+        file_path: &str,
+        options: Options,
+    ) -> Result<DiskMap, Box<dyn error::Error>> {
+        DiskMap::open(file_path, options, false)
     }
 
-    pub fn compact(&self) -> Result<isize, Box<dyn error::Error>> {
-        // open file
-        let fd = fcntl::open(
-            self.file_path.deref(),
-            OFlag::O_RDWR | OFlag::O_CREAT,
-            Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IROTH,
-        )?;
+    fn open(
+        file_path: &str,
+        options: Options,
+        background: bool,
+    ) -> Result<DiskMap, Box<dyn error::Error>> {
+        let (engine, compactor, merger): (Arc<dyn Engine>, _, _) = match options.backend {
+            Backend::Log => {
+                let engine = Arc::new(LogEngine::open(file_path, options.durability)?);
+                let compactor =
+                    background.then(|| Compactor::new(engine.clone(), options.compaction));
+                (engine, compactor, None)
+            }
+            Backend::Lsm => {
+                let engine = Arc::new(LsmEngine::open(file_path, options.durability)?);
+                let merger = background.then(|| Merger::new(engine.clone()));
+                (engine, None, merger)
+            }
+        };
+
+        Ok(DiskMap {
+            options,
+            commit_queue: CommitQueue::new(),
+            engine,
+            _compactor: compactor,
+            _merger: merger,
+        })
+    }
 
-        // acquire exclusive lock
-        let lock = fcntl::Flock::lock(fd, fcntl::FlockArg::LockExclusive).map_err(|(_, e)| e)?;
+    pub fn durability(&self) -> Durability {
+        self.options.durability
+    }
 
-        // read all keys
-        let read_result = DiskMap::slurp(lock.as_fd())?;
+    pub fn backend(&self) -> Backend {
+        self.options.backend
+    }
 
-        // create new vec buffer
-        let mut seen = collections::HashSet::<String>::new();
-        let mut new_buf = Vec::<u8>::new();
-        for entry in read_result {
-            if !seen.insert(entry.key.clone()) {
-                continue;
+    // writes every live entry to `out` in `format`, a page at a time, so the map never has to
+    // fit in memory. returns how many entries were written
+    pub fn export(
+        &self,
+        format: Format,
+        out: &mut impl io::Write,
+    ) -> Result<usize, Box<dyn error::Error>> {
+        export::write_header(format, out)?;
+        let mut start = Vec::<u8>::new();
+        let mut n = 0;
+        loop {
+            let page = self.scan(&start, None, TRANSFER_CHUNK)?;
+            for (k, v) in &page.entries {
+                export::write_entry(format, out, k, v)?;
+            }
+            n += page.entries.len();
+            match page.next {
+                Some(next) => start = next,
+                None => return Ok(n),
             }
+        }
+    }
 
-            let entry_bytes = entry.to_bytes()?;
-            new_buf.extend_from_slice(&entry_bytes);
+    // sets every entry read from `input` in `format`, a batch at a time. if anything in the
+    // input is bad, the batches before it stay written. returns how many entries were set
+    pub fn import(
+        &self,
+        format: Format,
+        input: impl io::BufRead,
+    ) -> Result<usize, Box<dyn error::Error>> {
+        let mut batch = WriteBatch::new();
+        let mut n = 0;
+        for entry in export::Reader::new(format, input) {
+            let (k, v) = entry?;
+            batch.put(&k, &v);
+            n += 1;
+            if batch.len() == TRANSFER_CHUNK {
+                self.write(mem::take(&mut batch))?;
+            }
         }
+        self.write(batch)?;
+        Ok(n)
+    }
 
-        // seek to beginning of file
-        if unsafe { libc::lseek(lock.as_raw_fd(), 0, libc::SEEK_SET) } == -1 {
-            return Err(io::Error::last_os_error().into());
+    // sets every entry of the snapshot at `path` in a map that has nothing in it yet, keeping
+    // expiry times. entries that have expired since the snapshot was taken are left out. keys
+    // get new versions, since the map hands them out itself. returns what the snapshot says
+    // about itself, and how many entries were set
+    pub fn restore(
+        &self,
+        path: &str,
+    ) -> Result<(snapshot::Metadata, usize), Box<dyn error::Error>> {
+        if !self.scan(&[], None, 1)?.entries.is_empty() {
+            return Err("can only restore a snapshot into an empty map".into());
         }
+        let (metadata, entries) = snapshot::read(path)?;
+
+        let now = engine::now();
+        let mut batch = WriteBatch::new();
+        let mut n = 0;
+        for entry in entries {
+            let entry = entry?;
+            if entry.expires_at != 0 && entry.expires_at <= now {
+                continue;
+            }
+            batch.push_set(&entry.key, &entry.value, None, entry.expires_at);
+            n += 1;
+            if batch.len() == TRANSFER_CHUNK {
+                self.write(mem::take(&mut batch))?;
+            }
+        }
+        self.write(batch)?;
+        Ok((metadata, n))
+    }
 
-        // write new vec buffer
-        let n = unsafe { libc::write(lock.as_raw_fd(), new_buf.as_ptr().cast(), new_buf.len()) };
-        if n == -1 {
-            return Err(io::Error::last_os_error().into());
+    // writes `k` again with the same value but a different expiry. if `k` changes in between
+    // reading and writing it, try again
+    fn set_expiry(&self, k: &[u8], expires_at: u64) -> Result<(), Box<dyn error::Error>> {
+        loop {
+            let (value, version) = self.get_with_version(k)?;
+            let mut batch = WriteBatch::new();
+            batch.push_set(k, &value, Some(version), expires_at);
+            match self.commit(batch) {
+                Ok(_) => return Ok(()),
+                Err(err) if matches!(err.downcast_ref(), Some(Error::VersionMismatch { .. })) => {
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
         }
+    }
 
-        // truncate everything after
-        if unsafe { libc::ftruncate(lock.as_raw_fd(), n as i64) } == -1 {
-            return Err(io::Error::last_os_error().into());
+    fn check_size(what: &'static str, size: usize, limit: usize) -> Result<(), Error> {
+        if size > limit {
+            return Err(Error::TooLarge { what, size, limit });
         }
+        Ok(())
+    }
 
-        // release lock
-        let _ = lock.unlock().map_err(|(_, e)| e)?;
+    fn commit(&self, batch: WriteBatch) -> Result<Written, Box<dyn error::Error>> {
+        let outcome = self
+            .commit_queue
+            .submit(batch, |batches| self.engine.write_group(batches))?;
+        outcome.map_err(|err| -> Box<dyn error::Error> { err })
+    }
+}
 
-        Ok(n)
+impl KvStore for DiskMap {
+    fn get_with_version(&self, k: &[u8]) -> Result<(Vec<u8>, u64), Box<dyn error::Error>> {
+        let entry = self
+            .engine
+            .get(k, engine::now())?
+            .ok_or(Error::NotFound { key: k.to_vec() })?;
+        Ok((entry.value, entry.version))
     }
 
-    fn _delete(&self, fd: os::fd::BorrowedFd, k: &str) -> Result<(), Box<dyn error::Error>> {
-        // read into variable
-        let mut read_result = DiskMap::slurp(fd)?;
+    fn set(&self, k: &[u8], v: &[u8]) -> Result<isize, Box<dyn error::Error>> {
+        DiskMap::check_size("key", k.len(), self.options.max_key_size)?;
+        DiskMap::check_size("value", v.len(), self.options.max_value_size)?;
 
-        // if key exists, delete it
-        if let Some(entry) = read_result.find(|x| x.key == k) {
-            DiskMap::delete_entry(fd, entry)?;
+        let mut batch = WriteBatch::new();
+        batch.put(k, v);
+        let written = self.commit(batch)?;
+
+        Ok(written.bytes as isize)
+    }
+
+    fn set_with_ttl(
+        &self,
+        k: &[u8],
+        v: &[u8],
+        ttl: time::Duration,
+    ) -> Result<isize, Box<dyn error::Error>> {
+        DiskMap::check_size("key", k.len(), self.options.max_key_size)?;
+        DiskMap::check_size("value", v.len(), self.options.max_value_size)?;
+
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(k, v, ttl);
+        let written = self.commit(batch)?;
+
+        Ok(written.bytes as isize)
+    }
+
+    fn cas(&self, k: &[u8], expected: u64, v: &[u8]) -> Result<u64, Box<dyn error::Error>> {
+        DiskMap::check_size("key", k.len(), self.options.max_key_size)?;
+        DiskMap::check_size("value", v.len(), self.options.max_value_size)?;
+
+        let mut batch = WriteBatch::new();
+        batch.put_if(k, v, expected);
+        let written = self.commit(batch)?;
+
+        Ok(written.version)
+    }
+
+    // if we crash partway through writing it, none of it will be there when the map is opened
+    // again
+    fn write(&self, batch: WriteBatch) -> Result<isize, Box<dyn error::Error>> {
+        batch.check_sizes(self.options.max_key_size, self.options.max_value_size)?;
+        if batch.is_empty() {
+            return Ok(0);
         }
 
+        let written = self.commit(batch)?;
+
+        Ok(written.bytes as isize)
+    }
+
+    fn delete(&self, k: &[u8]) -> Result<(), Box<dyn error::Error>> {
+        let mut batch = WriteBatch::new();
+        batch.delete(k);
+        self.commit(batch)?;
+
         Ok(())
     }
 
-    fn append_key(
-        fd: os::fd::BorrowedFd,
-        k: &str,
-        v: &str,
-    ) -> Result<isize, Box<dyn error::Error>> {
-        // seek to end
-        if unsafe { libc::lseek(fd.as_raw_fd(), 0, libc::SEEK_END) } == -1 {
-            return Err(io::Error::last_os_error().into());
+    fn expire(&self, k: &[u8], ttl: time::Duration) -> Result<(), Box<dyn error::Error>> {
+        self.set_expiry(k, engine::expiry(ttl))
+    }
+
+    fn ttl(&self, k: &[u8]) -> Result<Option<time::Duration>, Box<dyn error::Error>> {
+        let now = engine::now();
+        let entry = self
+            .engine
+            .get(k, now)?
+            .ok_or(Error::NotFound { key: k.to_vec() })?;
+        match entry.expires_at {
+            0 => Ok(None),
+            expires_at => Ok(Some(time::Duration::from_millis(
+                expires_at.saturating_sub(now),
+            ))),
         }
+    }
 
-        let buf = reader::Entry::new(k, v).to_bytes()?;
-        let n = unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
-        if n == -1 {
-            return Err(io::Error::last_os_error().into());
+    fn persist(&self, k: &[u8]) -> Result<bool, Box<dyn error::Error>> {
+        if self.ttl(k)?.is_none() {
+            return Ok(false);
         }
+        self.set_expiry(k, 0)?;
+        Ok(true)
+    }
 
-        Ok(n)
+    fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Page, Box<dyn error::Error>> {
+        self.engine.scan(start, end, limit, engine::now())
     }
 
-    fn delete_entry(
-        fd: os::fd::BorrowedFd,
-        entry: reader::Entry,
-    ) -> Result<(), Box<dyn error::Error>> {
-        // seek to offset
-        if unsafe { libc::lseek(fd.as_raw_fd(), entry.offset as i64, libc::SEEK_SET) } == -1 {
-            return Err(io::Error::last_os_error().into());
+    // their entries take up space on disk until they are compacted away
+    fn delete_expired(&self, limit: usize) -> Result<usize, Box<dyn error::Error>> {
+        let mut batch = WriteBatch::new();
+        for key in self.engine.expired_keys(limit, engine::now())? {
+            batch.ops.push(Op::DeleteExpired { key });
         }
-
-        // overwrite byte to be 0 instead of 1
-        let del = &[0u8; 1];
-        let len = del.len() as libc::size_t;
-        if unsafe { libc::write(fd.as_raw_fd(), del.as_ptr().cast(), len) } == -1 {
-            return Err(io::Error::last_os_error().into());
+        if batch.is_empty() {
+            return Ok(0);
         }
 
-        Ok(())
+        let n = batch.len();
+        self.commit(batch)?;
+        Ok(n)
     }
 
-    fn read(&self) -> Result<reader::ReadResult, Box<dyn error::Error>> {
-        let fd = fcntl::open(
-            self.file_path.deref(),
-            OFlag::O_RDWR | OFlag::O_CREAT,
-            Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IROTH,
-        )?;
+    fn compact(&self) -> Result<isize, Box<dyn error::Error>> {
+        let n = self.engine.compact()?;
+        Ok(n as isize)
+    }
 
-        // acquire non-exclusive lock
-        let lock = fcntl::Flock::lock(fd, fcntl::FlockArg::LockShared).map_err(|(_, e)| e)?;
+    fn size(&self) -> Result<usize, Box<dyn error::Error>> {
+        Ok(self.engine.usage()?.bytes)
+    }
 
-        // slurp
-        let read_result = DiskMap::slurp(lock.as_fd())?;
+    fn info(&self) -> Result<Info, Box<dyn error::Error>> {
+        self.engine.info()
+    }
 
-        // release lock
-        let _ = lock.unlock().map_err(|(_, e)| e)?;
+    // reads the whole map
+    fn stats(&self) -> Result<Stats, Box<dyn error::Error>> {
+        self.engine.stats(engine::now())
+    }
 
-        Ok(read_result)
+    // writers wait until every live entry has been copied
+    fn snapshot(&self, path: &str) -> Result<snapshot::Metadata, Box<dyn error::Error>> {
+        let now = engine::now();
+        let mut writer = snapshot::Writer::create(path)?;
+        self.engine.snapshot(now, &mut |entry| writer.add(entry))?;
+        writer.finish(now)
     }
 
-    fn slurp(fd: os::fd::BorrowedFd) -> Result<reader::ReadResult, Box<dyn error::Error>> {
-        let mut buf = [0u8; 1024];
-        let mut v: Vec<u8> = Vec::new();
-        loop {
-            let n = unistd::read(fd, &mut buf)?;
-            if n == 0 {
-                break;
+    fn settings(&self) -> String {
+        format!(
+            "Engine: {}. Durability: {}.",
+            self.backend(),
+            self.durability()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::file;
+
+    fn open(path: &str, backend: Backend) -> DiskMap {
+        let options = Options {
+            backend,
+            ..Options::default()
+        };
+        DiskMap::new(path, options).unwrap()
+    }
+
+    #[test]
+    fn everything_is_there_after_reopening() {
+        for backend in [Backend::Log, Backend::Lsm] {
+            let path = format!("{}/map", file::test_dir(&format!("reopen-{backend}")));
+            let map = open(&path, backend);
+            map.set(b"a", b"1").unwrap();
+            map.set(b"b", b"2").unwrap();
+            map.set(b"a", b"3").unwrap();
+            map.set(&[0, 0xff, b'\n'], &[b' ', 0]).unwrap();
+            map.delete(b"b").unwrap();
+            let (_, version) = map.get_with_version(b"a").unwrap();
+            drop(map);
+
+            let map = open(&path, backend);
+            assert_eq!(
+                map.get_with_version(b"a").unwrap(),
+                (b"3".to_vec(), version)
+            );
+            assert!(map.get(b"b").is_err());
+            assert_eq!(map.get(&[0, 0xff, b'\n']).unwrap(), [b' ', 0]);
+
+            // versions carry on from where they were
+            assert!(map.cas(b"c", 0, b"4").unwrap() > version);
+        }
+    }
+
+    #[test]
+    fn everything_is_there_after_compacting() {
+        for backend in [Backend::Log, Backend::Lsm] {
+            let path = format!("{}/map", file::test_dir(&format!("compact-{backend}")));
+            let map = open(&path, backend);
+            for i in 0..100u32 {
+                map.set(&i.to_be_bytes(), &[i as u8; 100]).unwrap();
             }
+            for i in 0..50u32 {
+                map.delete(&i.to_be_bytes()).unwrap();
+            }
+            let before = map.dump().unwrap();
+            map.compact().unwrap();
+            assert_eq!(map.dump().unwrap(), before);
+            drop(map);
+
+            let map = open(&path, backend);
+            assert_eq!(map.dump().unwrap(), before);
+            assert_eq!(before.len(), 50);
+        }
+    }
+
+    #[test]
+    fn exported_map_imports_into_another() {
+        for format in [Format::JsonLines, Format::Csv] {
+            let dir = file::test_dir(&format!("export-{format}"));
+            let map = open(&format!("{dir}/from"), Backend::Log);
+            // more than fits in one page or batch
+            for i in 0..(TRANSFER_CHUNK as u32 * 2 + 1) {
+                map.set(&i.to_be_bytes(), format!("{i}\n,\"").as_bytes())
+                    .unwrap();
+            }
+            let mut out = Vec::<u8>::new();
+            let exported = map.export(format, &mut out).unwrap();
+            assert_eq!(exported, TRANSFER_CHUNK * 2 + 1);
 
-            v.extend_from_slice(&buf[..n]);
+            let other = open(&format!("{dir}/to"), Backend::Lsm);
+            assert_eq!(other.import(format, out.as_slice()).unwrap(), exported);
+            assert_eq!(other.dump().unwrap(), map.dump().unwrap());
+        }
+    }
+
+    #[test]
+    fn snapshot_restores_into_an_empty_map() {
+        for backend in [Backend::Log, Backend::Lsm] {
+            let dir = file::test_dir(&format!("restore-{backend}"));
+            let map = open(&format!("{dir}/from"), backend);
+            for i in 0..(TRANSFER_CHUNK as u32 + 1) {
+                map.set(&i.to_be_bytes(), &[i as u8; 10]).unwrap();
+            }
+            map.set_with_ttl(b"later", b"1", time::Duration::from_secs(60))
+                .unwrap();
+            let snapshot_path = format!("{dir}/snap");
+            let written = map.snapshot(&snapshot_path).unwrap();
+            assert_eq!(written.entries, TRANSFER_CHUNK + 2);
+
+            let other = open(&format!("{dir}/to"), backend);
+            let (metadata, n) = other.restore(&snapshot_path).unwrap();
+            assert_eq!(
+                (metadata.entries, n),
+                (TRANSFER_CHUNK + 2, TRANSFER_CHUNK + 2)
+            );
+            assert_eq!(other.dump().unwrap(), map.dump().unwrap());
+            assert!(other.ttl(b"later").unwrap().is_some());
+            assert!(other.restore(&snapshot_path).is_err());
         }
-        Ok(reader::ReadResult::new(0, v))
     }
 }
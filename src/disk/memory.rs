@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::RwLock;
+use std::{error, ops, time};
+
+use crate::disk::engine::{self, Info, Stamp, Stats};
+use crate::disk::error::Error;
+use crate::disk::map::{Op, Page, WriteBatch};
+use crate::disk::store::KvStore;
+use crate::disk::{reader, snapshot};
+
+// a key's value, with the version and expiry time a map file would keep next to it
+struct Item {
+    value: Vec<u8>,
+    version: u64,
+    expires_at: u64,
+}
+
+impl Item {
+    fn stamp(&self) -> Stamp {
+        Stamp {
+            version: self.version,
+            expires_at: self.expires_at,
+        }
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        self.stamp().expired(now)
+    }
+
+    // what the item counts for in `size` and `stats`
+    fn len(&self, key: &[u8]) -> usize {
+        key.len() + self.value.len()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    items: BTreeMap<Vec<u8>, Item>,
+    last_version: u64,
+    // every key that expires, soonest first
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl State {
+    fn insert(&mut self, key: &[u8], item: Item) {
+        if item.expires_at != 0 {
+            self.expiring.insert((item.expires_at, key.to_vec()));
+        }
+        if let Some(old) = self.items.insert(key.to_vec(), item) {
+            self.forget_expiry(key, &old);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(old) = self.items.remove(key) {
+            self.forget_expiry(key, &old);
+        }
+    }
+
+    // removes up to `limit` keys that have expired by `now`. returns how many it removed
+    fn remove_expired(&mut self, now: u64, limit: usize) -> usize {
+        let expired: Vec<Vec<u8>> = self
+            .expiring
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove(key);
+        }
+        expired.len()
+    }
+
+    // drops what `expiring` has on `old`, unless the item that replaced it expires at the same
+    // time
+    fn forget_expiry(&mut self, key: &[u8], old: &Item) {
+        let still = self.items.get(key).map(|item| item.expires_at);
+        if old.expires_at != 0 && still != Some(old.expires_at) {
+            self.expiring.remove(&(old.expires_at, key.to_vec()));
+        }
+    }
+}
+
+// keeps everything in memory and nothing on disk, for tests and for running as a cache that
+// starts out empty every time. batches, versions and expiry work like they do in `DiskMap`, and
+// expired keys stay around until they are deleted or compacted away, same as there. keys and
+// values have the same limits too
+pub struct MemoryStore {
+    state: RwLock<State>,
+    max_key_size: usize,
+    max_value_size: usize,
+}
+
+impl MemoryStore {
+    pub fn new(max_key_size: usize, max_value_size: usize) -> MemoryStore {
+        MemoryStore {
+            state: RwLock::new(State::default()),
+            max_key_size,
+            max_value_size,
+        }
+    }
+
+    // applies `batch`, unless one of its version checks doesn't hold. returns how many bytes of
+    // keys and values it set, and the last version it handed out
+    fn commit(&self, batch: &WriteBatch) -> Result<(usize, u64), Box<dyn error::Error>> {
+        batch.check_sizes(self.max_key_size, self.max_value_size)?;
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+        let now = engine::now();
+
+        // every op is checked before any is applied, against what the ops before it in the batch
+        // will have made of its key
+        let mut pending = HashMap::<&[u8], Option<Stamp>>::new();
+        let mut applies = Vec::<bool>::with_capacity(batch.len());
+        let mut version = state.last_version;
+        for op in batch.ops() {
+            let current = match pending.get(op.key()) {
+                Some(stamp) => *stamp,
+                None => state.items.get(op.key()).map(Item::stamp),
+            };
+            let applied = op.applies(current, now)?;
+            if applied {
+                version += 1;
+                let stamp = match op {
+                    Op::Set { expires_at, .. } => Some(Stamp {
+                        version,
+                        expires_at: *expires_at,
+                    }),
+                    Op::Delete { .. } | Op::DeleteExpired { .. } => None,
+                };
+                pending.insert(op.key(), stamp);
+            }
+            applies.push(applied);
+        }
+
+        let mut bytes = 0;
+        for (op, applied) in batch.ops().iter().zip(applies) {
+            if !applied {
+                continue;
+            }
+            state.last_version += 1;
+            match op {
+                Op::Set {
+                    key,
+                    value,
+                    expires_at,
+                    ..
+                } => {
+                    let item = Item {
+                        value: value.clone(),
+                        version: state.last_version,
+                        expires_at: *expires_at,
+                    };
+                    bytes += item.len(key);
+                    state.insert(key, item);
+                }
+                Op::Delete { key } | Op::DeleteExpired { key } => state.remove(key),
+            }
+        }
+        Ok((bytes, state.last_version))
+    }
+
+    // gives `k` a new expiry time, and with it a new version, like rewriting it would
+    fn set_expiry(&self, k: &[u8], expires_at: u64) -> Result<(), Box<dyn error::Error>> {
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+        let value = state
+            .items
+            .get(k)
+            .filter(|item| !item.expired(engine::now()))
+            .ok_or(Error::NotFound { key: k.to_vec() })?
+            .value
+            .clone();
+        state.last_version += 1;
+        let item = Item {
+            value,
+            version: state.last_version,
+            expires_at,
+        };
+        state.insert(k, item);
+        Ok(())
+    }
+
+    fn bytes(state: &State) -> usize {
+        state.items.iter().map(|(key, item)| item.len(key)).sum()
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn get_with_version(&self, k: &[u8]) -> Result<(Vec<u8>, u64), Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let item = state
+            .items
+            .get(k)
+            .filter(|item| !item.expired(engine::now()))
+            .ok_or(Error::NotFound { key: k.to_vec() })?;
+        Ok((item.value.clone(), item.version))
+    }
+
+    fn set(&self, k: &[u8], v: &[u8]) -> Result<isize, Box<dyn error::Error>> {
+        let mut batch = WriteBatch::new();
+        batch.put(k, v);
+        let (bytes, _) = self.commit(&batch)?;
+        Ok(bytes as isize)
+    }
+
+    fn set_with_ttl(
+        &self,
+        k: &[u8],
+        v: &[u8],
+        ttl: time::Duration,
+    ) -> Result<isize, Box<dyn error::Error>> {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(k, v, ttl);
+        let (bytes, _) = self.commit(&batch)?;
+        Ok(bytes as isize)
+    }
+
+    fn cas(&self, k: &[u8], expected: u64, v: &[u8]) -> Result<u64, Box<dyn error::Error>> {
+        let mut batch = WriteBatch::new();
+        batch.put_if(k, v, expected);
+        let (_, version) = self.commit(&batch)?;
+        Ok(version)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<isize, Box<dyn error::Error>> {
+        let (bytes, _) = self.commit(&batch)?;
+        Ok(bytes as isize)
+    }
+
+    fn delete(&self, k: &[u8]) -> Result<(), Box<dyn error::Error>> {
+        let mut batch = WriteBatch::new();
+        batch.delete(k);
+        self.commit(&batch)?;
+        Ok(())
+    }
+
+    fn expire(&self, k: &[u8], ttl: time::Duration) -> Result<(), Box<dyn error::Error>> {
+        self.set_expiry(k, engine::expiry(ttl))
+    }
+
+    fn ttl(&self, k: &[u8]) -> Result<Option<time::Duration>, Box<dyn error::Error>> {
+        let now = engine::now();
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let item = state
+            .items
+            .get(k)
+            .filter(|item| !item.expired(now))
+            .ok_or(Error::NotFound { key: k.to_vec() })?;
+        match item.expires_at {
+            0 => Ok(None),
+            expires_at => Ok(Some(time::Duration::from_millis(
+                expires_at.saturating_sub(now),
+            ))),
+        }
+    }
+
+    fn persist(&self, k: &[u8]) -> Result<bool, Box<dyn error::Error>> {
+        if self.ttl(k)?.is_none() {
+            return Ok(false);
+        }
+        self.set_expiry(k, 0)?;
+        Ok(true)
+    }
+
+    fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Page, Box<dyn error::Error>> {
+        // `range` panics on a range that ends before it starts
+        if let Some(end) = end
+            && start >= end
+        {
+            return Ok(Page::default());
+        }
+        let end = match end {
+            Some(end) => ops::Bound::Excluded(end),
+            None => ops::Bound::Unbounded,
+        };
+        let now = engine::now();
+        let state = self.state.read().map_err(|e| e.to_string())?;
+
+        let mut live = state
+            .items
+            .range::<[u8], _>((ops::Bound::Included(start), end))
+            .filter(|(_, item)| !item.expired(now));
+        let entries = live
+            .by_ref()
+            .take(limit)
+            .map(|(key, item)| (key.clone(), item.value.clone()))
+            .collect();
+        let next = live.next().map(|(key, _)| key.clone());
+
+        Ok(Page { entries, next })
+    }
+
+    fn delete_expired(&self, limit: usize) -> Result<usize, Box<dyn error::Error>> {
+        let now = engine::now();
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+        Ok(state.remove_expired(now, limit))
+    }
+
+    // deleted keys are gone right away, so only expired ones are left to drop
+    fn compact(&self) -> Result<isize, Box<dyn error::Error>> {
+        let now = engine::now();
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+        state.remove_expired(now, usize::MAX);
+        Ok(MemoryStore::bytes(&state) as isize)
+    }
+
+    // the bytes of every key and value, leaving out what it takes to keep them in memory
+    fn size(&self) -> Result<usize, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        Ok(MemoryStore::bytes(&state))
+    }
+
+    fn info(&self) -> Result<Info, Box<dyn error::Error>> {
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        Ok(Info {
+            files: Vec::new(),
+            entries: state.items.len(),
+        })
+    }
+
+    // only expired keys are dead
+    fn stats(&self) -> Result<Stats, Box<dyn error::Error>> {
+        let now = engine::now();
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        let mut stats = Stats::default();
+        for (key, item) in &state.items {
+            if item.expired(now) {
+                stats.dead_entries += 1;
+                stats.dead_bytes += item.len(key);
+                continue;
+            }
+            stats.live_keys += 1;
+            stats.live_bytes += item.len(key);
+            stats.key_bytes += key.len();
+            stats.value_bytes += item.value.len();
+        }
+        Ok(stats)
+    }
+
+    // writers wait until every live entry has been copied
+    fn snapshot(&self, path: &str) -> Result<snapshot::Metadata, Box<dyn error::Error>> {
+        let now = engine::now();
+        let mut writer = snapshot::Writer::create(path)?;
+        let state = self.state.read().map_err(|e| e.to_string())?;
+        for (key, item) in state.items.iter().filter(|(_, item)| !item.expired(now)) {
+            writer.add(&reader::Entry::new(
+                key,
+                &item.value,
+                item.version,
+                item.expires_at,
+            ))?;
+        }
+        writer.finish(now)
+    }
+
+    fn settings(&self) -> String {
+        String::from(
+            "Engine: memory. Nothing is kept on disk, so every key is gone once the server stops.",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn only_keys_that_expired_are_deleted() {
+        let store = MemoryStore::new(100, 100);
+        let ttl = time::Duration::from_millis(1);
+        store.set_with_ttl(b"a", b"1", ttl).unwrap();
+        store.set_with_ttl(b"b", b"2", ttl).unwrap();
+        // `c` mustn't expire before it's persisted, or persisting it fails
+        let long_ttl = time::Duration::from_secs(100);
+        store.set_with_ttl(b"c", b"3", long_ttl).unwrap();
+        store.set(b"b", b"2").unwrap();
+        store.persist(b"c").unwrap();
+        store.set_with_ttl(b"d", b"4", long_ttl).unwrap();
+        thread::sleep(time::Duration::from_millis(5));
+
+        assert_eq!(store.delete_expired(10).unwrap(), 1);
+        assert_eq!(store.delete_expired(10).unwrap(), 0);
+        let keys: Vec<Vec<u8>> = store.dump().unwrap().into_keys().collect();
+        assert_eq!(keys, [b"b", b"c", b"d"]);
+        assert_eq!(store.info().unwrap().entries, 3);
+    }
+}
@@ -1,2 +1,20 @@
+mod commit;
+pub mod compactor;
+mod crc;
+pub mod durability;
+mod engine;
+pub mod error;
+pub mod export;
+pub(crate) mod file;
+pub mod fsck;
+mod header;
+mod hint;
+pub mod keyspaces;
+mod log;
+mod lsm;
 pub mod map;
+pub mod memory;
 mod reader;
+pub mod snapshot;
+pub mod store;
+mod varint;
@@ -1,114 +1,523 @@
-use std::error;
+use std::collections::VecDeque;
 
-const LEN_SIZE: usize = 2;
+use crate::disk::{crc, error::Error, header, varint};
 
+// size fields were 2 bytes long and stored in big-endian before format version 2
+const FIXED_LEN_SIZE: usize = 2;
+const CRC_SIZE: usize = 4;
+
+// the first byte of every entry says what kind of entry it is. before format version 6, deleting
+// a put overwrote that byte with `Dead` in place, so older files can still have dead entries.
+// before format version 3 puts were the only kind there was, so that byte could only be `Dead`
+// or `Put`. a `Sequence` entry only carries a version: compaction writes one with the highest
+// version handed out so far, since the entry that had it might not survive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Dead = 0,
+    Put = 1,
+    Tombstone = 2,
+    BatchBegin = 3,
+    BatchCommit = 4,
+    Sequence = 5,
+}
+
+impl Kind {
+    fn from_byte(byte: u8, format_version: u16) -> Option<Kind> {
+        match byte {
+            0 => Some(Kind::Dead),
+            1 => Some(Kind::Put),
+            2 if format_version >= 3 => Some(Kind::Tombstone),
+            3 if format_version >= 3 => Some(Kind::BatchBegin),
+            4 if format_version >= 3 => Some(Kind::BatchCommit),
+            5 if format_version >= 4 => Some(Kind::Sequence),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Entry {
     pub offset: usize,
-    pub live: bool,
-    pub key: String,
-    pub value: String,
+    pub kind: Kind,
+    // every put and tombstone gets a version from one counter for the whole map, so a key that is
+    // deleted and set again never goes back to a version it had before. markers have version 0,
+    // and so does everything written before format version 4
+    pub version: u64,
+    // when the entry stops being visible, in milliseconds since the unix epoch. 0 means never
+    pub expires_at: u64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
     pub len: usize,
 }
 
+// where the pieces of an entry are, going only by its fixed-size fields and its size fields
+struct Layout {
+    kind: Kind,
+    checksum: Option<u32>,
+    checksums_kind: bool,
+    checksummed_start: usize,
+    version: u64,
+    expires_at: u64,
+    key_start: usize,
+    key_size: usize,
+    value_size: usize,
+}
+
+impl Layout {
+    // size fields can claim anything up to u64::MAX, so adding them up can overflow
+    fn end(&self, start: usize) -> Result<usize, Error> {
+        self.key_start
+            .checked_add(self.key_size)
+            .and_then(|end| end.checked_add(self.value_size))
+            .ok_or(Error::Corrupt {
+                offset: start,
+                reason: format!(
+                    "key size {} and value size {} add up to more than fits in memory",
+                    self.key_size, self.value_size
+                ),
+            })
+    }
+}
+
 impl Entry {
-    pub fn new(key: &str, value: &str) -> Entry {
+    pub fn new(key: &[u8], value: &[u8], version: u64, expires_at: u64) -> Entry {
+        Entry::with_kind(Kind::Put, key, value, version, expires_at)
+    }
+
+    pub fn tombstone(key: &[u8], version: u64) -> Entry {
+        Entry::with_kind(Kind::Tombstone, key, &[], version, 0)
+    }
+
+    pub fn marker(kind: Kind) -> Entry {
+        Entry::with_kind(kind, &[], &[], 0, 0)
+    }
+
+    pub fn sequence(version: u64) -> Entry {
+        Entry::with_kind(Kind::Sequence, &[], &[], version, 0)
+    }
+
+    fn with_kind(kind: Kind, key: &[u8], value: &[u8], version: u64, expires_at: u64) -> Entry {
         Entry {
-            live: true,
+            kind,
             offset: 0,
+            version,
+            expires_at,
             key: key.to_owned(),
             value: value.to_owned(),
-            len: 1 + LEN_SIZE + LEN_SIZE + key.len() + value.len(),
+            len: 1
+                + CRC_SIZE
+                + varint::encoded_len(version)
+                + varint::encoded_len(expires_at)
+                + varint::encoded_len(key.len() as u64)
+                + varint::encoded_len(value.len() as u64)
+                + key.len()
+                + value.len(),
         }
     }
 
-    pub fn from_bytes(bytes: &[u8], start: usize) -> Option<Entry> {
-        let mut offset = start;
-
-        // get live byte
-        let live = bytes[offset] == 1;
-        offset += 1;
+    pub fn from_bytes(bytes: &[u8], start: usize) -> Result<Entry, Error> {
+        Entry::from_versioned_bytes(bytes, start, header::VERSION)
+    }
 
-        // get key size field, it is 4 bytes long and stored in big-endian
-        // if number is 0xCAFEBABE, it is stored as CA FE BA BE
-        let key_size_bytes = &bytes[offset..(offset + LEN_SIZE)];
-        let key_size = Entry::parse_size(key_size_bytes) as usize;
-        offset += LEN_SIZE;
+    // decodes an entry written in the layout of format `format_version`. only migrations should
+    // need anything other than the current version
+    pub fn from_versioned_bytes(
+        bytes: &[u8],
+        start: usize,
+        format_version: u16,
+    ) -> Result<Entry, Error> {
+        let layout = Entry::parse_layout(bytes, start, format_version)?;
+        let end = layout.end(start)?;
+        if bytes.len() < end {
+            return Err(Error::Truncated { offset: start });
+        }
 
-        // get value size field, also 4 bytes long and stored in big-endian
-        let value_size_bytes = &bytes[offset..(offset + LEN_SIZE)];
-        let value_size = Entry::parse_size(value_size_bytes) as usize;
-        offset += LEN_SIZE;
+        if let Some(stored_checksum) = layout.checksum {
+            let checksummed = &bytes[layout.checksummed_start..end];
+            let actual_checksum = match layout.checksums_kind {
+                true => crc::checksum_all(&[&bytes[start..(start + 1)], checksummed]),
+                false => crc::checksum(checksummed),
+            };
+            if actual_checksum != stored_checksum {
+                return Err(Error::Corrupt {
+                    offset: start,
+                    reason: format!(
+                        "checksum mismatch: stored {stored_checksum:08x}, computed {actual_checksum:08x}"
+                    ),
+                });
+            }
+        }
 
-        let key = str::from_utf8(&bytes[offset..(offset + key_size)]).ok()?;
-        offset += key_size;
-        let value = str::from_utf8(&bytes[offset..(offset + value_size)]).ok()?;
-        offset += value_size;
+        let mut offset = layout.key_start;
+        let key = &bytes[offset..(offset + layout.key_size)];
+        offset += layout.key_size;
+        let value = &bytes[offset..(offset + layout.value_size)];
+        offset += layout.value_size;
 
-        Some(Entry {
-            live,
+        Ok(Entry {
+            kind: layout.kind,
             offset: start,
+            version: layout.version,
+            expires_at: layout.expires_at,
             key: key.to_owned(),
             value: value.to_owned(),
             len: offset - start,
         })
     }
 
-    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn error::Error>> {
-        let key_size_bytes = Entry::size_to_bytes(self.key.len().try_into()?);
-        let value_size_bytes = Entry::size_to_bytes(self.value.len().try_into()?);
+    // how long the entry at `start` says it is, going only by its size fields. returns `None` if
+    // the size fields themselves can't be read, or claim more than is left of `bytes`
+    pub fn claimed_len(bytes: &[u8], start: usize, format_version: u16) -> Option<usize> {
+        let layout = Entry::parse_layout(bytes, start, format_version).ok()?;
+        let end = layout.end(start).ok()?;
+        (end <= bytes.len()).then_some(end - start)
+    }
 
-        let mut buf = Vec::<u8>::with_capacity(
-            1 + key_size_bytes.len() + value_size_bytes.len() + self.key.len() + self.value.len(),
-        );
-        buf.extend_from_slice(&[1u8; 1]);
-        buf.extend_from_slice(&key_size_bytes);
-        buf.extend_from_slice(&value_size_bytes);
-        buf.extend_from_slice(self.key.as_bytes());
-        buf.extend_from_slice(self.value.as_bytes());
+    fn parse_layout(bytes: &[u8], start: usize, format_version: u16) -> Result<Layout, Error> {
+        let truncated = || Error::Truncated { offset: start };
+        let mut offset = start;
+
+        // get kind byte
+        let kind_byte = *bytes.get(offset).ok_or_else(truncated)?;
+        let kind = Kind::from_byte(kind_byte, format_version).ok_or(Error::Corrupt {
+            offset: start,
+            reason: format!("unknown entry kind {kind_byte}"),
+        })?;
+        offset += 1;
+
+        // get checksum. it covers the kind byte and everything after itself. before format
+        // version 6 the kind byte was left out, because it got flipped in place when an entry
+        // was deleted
+        let mut checksum = None;
+        if format_version >= 1 {
+            let checksum_bytes = bytes
+                .get(offset..(offset + CRC_SIZE))
+                .ok_or_else(truncated)?;
+            checksum = Some(u32::from_be_bytes([
+                checksum_bytes[0],
+                checksum_bytes[1],
+                checksum_bytes[2],
+                checksum_bytes[3],
+            ]));
+            offset += CRC_SIZE;
+        }
+        let checksummed_start = offset;
+
+        // get version
+        let mut version = 0;
+        if format_version >= 4 {
+            version = Entry::parse_varint(bytes, &mut offset, start, "version")?;
+        }
+
+        // get expiry
+        let mut expires_at = 0;
+        if format_version >= 5 {
+            expires_at = Entry::parse_varint(bytes, &mut offset, start, "expiry")?;
+        }
+
+        // get key and value size fields
+        let (key_size, value_size) = if format_version >= 2 {
+            let key_size = Entry::parse_size(bytes, &mut offset, start)?;
+            let value_size = Entry::parse_size(bytes, &mut offset, start)?;
+            (key_size, value_size)
+        } else {
+            let key_size = Entry::parse_fixed_size(bytes, &mut offset, start)?;
+            let value_size = Entry::parse_fixed_size(bytes, &mut offset, start)?;
+            (key_size, value_size)
+        };
+
+        Ok(Layout {
+            kind,
+            checksum,
+            checksums_kind: format_version >= 6,
+            checksummed_start,
+            version,
+            expires_at,
+            key_start: offset,
+            key_size,
+            value_size,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body =
+            Vec::<u8>::with_capacity(4 * varint::MAX_LEN + self.key.len() + self.value.len());
+        varint::encode(self.version, &mut body);
+        varint::encode(self.expires_at, &mut body);
+        varint::encode(self.key.len() as u64, &mut body);
+        varint::encode(self.value.len() as u64, &mut body);
+        body.extend_from_slice(&self.key);
+        body.extend_from_slice(&self.value);
+
+        let kind = [self.kind as u8; 1];
+        let mut buf = Vec::<u8>::with_capacity(1 + CRC_SIZE + body.len());
+        buf.extend_from_slice(&kind);
+        buf.extend_from_slice(&crc::checksum_all(&[&kind, &body]).to_be_bytes());
+        buf.extend_from_slice(&body);
+
+        buf
+    }
 
-        Ok(buf)
+    fn parse_size(bytes: &[u8], offset: &mut usize, start: usize) -> Result<usize, Error> {
+        let n = Entry::parse_varint(bytes, offset, start, "size")?;
+        usize::try_from(n).map_err(|_| Error::Corrupt {
+            offset: start,
+            reason: format!("size field {n} does not fit in memory"),
+        })
     }
 
-    fn size_to_bytes(size: u16) -> [u8; LEN_SIZE] {
-        [((size >> 8) as u8), (size as u8)]
+    fn parse_varint(
+        bytes: &[u8],
+        offset: &mut usize,
+        start: usize,
+        field: &str,
+    ) -> Result<u64, Error> {
+        match varint::decode(bytes, *offset) {
+            Ok((n, len)) => {
+                *offset += len;
+                Ok(n)
+            }
+            Err(varint::DecodeError::Truncated) => Err(Error::Truncated { offset: start }),
+            Err(varint::DecodeError::Overlong) => Err(Error::Corrupt {
+                offset: start,
+                reason: format!("{field} field is longer than any {field}"),
+            }),
+        }
     }
 
-    fn parse_size(bytes: &[u8]) -> u16 {
-        ((bytes[0] as u16) << 8) | (bytes[1] as u16)
+    // if number is 0xCAFE, it is stored as CA FE
+    fn parse_fixed_size(bytes: &[u8], offset: &mut usize, start: usize) -> Result<usize, Error> {
+        let size_bytes = bytes
+            .get(*offset..(*offset + FIXED_LEN_SIZE))
+            .ok_or(Error::Truncated { offset: start })?;
+        *offset += FIXED_LEN_SIZE;
+        Ok(((size_bytes[0] as usize) << 8) | (size_bytes[1] as usize))
     }
 }
 
+// walks the entries of a file, skipping dead ones unless asked not to. entries that are part of
+// a batch are only handed out once the batch's commit marker is reached, so an unfinished batch
+// is never seen. what comes out is puts, tombstones and sequence entries in the order they were
+// written, so the last one for a key is the one that counts
 pub struct ReadResult {
     offset: usize,
     data: Vec<u8>,
+    format_version: u16,
+    batch: Option<Vec<Entry>>,
+    committed: VecDeque<Entry>,
+    keep_dead: bool,
 }
 
 impl ReadResult {
     pub fn new(offset: usize, data: Vec<u8>) -> ReadResult {
-        return ReadResult { offset, data };
+        ReadResult::with_version(offset, data, header::VERSION)
+    }
+
+    pub fn with_version(offset: usize, data: Vec<u8>, format_version: u16) -> ReadResult {
+        ReadResult {
+            offset,
+            data,
+            format_version,
+            batch: None,
+            committed: VecDeque::new(),
+            keep_dead: false,
+        }
+    }
+
+    // hands out dead entries too, for when what matters is everything that takes up space
+    pub fn keeping_dead(mut self) -> ReadResult {
+        self.keep_dead = true;
+        self
     }
 }
 
 impl Iterator for ReadResult {
-    type Item = Entry;
+    type Item = Result<Entry, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // increment offset until this is no longer deleted
-        while self.offset < self.data.len() {
+        loop {
+            if let Some(x) = self.committed.pop_front() {
+                return Some(Ok(x));
+            }
+            if self.offset >= self.data.len() {
+                return None;
+            }
+
             let start = self.offset;
+            let x = match Entry::from_versioned_bytes(&self.data, start, self.format_version) {
+                Ok(x) => x,
+                Err(err) => {
+                    // once one entry is bad, we can't trust where the next one starts
+                    self.offset = self.data.len();
+                    return Some(Err(err));
+                }
+            };
+            self.offset += x.len;
 
-            match Entry::from_bytes(&self.data, start) {
-                Some(x) => {
-                    self.offset += x.len;
-                    if x.live {
-                        return Some(x);
-                    } else {
-                        continue;
+            match x.kind {
+                Kind::Dead if !self.keep_dead => continue,
+                Kind::Sequence => return Some(Ok(x)),
+                Kind::Dead | Kind::Put | Kind::Tombstone => match &mut self.batch {
+                    Some(batch) => batch.push(x),
+                    None => return Some(Ok(x)),
+                },
+                // a batch that begins before the last one committed never will
+                Kind::BatchBegin => self.batch = Some(Vec::new()),
+                Kind::BatchCommit => {
+                    if let Some(batch) = self.batch.take() {
+                        self.committed.extend(batch);
                     }
                 }
-                None => continue,
             }
         }
-        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes_of(entries: &[Entry]) -> Vec<u8> {
+        entries.iter().flat_map(Entry::to_bytes).collect()
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let entries = [
+            Entry::new(b"key", b"value", 7, 1_700_000_000_000),
+            Entry::new(b"", b"", 0, 0),
+            Entry::new(&[0xff; 300], &[0; 70_000], u64::MAX, u64::MAX),
+            Entry::tombstone(b"key", 8),
+            Entry::marker(Kind::BatchBegin),
+            Entry::sequence(9),
+        ];
+        let bytes = bytes_of(&entries);
+
+        let mut offset = 0;
+        for expected in &entries {
+            let entry = Entry::from_bytes(&bytes, offset).unwrap();
+            assert_eq!(entry.kind, expected.kind);
+            assert_eq!(entry.offset, offset);
+            assert_eq!(entry.version, expected.version);
+            assert_eq!(entry.expires_at, expected.expires_at);
+            assert_eq!(entry.key, expected.key);
+            assert_eq!(entry.value, expected.value);
+            assert_eq!(entry.len, expected.len);
+            assert_eq!(
+                Entry::claimed_len(&bytes, offset, header::VERSION),
+                Some(entry.len)
+            );
+            offset += entry.len;
+        }
+        assert_eq!(offset, bytes.len());
+    }
+
+    #[test]
+    fn checksum_covers_kind_and_body() {
+        let bytes = Entry::new(b"key", b"value", 1, 0).to_bytes();
+
+        let mut flipped_kind = bytes.clone();
+        flipped_kind[0] = Kind::Tombstone as u8;
+        let mut flipped_value = bytes.clone();
+        *flipped_value.last_mut().unwrap() ^= 1;
+
+        for bytes in [flipped_kind, flipped_value] {
+            assert!(matches!(
+                Entry::from_bytes(&bytes, 0),
+                Err(Error::Corrupt { offset: 0, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn short_entry_is_truncated() {
+        let bytes = Entry::new(b"key", b"value", 1, 0).to_bytes();
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                Entry::from_bytes(&bytes[..len], 0),
+                Err(Error::Truncated { offset: 0 })
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_kind_is_corrupt() {
+        let mut bytes = Entry::new(b"key", b"value", 1, 0).to_bytes();
+        bytes[0] = 9;
+        assert!(matches!(
+            Entry::from_bytes(&bytes, 0),
+            Err(Error::Corrupt { .. })
+        ));
+        assert_eq!(Entry::claimed_len(&bytes, 0, header::VERSION), None);
+    }
+
+    #[test]
+    fn sizes_that_overflow_are_corrupt() {
+        let mut bytes = vec![Kind::Put as u8, 0, 0, 0, 0];
+        varint::encode(0, &mut bytes);
+        varint::encode(0, &mut bytes);
+        varint::encode(u64::MAX, &mut bytes);
+        varint::encode(u64::MAX, &mut bytes);
+
+        assert!(matches!(
+            Entry::from_bytes(&bytes, 0),
+            Err(Error::Corrupt { offset: 0, .. })
+        ));
+        assert_eq!(Entry::claimed_len(&bytes, 0, header::VERSION), None);
+    }
+
+    #[test]
+    fn claimed_len_stops_at_the_end_of_the_data() {
+        let bytes = Entry::new(b"key", b"value", 1, 0).to_bytes();
+        let short = &bytes[..bytes.len() - 1];
+        assert_eq!(Entry::claimed_len(short, 0, header::VERSION), None);
+    }
+
+    #[test]
+    fn older_formats_decode() {
+        // format version 1: kind, a checksum of what follows it, then 2-byte sizes
+        let mut bytes = vec![Kind::Put as u8];
+        let body = [0, 3, 0, 1, b'k', b'e', b'y', b'v'];
+        bytes.extend_from_slice(&crc::checksum(&body).to_be_bytes());
+        bytes.extend_from_slice(&body);
+        let entry = Entry::from_versioned_bytes(&bytes, 0, 1).unwrap();
+        assert_eq!(
+            (entry.key.as_slice(), entry.value.as_slice()),
+            (&b"key"[..], &b"v"[..])
+        );
+        assert_eq!(entry.len, bytes.len());
+
+        // format version 0 has no checksum at all
+        let bytes = [Kind::Put as u8, 0, 1, 0, 1, b'k', b'v'];
+        let entry = Entry::from_versioned_bytes(&bytes, 0, 0).unwrap();
+        assert_eq!(
+            (entry.key.as_slice(), entry.value.as_slice()),
+            (&b"k"[..], &b"v"[..])
+        );
+    }
+
+    #[test]
+    fn unfinished_batches_are_never_read() {
+        let bytes = bytes_of(&[
+            Entry::new(b"a", b"1", 1, 0),
+            Entry::marker(Kind::BatchBegin),
+            Entry::new(b"b", b"2", 2, 0),
+            Entry::tombstone(b"a", 3),
+            Entry::marker(Kind::BatchCommit),
+            Entry::marker(Kind::BatchBegin),
+            Entry::new(b"c", b"3", 4, 0),
+        ]);
+
+        let read: Vec<(Kind, Vec<u8>)> = ReadResult::new(0, bytes)
+            .map(|entry| entry.map(|entry| (entry.kind, entry.key)).unwrap())
+            .collect();
+        assert_eq!(
+            read,
+            vec![
+                (Kind::Put, b"a".to_vec()),
+                (Kind::Put, b"b".to_vec()),
+                (Kind::Tombstone, b"a".to_vec()),
+            ]
+        );
     }
 }
@@ -0,0 +1,451 @@
+use nix::{errno, fcntl, fcntl::OFlag, libc, sys::stat, sys::stat::Mode, unistd};
+use std::error;
+use std::os::fd::{AsFd, OwnedFd};
+
+use crate::disk::{crc, error::Error, file, header, reader};
+
+// snapshots start with these bytes instead of a map header, so that a snapshot is never opened
+// as a map or a map restored as a snapshot
+const MAGIC: [u8; 8] = *b"DMSNAP\0\0";
+
+// magic and the format version of the entries that follow
+const PREAMBLE_LEN: usize = MAGIC.len() + 2;
+
+// creation time, entry count and checksum
+const FOOTER_LEN: usize = 8 + 8 + 4;
+
+// entries are gathered up to this many bytes before they are written out, and read back this
+// many bytes at a time
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+const MAX_NAME_LEN: usize = 255;
+
+// what a snapshot says about itself
+pub struct Metadata {
+    // milliseconds since the unix epoch
+    pub created_at: u64,
+    pub entries: usize,
+    // a checksum of everything in the file before it
+    pub checksum: u32,
+}
+
+// where the snapshot called `name` is kept in `dir`. names are file names and nothing more, so
+// that a snapshot never ends up anywhere else
+pub fn path_in(dir: &str, name: &str) -> Result<String, Box<dyn error::Error>> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!("snapshot names are 1 to {MAX_NAME_LEN} characters long").into());
+    }
+    if name.starts_with('.')
+        || !name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    {
+        return Err(format!(
+            "snapshot name {name} can only have letters, digits, -, _ and ., and can't start with ."
+        )
+        .into());
+    }
+    Ok(format!("{dir}/{name}"))
+}
+
+// makes sure there is a directory at `dir` to keep snapshots in
+pub fn create_dir(dir: &str) -> Result<(), Box<dyn error::Error>> {
+    match stat::stat(dir) {
+        Ok(st) if st.st_mode & libc::S_IFMT == libc::S_IFDIR => Ok(()),
+        Ok(_) => Err(format!("{dir} is not a directory").into()),
+        Err(errno::Errno::ENOENT) => {
+            unistd::mkdir(dir, Mode::S_IRWXU | Mode::S_IRGRP | Mode::S_IXGRP)?;
+            file::fsync_parent(dir)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// writes a snapshot to `path` one live entry at a time. laid out as the magic bytes, the format
+// version, the entries as they would be in a map file, then the footer: the creation time, how
+// many entries there are and a checksum of all of that. the snapshot only shows up at `path`
+// once `finish` is done with it, so a snapshot that is there is whole
+pub struct Writer {
+    path: String,
+    tmp_path: String,
+    fd: OwnedFd,
+    buf: Vec<u8>,
+    checksum: u32,
+    entries: usize,
+    // until it's set, dropping the writer removes what it wrote so far
+    finished: bool,
+}
+
+impl Writer {
+    // never writes over anything that is already at `path`, or at the file next to it that the
+    // snapshot is written to first, and never follows a symlink at either
+    pub fn create(path: &str) -> Result<Writer, Box<dyn error::Error>> {
+        match unistd::access(path, unistd::AccessFlags::F_OK) {
+            Ok(()) => return Err(format!("{path} already exists").into()),
+            Err(errno::Errno::ENOENT) => {}
+            Err(err) => return Err(err.into()),
+        }
+        let tmp_path = format!("{path}.tmp");
+        let fd = match fcntl::open(
+            tmp_path.as_str(),
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW,
+            file::mode(),
+        ) {
+            Ok(fd) => fd,
+            Err(errno::Errno::EEXIST) => {
+                return Err(format!(
+                    "{tmp_path} already exists. a snapshot of the same name is being written, or one was cut short"
+                )
+                .into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut buf = Vec::<u8>::with_capacity(BUFFER_SIZE);
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&header::VERSION.to_be_bytes());
+        Ok(Writer {
+            path: String::from(path),
+            tmp_path,
+            fd,
+            buf,
+            checksum: 0,
+            entries: 0,
+            finished: false,
+        })
+    }
+
+    pub fn add(&mut self, entry: &reader::Entry) -> Result<(), Box<dyn error::Error>> {
+        self.buf.extend_from_slice(&entry.to_bytes());
+        self.entries += 1;
+        if self.buf.len() >= BUFFER_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // writes the footer and moves the snapshot into place
+    pub fn finish(mut self, created_at: u64) -> Result<Metadata, Box<dyn error::Error>> {
+        self.buf.extend_from_slice(&created_at.to_be_bytes());
+        self.buf
+            .extend_from_slice(&(self.entries as u64).to_be_bytes());
+        self.checksum = crc::extend(self.checksum, &self.buf);
+        self.buf.extend_from_slice(&self.checksum.to_be_bytes());
+        file::write_all(self.fd.as_fd(), &self.buf)?;
+        unistd::fsync(self.fd.as_fd())?;
+
+        // something might have shown up at `path` since we checked
+        fcntl::renameat2(
+            fcntl::AT_FDCWD,
+            self.tmp_path.as_str(),
+            fcntl::AT_FDCWD,
+            self.path.as_str(),
+            fcntl::RenameFlags::RENAME_NOREPLACE,
+        )
+        .map_err(|err| format!("error moving the snapshot to {}: {err}", self.path))?;
+        self.finished = true;
+        file::fsync_parent(&self.path)?;
+
+        Ok(Metadata {
+            created_at,
+            entries: self.entries,
+            checksum: self.checksum,
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn error::Error>> {
+        self.checksum = crc::extend(self.checksum, &self.buf);
+        file::write_all(self.fd.as_fd(), &self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = unistd::unlink(self.tmp_path.as_str());
+        }
+    }
+}
+
+// reads back the snapshot at `path`. nothing in it is handed back unless the whole file checks
+// out, so it gets read through twice before the entries are, a buffer at a time, and never has
+// to fit in memory
+pub fn read(path: &str) -> Result<(Metadata, Entries), Box<dyn error::Error>> {
+    let fd = fcntl::open(path, OFlag::O_RDONLY, Mode::empty())?;
+    let size = stat::fstat(fd.as_fd())?.st_size as usize;
+    if size < PREAMBLE_LEN + FOOTER_LEN {
+        return Err(format!("{path} is not a snapshot").into());
+    }
+    let preamble = file::read_at(fd.as_fd(), 0, PREAMBLE_LEN)?;
+    if preamble[..MAGIC.len()] != MAGIC {
+        return Err(format!("{path} is not a snapshot").into());
+    }
+
+    let body_len = size - 4;
+    let mut actual_checksum = 0;
+    let mut offset = 0;
+    while offset < body_len {
+        let n = BUFFER_SIZE.min(body_len - offset);
+        actual_checksum = crc::extend(actual_checksum, &file::read_at(fd.as_fd(), offset, n)?);
+        offset += n;
+    }
+    let footer = file::read_at(fd.as_fd(), size - FOOTER_LEN, FOOTER_LEN)?;
+    let stored_checksum = u32::from_be_bytes(footer[16..].try_into()?);
+    if stored_checksum != actual_checksum {
+        return Err(format!(
+            "snapshot checksum mismatch: stored {stored_checksum:08x}, computed {actual_checksum:08x}"
+        )
+        .into());
+    }
+
+    let format_version = u16::from_be_bytes([preamble[MAGIC.len()], preamble[MAGIC.len() + 1]]);
+    if format_version > header::VERSION {
+        return Err(format!(
+            "snapshot format version {format_version} is newer than supported {}",
+            header::VERSION
+        )
+        .into());
+    }
+    let created_at = u64::from_be_bytes(footer[..8].try_into()?);
+    let n = u64::from_be_bytes(footer[8..16].try_into()?);
+
+    let end = size - FOOTER_LEN;
+    let mut count = 0u64;
+    for entry in Entries::new(fd.try_clone()?, format_version, end) {
+        entry?;
+        count += 1;
+    }
+    if count != n {
+        return Err(format!("snapshot says it has {n} entries, but {count} are there").into());
+    }
+
+    let metadata = Metadata {
+        created_at,
+        entries: count as usize,
+        checksum: stored_checksum,
+    };
+    Ok((metadata, Entries::new(fd, format_version, end)))
+}
+
+// the entries of a snapshot, in the order they were written. `read` has already been through
+// them, so an error here means the file changed since
+pub struct Entries {
+    fd: OwnedFd,
+    format_version: u16,
+    // where the entries stop and the footer starts
+    end: usize,
+    // the part of the file from `offset` on that was read so far, and where in it the next
+    // entry starts
+    buf: Vec<u8>,
+    offset: usize,
+    pos: usize,
+}
+
+impl Entries {
+    fn new(fd: OwnedFd, format_version: u16, end: usize) -> Entries {
+        Entries {
+            fd,
+            format_version,
+            end,
+            buf: Vec::new(),
+            offset: PREAMBLE_LEN,
+            pos: 0,
+        }
+    }
+
+    fn read_entry(&mut self) -> Result<Option<reader::Entry>, Box<dyn error::Error>> {
+        loop {
+            if self.offset + self.pos >= self.end {
+                return Ok(None);
+            }
+            match reader::Entry::from_versioned_bytes(&self.buf, self.pos, self.format_version) {
+                Ok(entry) => {
+                    self.pos += entry.len;
+                    return Ok(Some(entry));
+                }
+                // the entry runs past what was read so far
+                Err(Error::Truncated { .. }) if self.offset + self.buf.len() < self.end => {
+                    self.read_more()?;
+                }
+                Err(err) => return Err(err.shifted(self.offset).into()),
+            }
+        }
+    }
+
+    // drops the entries already handed back and reads up to another buffer's worth
+    fn read_more(&mut self) -> Result<(), Box<dyn error::Error>> {
+        self.buf.drain(..self.pos);
+        self.offset += self.pos;
+        self.pos = 0;
+        let start = self.offset + self.buf.len();
+        let n = BUFFER_SIZE.min(self.end - start);
+        self.buf
+            .extend_from_slice(&file::read_at(self.fd.as_fd(), start, n)?);
+        Ok(())
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Result<reader::Entry, Box<dyn error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write(path: &str, entries: &[reader::Entry]) -> Metadata {
+        let mut writer = Writer::create(path).unwrap();
+        for entry in entries {
+            writer.add(entry).unwrap();
+        }
+        writer.finish(1234).unwrap()
+    }
+
+    fn entries() -> Vec<reader::Entry> {
+        // enough to take several buffers, with an entry bigger than a buffer in the middle
+        let mut entries: Vec<reader::Entry> = (0..3000u32)
+            .map(|i| reader::Entry::new(&i.to_be_bytes(), &[i as u8; 1000], i as u64 + 1, 0))
+            .collect();
+        entries.insert(
+            1500,
+            reader::Entry::new(b"big", &vec![7; BUFFER_SIZE * 3 / 2], 5000, 99),
+        );
+        entries
+    }
+
+    #[test]
+    fn written_snapshot_reads_back() {
+        let path = format!("{}/snap", file::test_dir("snapshot-round-trip"));
+        let written = write(&path, &entries());
+        assert_eq!(written.entries, 3001);
+
+        let (metadata, read) = read(&path).unwrap();
+        assert_eq!(
+            (metadata.created_at, metadata.entries, metadata.checksum),
+            (1234, 3001, written.checksum)
+        );
+        let read: Vec<reader::Entry> = read.map(Result::unwrap).collect();
+        assert_eq!(read.len(), entries().len());
+        for (read, written) in read.iter().zip(entries()) {
+            assert_eq!(
+                (&read.key, &read.value, read.version, read.expires_at),
+                (
+                    &written.key,
+                    &written.value,
+                    written.version,
+                    written.expires_at
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn empty_snapshot_reads_back() {
+        let path = format!("{}/snap", file::test_dir("snapshot-empty"));
+        write(&path, &[]);
+        let (metadata, mut read) = read(&path).unwrap();
+        assert_eq!(metadata.entries, 0);
+        assert!(read.next().is_none());
+    }
+
+    #[test]
+    fn nothing_is_written_over() {
+        let dir = file::test_dir("snapshot-exists");
+        let path = format!("{dir}/snap");
+        fs::write(&path, b"mine").unwrap();
+        assert!(Writer::create(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"mine");
+
+        // nor is a snapshot of the same name that is still being written
+        let path = format!("{dir}/other");
+        let tmp_path = format!("{path}.tmp");
+        fs::write(&tmp_path, b"theirs").unwrap();
+        assert!(Writer::create(&path).is_err());
+        assert_eq!(fs::read(&tmp_path).unwrap(), b"theirs");
+    }
+
+    #[test]
+    fn unfinished_snapshot_leaves_nothing_behind() {
+        let dir = file::test_dir("snapshot-unfinished");
+        let mut writer = Writer::create(&format!("{dir}/snap")).unwrap();
+        writer.add(&entries()[0]).unwrap();
+        drop(writer);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn damaged_snapshot_is_refused() {
+        let dir = file::test_dir("snapshot-damaged");
+        let path = format!("{dir}/snap");
+        write(&path, &entries()[..10]);
+        let bytes = fs::read(&path).unwrap();
+
+        let mut flipped = bytes.clone();
+        flipped[PREAMBLE_LEN + 20] ^= 1;
+        let flipped_path = format!("{dir}/flipped");
+        fs::write(&flipped_path, &flipped).unwrap();
+        let err = read(&flipped_path).err().unwrap().to_string();
+        assert!(err.contains("checksum mismatch"), "{err}");
+
+        let short_path = format!("{dir}/short");
+        fs::write(&short_path, &bytes[..(PREAMBLE_LEN + FOOTER_LEN - 1)]).unwrap();
+        let err = read(&short_path).err().unwrap().to_string();
+        assert!(err.ends_with("is not a snapshot"), "{err}");
+
+        let map_path = format!("{dir}/map");
+        let mut map = header::Header::new().to_bytes().to_vec();
+        map.resize(100, 0);
+        fs::write(&map_path, &map).unwrap();
+        let err = read(&map_path).err().unwrap().to_string();
+        assert!(err.ends_with("is not a snapshot"), "{err}");
+    }
+
+    #[test]
+    fn entry_count_has_to_match() {
+        let dir = file::test_dir("snapshot-count");
+        let path = format!("{dir}/snap");
+        write(&path, &entries()[..10]);
+
+        // a footer claiming one more entry, with a checksum that matches it
+        let mut bytes = fs::read(&path).unwrap();
+        let count_at = bytes.len() - 12;
+        bytes[count_at..(count_at + 8)].copy_from_slice(&11u64.to_be_bytes());
+        bytes.truncate(bytes.len() - 4);
+        let checksum = crc::checksum(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        let path = format!("{dir}/lying");
+        fs::write(&path, &bytes).unwrap();
+
+        let err = read(&path).err().unwrap().to_string();
+        assert_eq!(err, "snapshot says it has 11 entries, but 10 are there");
+    }
+
+    #[test]
+    fn names_are_file_names() {
+        assert_eq!(
+            path_in("/snaps", "daily-1_a.snap").unwrap(),
+            "/snaps/daily-1_a.snap"
+        );
+        for name in [
+            "",
+            ".",
+            "..",
+            "../a",
+            "a/b",
+            "/a",
+            ".a",
+            "a\0",
+            "a b",
+            &"a".repeat(256),
+        ] {
+            assert!(path_in("/snaps", name).is_err(), "{name:?}");
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::{error, time};
+
+use crate::disk::engine::{Info, Stats};
+use crate::disk::map::{Page, WriteBatch};
+use crate::disk::snapshot;
+
+// everything a handler can ask of a key-value store. `DiskMap` keeps its entries on disk,
+// `MemoryStore` only in memory
+pub trait KvStore: Send + Sync {
+    fn get(&self, k: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let (value, _) = self.get_with_version(k)?;
+        Ok(value)
+    }
+
+    // returns the value of `k` along with the version to pass to `cas` to overwrite it
+    fn get_with_version(&self, k: &[u8]) -> Result<(Vec<u8>, u64), Box<dyn error::Error>>;
+
+    // returns how many bytes were written
+    fn set(&self, k: &[u8], v: &[u8]) -> Result<isize, Box<dyn error::Error>>;
+
+    // like `set`, but `k` disappears once `ttl` has passed
+    fn set_with_ttl(
+        &self,
+        k: &[u8],
+        v: &[u8],
+        ttl: time::Duration,
+    ) -> Result<isize, Box<dyn error::Error>>;
+
+    // sets `k` to `v` only if `k` is still at version `expected`, or doesn't exist if `expected`
+    // is 0. returns the version `k` is at now
+    fn cas(&self, k: &[u8], expected: u64, v: &[u8]) -> Result<u64, Box<dyn error::Error>>;
+
+    // applies every op in `batch` atomically, or none of them
+    fn write(&self, batch: WriteBatch) -> Result<isize, Box<dyn error::Error>>;
+
+    fn delete(&self, k: &[u8]) -> Result<(), Box<dyn error::Error>>;
+
+    // makes `k` expire once `ttl` has passed, replacing whatever expiry it had
+    fn expire(&self, k: &[u8], ttl: time::Duration) -> Result<(), Box<dyn error::Error>>;
+
+    // how long until `k` expires. `None` if it never does
+    fn ttl(&self, k: &[u8]) -> Result<Option<time::Duration>, Box<dyn error::Error>>;
+
+    // makes `k` never expire. returns whether it was going to
+    fn persist(&self, k: &[u8]) -> Result<bool, Box<dyn error::Error>>;
+
+    fn dump(&self) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, Box<dyn error::Error>> {
+        let page = self.scan(&[], None, usize::MAX)?;
+        Ok(page.entries.into_iter().collect())
+    }
+
+    // reads up to `limit` keys from `start` up to but not including `end`, or to the last key if
+    // there is no `end`
+    fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Page, Box<dyn error::Error>>;
+
+    // reads up to `limit` keys that start with `prefix`, beginning at `cursor` if this isn't the
+    // first page
+    fn prefix(
+        &self,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Page, Box<dyn error::Error>> {
+        let start = match cursor {
+            Some(cursor) if cursor > prefix => cursor,
+            _ => prefix,
+        };
+
+        // the first key after every key that starts with `prefix`. a prefix of only 0xff bytes
+        // has no such key, so the scan runs to the end
+        let mut end = prefix.to_vec();
+        while end.last() == Some(&0xff) {
+            end.pop();
+        }
+        if let Some(last) = end.last_mut() {
+            *last += 1;
+        }
+
+        match end.is_empty() {
+            true => self.scan(start, None, limit),
+            // a cursor past every key with the prefix has nothing left to read
+            false if start >= end.as_slice() => Ok(Page::default()),
+            false => self.scan(start, Some(&end), limit),
+        }
+    }
+
+    // deletes up to `limit` keys that have expired. reads already skip them, but they take up
+    // space until they are deleted. returns how many were deleted
+    fn delete_expired(&self, limit: usize) -> Result<usize, Box<dyn error::Error>>;
+
+    // gets back the space taken up by whatever isn't live anymore. returns how many bytes are
+    // left
+    fn compact(&self) -> Result<isize, Box<dyn error::Error>>;
+
+    // how many bytes the store takes up
+    fn size(&self) -> Result<usize, Box<dyn error::Error>>;
+
+    fn info(&self) -> Result<Info, Box<dyn error::Error>>;
+
+    // how much of the store is live and how much is garbage
+    fn stats(&self) -> Result<Stats, Box<dyn error::Error>>;
+
+    // writes a copy of the store as it is right now to a new file at `path`
+    fn snapshot(&self, path: &str) -> Result<snapshot::Metadata, Box<dyn error::Error>>;
+
+    // how the store is set up, shown to users when they connect
+    fn settings(&self) -> String;
+}
@@ -0,0 +1,104 @@
+// unsigned leb128: 7 bits of the number per byte, least significant group first, with the high
+// bit set on every byte except the last. small numbers take one byte and nothing has a limit
+// short of u64::MAX
+pub const MAX_LEN: usize = 10;
+
+pub fn encode(mut n: u64, buf: &mut Vec<u8>) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+pub fn encoded_len(mut n: u64) -> usize {
+    let mut len = 1;
+    while n >= 0x80 {
+        n >>= 7;
+        len += 1;
+    }
+    len
+}
+
+pub enum DecodeError {
+    // ran out of bytes before the last byte of the number
+    Truncated,
+    // more bytes than any u64 needs
+    Overlong,
+}
+
+// returns the number at `offset` and how many bytes it took up
+pub fn decode(bytes: &[u8], offset: usize) -> Result<(u64, usize), DecodeError> {
+    let mut n = 0u64;
+    for i in 0..MAX_LEN {
+        let byte = *bytes.get(offset + i).ok_or(DecodeError::Truncated)?;
+        if i == MAX_LEN - 1 && byte > 1 {
+            return Err(DecodeError::Overlong);
+        }
+
+        n |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((n, i + 1));
+        }
+    }
+    Err(DecodeError::Overlong)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        for n in [
+            0,
+            1,
+            0x7f,
+            0x80,
+            0x3fff,
+            0x4000,
+            u32::MAX as u64,
+            u64::MAX - 1,
+            u64::MAX,
+        ] {
+            let mut buf = vec![0xaa];
+            encode(n, &mut buf);
+            assert_eq!(buf.len() - 1, encoded_len(n));
+            assert!(encoded_len(n) <= MAX_LEN);
+            assert!(matches!(decode(&buf, 1), Ok((m, len)) if m == n && len == buf.len() - 1));
+        }
+    }
+
+    #[test]
+    fn small_numbers_take_one_byte() {
+        let mut buf = Vec::new();
+        encode(0x7f, &mut buf);
+        assert_eq!(buf, [0x7f]);
+        buf.clear();
+        encode(0x80, &mut buf);
+        assert_eq!(buf, [0x80, 0x01]);
+    }
+
+    #[test]
+    fn runs_out_of_bytes() {
+        let mut buf = Vec::new();
+        encode(u64::MAX, &mut buf);
+        for len in 0..buf.len() {
+            assert!(matches!(
+                decode(&buf[..len], 0),
+                Err(DecodeError::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_more_than_a_u64() {
+        // the tenth byte can only hold the top bit of a u64
+        let mut buf = vec![0xff; MAX_LEN - 1];
+        buf.push(0x02);
+        assert!(matches!(decode(&buf, 0), Err(DecodeError::Overlong)));
+
+        let buf = vec![0x80; MAX_LEN + 1];
+        assert!(matches!(decode(&buf, 0), Err(DecodeError::Overlong)));
+    }
+}
@@ -1,70 +1,801 @@
-use crate::{disk::map::DiskMap, net::types::Handler};
-use std::error;
+use crate::{
+    disk::keyspaces::{Keyspace, Keyspaces},
+    disk::map::{Page, WriteBatch},
+    disk::snapshot,
+    disk::store::KvStore,
+    net::{
+        escape,
+        types::{Handler, Session},
+    },
+};
+use std::sync::Arc;
+use std::{error, str, time};
 
-pub struct DiskHandler {
-    disk_map: DiskMap,
+// how many keys a scan returns when no limit is given
+const DEFAULT_SCAN_LIMIT: u64 = 100;
+
+pub struct DiskHandler<S: KvStore> {
+    keyspaces: Keyspaces<S>,
+    // where `snapshot` writes to. clients only ever name a file in it
+    snapshot_dir: String,
     supported_commands: Vec<&'static str>,
 }
 
-impl DiskHandler {
-    pub fn new(disk_map: DiskMap) -> DiskHandler {
+pub struct DiskSession<'a, S: KvStore> {
+    handler: &'a DiskHandler<S>,
+    // the keyspace picked with `use`
+    keyspace: Arc<Keyspace<S>>,
+    // sets and deletes queued up since `batch`, written together on `end`
+    batch: Option<WriteBatch>,
+}
+
+impl<S: KvStore> DiskHandler<S> {
+    pub fn new(keyspaces: Keyspaces<S>, snapshot_dir: &str) -> DiskHandler<S> {
         DiskHandler {
-            disk_map,
+            keyspaces,
+            snapshot_dir: String::from(snapshot_dir),
             supported_commands: vec![
-                "get <key>",
-                "set <key> <value>",
+                "get <key> [withversion]",
+                "set <key> <value> [ex <seconds>]",
+                "cas <key> <expected_version> <value> (version 0 means the key must not exist)",
                 "delete <key>",
+                "expire <key> <seconds>",
+                "ttl <key>",
+                "persist <key>",
+                "batch (then any number of set, cas and delete, then end or discard)",
                 "compact",
                 "size",
+                "info",
+                "stats",
+                "snapshot <name> (a file in the server's snapshot directory, which must not exist yet)",
                 "dump",
+                "scan <start> <end> [limit] (end is exclusive, \"\" for no end)",
+                "prefix <prefix> [limit] [cursor]",
+                "use <keyspace> (every connection starts out in default)",
+                "keyspaces",
+                "create <keyspace>",
+                "drop <keyspace> (connections using it have to switch to another one)",
             ],
         }
     }
 
-    fn handle_result(&self, str: &str) -> Result<String, Box<dyn error::Error>> {
-        let mut split = str.split_whitespace();
+    // runs a command against `store`, the store of the keyspace the connection uses
+    fn handle_result(&self, store: &S, args: &[Vec<u8>]) -> Result<String, Box<dyn error::Error>> {
+        let mut split = args.iter().map(|arg| arg.as_slice());
         match split.next().ok_or("empty body")? {
-            "get" => {
+            b"get" => {
                 let key = split.next().ok_or("missing key argument")?;
-                self.disk_map.get(key)
+                match split.next() {
+                    None => Ok(escape::render(&store.get(key)?)),
+                    Some(b"withversion") => {
+                        let (value, version) = store.get_with_version(key)?;
+                        Ok(format!("{} (version {version})", escape::render(&value)))
+                    }
+                    Some(_) => Err("expected withversion or nothing after the key".into()),
+                }
+            }
+            b"set" => {
+                let k = split.next().ok_or("missing key argument")?;
+                let v = split.next().ok_or("missing value argument")?;
+                let n = match parse_ttl(&mut split)? {
+                    Some(ttl) => store.set_with_ttl(k, v, ttl)?,
+                    None => store.set(k, v)?,
+                };
+                Ok(format!(
+                    "wrote {}={}. {} bytes",
+                    escape::render(k),
+                    escape::render(v),
+                    n
+                ))
             }
-            "set" => {
+            b"cas" => {
                 let k = split.next().ok_or("missing key argument")?;
+                let expected = parse_number(split.next(), "expected version")?;
                 let v = split.next().ok_or("missing value argument")?;
-                let n = self.disk_map.set(k, v)?;
-                Ok(format!("wrote {}={}. {} bytes", k, v, n))
+                let version = store.cas(k, expected, v)?;
+                Ok(format!(
+                    "wrote {}={}. version {version}",
+                    escape::render(k),
+                    escape::render(v)
+                ))
+            }
+            b"delete" => {
+                let k = split.next().ok_or("missing key argument")?;
+                store.delete(k)?;
+                Ok(format!("deleted {}", escape::render(k)))
             }
-            "delete" => {
+            b"expire" => {
                 let k = split.next().ok_or("missing key argument")?;
-                self.disk_map.delete(k)?;
-                Ok(format!("deleted {k}"))
+                let seconds = parse_number(split.next(), "seconds")?;
+                store.expire(k, time::Duration::from_secs(seconds))?;
+                Ok(format!("{} expires in {seconds}s", escape::render(k)))
             }
-            "compact" => match self.disk_map.compact() {
+            b"ttl" => {
+                let k = split.next().ok_or("missing key argument")?;
+                match store.ttl(k)? {
+                    // round up, so a key that is about to expire doesn't show 0s
+                    Some(ttl) => Ok(format!(
+                        "{} expires in {}s",
+                        escape::render(k),
+                        ttl.as_millis().div_ceil(1000)
+                    )),
+                    None => Ok(format!("{} does not expire", escape::render(k))),
+                }
+            }
+            b"persist" => {
+                let k = split.next().ok_or("missing key argument")?;
+                match store.persist(k)? {
+                    true => Ok(format!("{} no longer expires", escape::render(k))),
+                    false => Ok(format!("{} already did not expire", escape::render(k))),
+                }
+            }
+            b"compact" => match store.compact() {
                 Err(err) => Err(err),
                 Ok(n) => Ok(format!("compacted to {n} bytes")),
             },
-            "size" => match self.disk_map.size() {
+            b"size" => match store.size() {
                 Err(err) => Err(format!("error calling size: {}", err).into()),
-                Ok(size) => Ok(size),
+                Ok(size) => Ok(format!("{size} bytes")),
             },
-            "dump" => {
-                let m = self.disk_map.dump()?;
-                Ok(format!("{:#?}", m))
+            b"info" => {
+                let info = store.info()?;
+                let mut lines: Vec<String> = info
+                    .files
+                    .iter()
+                    .map(|file| {
+                        format!(
+                            "{}: {} bytes, {} blocks, inode {}, modified {}",
+                            file.path, file.size, file.blocks, file.inode, file.modified
+                        )
+                    })
+                    .collect();
+                lines.push(format!(
+                    "{} files, {} bytes, {} blocks, {} entries",
+                    info.files.len(),
+                    info.files.iter().map(|file| file.size).sum::<usize>(),
+                    info.files.iter().map(|file| file.blocks).sum::<u64>(),
+                    info.entries
+                ));
+                Ok(lines.join("\n"))
+            }
+            b"stats" => {
+                let stats = store.stats()?;
+                Ok([
+                    format!("live keys: {}", stats.live_keys),
+                    format!("live bytes: {}", stats.live_bytes),
+                    format!("dead entries: {}", stats.dead_entries),
+                    format!("dead bytes: {}", stats.dead_bytes),
+                    format!("fragmentation: {:.1}%", stats.fragmentation() * 100.0),
+                    format!("average key size: {:.1} bytes", stats.average_key_size()),
+                    format!(
+                        "average value size: {:.1} bytes",
+                        stats.average_value_size()
+                    ),
+                ]
+                .join("\n"))
+            }
+            b"snapshot" => {
+                let name = split.next().ok_or("missing name argument")?;
+                let name = str::from_utf8(name).map_err(|_| "name must be utf-8")?;
+                let path = snapshot::path_in(&self.snapshot_dir, name)?;
+                snapshot::create_dir(&self.snapshot_dir)?;
+                let metadata = store.snapshot(&path)?;
+                Ok(format!(
+                    "wrote snapshot of {} entries to {path}. created at {}, checksum {:08x}",
+                    metadata.entries, metadata.created_at, metadata.checksum
+                ))
+            }
+            b"dump" => {
+                let lines: Vec<String> = store
+                    .dump()?
+                    .iter()
+                    .map(|(k, v)| format!("{} {}", escape::render(k), escape::render(v)))
+                    .collect();
+                Ok(lines.join("\n"))
             }
+            b"scan" => {
+                let start = split.next().ok_or("missing start argument")?;
+                let end = split.next().ok_or("missing end argument")?;
+                let end = (!end.is_empty()).then_some(end);
+                let limit = parse_limit(split.next())?;
+                Ok(render_page(store.scan(start, end, limit)?))
+            }
+            b"prefix" => {
+                let prefix = split.next().ok_or("missing prefix argument")?;
+                let limit = parse_limit(split.next())?;
+                let cursor = split.next();
+                Ok(render_page(store.prefix(prefix, cursor, limit)?))
+            }
+            b"create" => {
+                let name = parse_keyspace(split.next())?;
+                self.keyspaces.create(name)?;
+                Ok(format!("created keyspace {name}"))
+            }
+            b"drop" => {
+                let name = parse_keyspace(split.next())?;
+                self.keyspaces.remove(name)?;
+                Ok(format!("dropped keyspace {name}"))
+            }
+            b"end" | b"discard" => Err("no batch in progress".into()),
             _ => Ok("unrecognized".into()),
         }
     }
 }
 
-impl Handler for DiskHandler {
-    fn handle(&self, s: &str) -> String {
+impl<S: KvStore> DiskSession<'_, S> {
+    fn handle_result(&mut self, line: &[u8]) -> Result<String, Box<dyn error::Error>> {
+        let args = escape::split(line)?;
+        let Some(batch) = &mut self.batch else {
+            match args.first().map(|arg| arg.as_slice()) {
+                Some(b"batch") => {
+                    self.batch = Some(WriteBatch::new());
+                    return Ok("started batch".into());
+                }
+                Some(b"use") => {
+                    let name = parse_keyspace(args.get(1).map(|arg| arg.as_slice()))?;
+                    self.keyspace = self.handler.keyspaces.get(name)?;
+                    return Ok(format!("using keyspace {name}"));
+                }
+                Some(b"keyspaces") => {
+                    let names = self.handler.keyspaces.names()?;
+                    let lines: Vec<String> = names
+                        .into_iter()
+                        .map(|name| match name == self.keyspace.name() {
+                            true => format!("{name} (in use)"),
+                            false => name,
+                        })
+                        .collect();
+                    return Ok(lines.join("\n"));
+                }
+                _ => return self.handler.handle_result(self.keyspace.store()?, &args),
+            }
+        };
+
+        let mut split = args.iter().map(|arg| arg.as_slice());
+        match split.next().ok_or("empty body")? {
+            b"set" => {
+                let k = split.next().ok_or("missing key argument")?;
+                let v = split.next().ok_or("missing value argument")?;
+                match parse_ttl(&mut split)? {
+                    Some(ttl) => batch.put_with_ttl(k, v, ttl),
+                    None => batch.put(k, v),
+                }
+                Ok(format!("queued set of {}", escape::render(k)))
+            }
+            b"cas" => {
+                let k = split.next().ok_or("missing key argument")?;
+                let expected = parse_number(split.next(), "expected version")?;
+                let v = split.next().ok_or("missing value argument")?;
+                batch.put_if(k, v, expected);
+                Ok(format!("queued cas of {}", escape::render(k)))
+            }
+            b"delete" => {
+                let k = split.next().ok_or("missing key argument")?;
+                batch.delete(k);
+                Ok(format!("queued delete of {}", escape::render(k)))
+            }
+            b"end" => {
+                let batch = self.batch.take().ok_or("no batch in progress")?;
+                let len = batch.len();
+                let n = self.keyspace.store()?.write(batch)?;
+                Ok(format!("wrote batch of {len}. {n} bytes"))
+            }
+            b"discard" => {
+                self.batch = None;
+                Ok("discarded batch".into())
+            }
+            _ => Err(
+                "only set, cas and delete can be batched. finish the batch with end or discard"
+                    .into(),
+            ),
+        }
+    }
+}
+
+fn parse_number(arg: Option<&[u8]>, what: &str) -> Result<u64, Box<dyn error::Error>> {
+    let arg = arg.ok_or(format!("missing {what} argument"))?;
+    let n = str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(format!("{what} must be a number"))?;
+    Ok(n)
+}
+
+fn parse_limit(arg: Option<&[u8]>) -> Result<usize, Box<dyn error::Error>> {
+    match arg {
+        None => Ok(DEFAULT_SCAN_LIMIT as usize),
+        arg => match parse_number(arg, "limit")? {
+            0 => Err("limit must be more than 0".into()),
+            limit => Ok(limit as usize),
+        },
+    }
+}
+
+// one "key value" line per entry. if there is more, a last "next cursor <key>" line says where
+// to pick up
+fn render_page(page: Page) -> String {
+    let mut lines: Vec<String> = page
+        .entries
+        .iter()
+        .map(|(k, v)| format!("{} {}", escape::render(k), escape::render(v)))
+        .collect();
+    if let Some(next) = page.next {
+        lines.push(format!("next cursor {}", escape::render(&next)));
+    }
+    lines.join("\n")
+}
+
+fn parse_keyspace(arg: Option<&[u8]>) -> Result<&str, Box<dyn error::Error>> {
+    let arg = arg.ok_or("missing keyspace argument")?;
+    Ok(str::from_utf8(arg).map_err(|_| "keyspace must be utf-8")?)
+}
+
+// the optional `ex <seconds>` after a set's value
+fn parse_ttl<'a>(
+    split: &mut impl Iterator<Item = &'a [u8]>,
+) -> Result<Option<time::Duration>, Box<dyn error::Error>> {
+    match split.next() {
+        None => Ok(None),
+        Some(b"ex") => match parse_number(split.next(), "seconds")? {
+            0 => Err("seconds must be more than 0".into()),
+            seconds => Ok(Some(time::Duration::from_secs(seconds))),
+        },
+        Some(_) => Err("expected ex <seconds> or nothing after the value".into()),
+    }
+}
+
+impl<S: KvStore> Handler for DiskHandler<S> {
+    fn session(&self) -> Box<dyn Session + '_> {
+        Box::new(DiskSession {
+            handler: self,
+            keyspace: self.keyspaces.default(),
+            batch: None,
+        })
+    }
+
+    fn supported_commands(&self) -> &[&str] {
+        &self.supported_commands
+    }
+
+    fn settings(&self) -> String {
+        let keyspaces = match self.keyspaces.dir() {
+            Some(dir) => format!("Keyspaces in {dir}."),
+            None => String::from("Keyspaces only live in memory."),
+        };
+        format!("{} {keyspaces}", self.keyspaces.default_store().settings())
+    }
+
+    fn tick(&self) {
+        let keyspaces = match self.keyspaces.all() {
+            Ok(keyspaces) => keyspaces,
+            Err(err) => {
+                eprintln!("error listing keyspaces: {err}");
+                return;
+            }
+        };
+        // a bounded amount per tick, so the server gets back to accepting connections quickly
+        for keyspace in keyspaces {
+            // dropped since it was listed
+            let Ok(store) = keyspace.store() else {
+                continue;
+            };
+            if let Err(err) = store.delete_expired(1000) {
+                eprintln!("error deleting expired keys: {err}");
+            }
+        }
+    }
+}
+
+impl<S: KvStore> Session for DiskSession<'_, S> {
+    fn handle(&mut self, s: &[u8]) -> String {
         match self.handle_result(s) {
             Ok(out_string) => out_string,
             Err(err) => err.to_string().to_owned(),
         }
     }
+}
 
-    fn supported_commands(&self) -> &[&str] {
-        &self.supported_commands
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::file;
+    use crate::disk::memory::MemoryStore;
+    use std::fs;
+
+    const MAX_KEY_SIZE: usize = 8;
+    const MAX_VALUE_SIZE: usize = 16;
+
+    // only the snapshot tests write any, to a directory of their own
+    const NO_SNAPSHOTS: &str = "/nonexistent";
+
+    fn handler() -> DiskHandler<MemoryStore> {
+        handler_with_snapshots(NO_SNAPSHOTS)
+    }
+
+    fn handler_with_snapshots(snapshot_dir: &str) -> DiskHandler<MemoryStore> {
+        let new = || MemoryStore::new(MAX_KEY_SIZE, MAX_VALUE_SIZE);
+        DiskHandler::new(Keyspaces::in_memory(new(), new), snapshot_dir)
+    }
+
+    // runs `commands` in one session and returns what each one replied
+    fn run(handler: &DiskHandler<MemoryStore>, commands: &[&str]) -> Vec<String> {
+        let mut session = handler.session();
+        commands
+            .iter()
+            .map(|command| session.handle(command.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn get_set_delete() {
+        let out = run(
+            &handler(),
+            &[
+                "get a",
+                "set a 1",
+                "get a",
+                "set a 22",
+                "get a withversion",
+                "delete a",
+                "get a",
+                "set \"a b\" \"\\x00\"",
+                "get \"a b\"",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "a not found",
+                "wrote a=1. 2 bytes",
+                "1",
+                "wrote a=22. 3 bytes",
+                "22 (version 2)",
+                "deleted a",
+                "a not found",
+                "wrote \"a b\"=\"\\0\". 4 bytes",
+                "\"\\0\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn cas() {
+        let out = run(
+            &handler(),
+            &[
+                "cas a 0 1",
+                "cas a 0 2",
+                "cas a 1 2",
+                "cas a 1 3",
+                "get a",
+                "cas b 5 1",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "wrote a=1. version 1",
+                "a already exists at version 1",
+                "wrote a=2. version 2",
+                "a is at version 2, expected 1",
+                "2",
+                "b does not exist, expected version 5",
+            ]
+        );
+    }
+
+    #[test]
+    fn batch_that_fails_writes_nothing() {
+        let out = run(
+            &handler(),
+            &[
+                "set a 1",
+                "batch",
+                "set b 2",
+                "delete a",
+                "cas a 7 3",
+                "get a",
+                "end",
+                "get a",
+                "get b",
+                "end",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "wrote a=1. 2 bytes",
+                "started batch",
+                "queued set of b",
+                "queued delete of a",
+                "queued cas of a",
+                "only set, cas and delete can be batched. finish the batch with end or discard",
+                "a does not exist, expected version 7",
+                "1",
+                "b not found",
+                "no batch in progress",
+            ]
+        );
+    }
+
+    #[test]
+    fn batch_is_written_on_end() {
+        let out = run(
+            &handler(),
+            &[
+                "batch", "set a 1", "set b 2", "end", "dump", "batch", "set c 3", "discard",
+                "get c",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "started batch",
+                "queued set of a",
+                "queued set of b",
+                "wrote batch of 2. 4 bytes",
+                "a 1\nb 2",
+                "started batch",
+                "queued set of c",
+                "discarded batch",
+                "c not found",
+            ]
+        );
+    }
+
+    #[test]
+    fn ttl_and_persist() {
+        let out = run(
+            &handler(),
+            &[
+                "set a 1 ex 100",
+                "ttl a",
+                "persist a",
+                "persist a",
+                "ttl a",
+                "expire a 5",
+                "ttl a",
+                "get a",
+                "ttl b",
+                "set b 1 ex 0",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "wrote a=1. 2 bytes",
+                "a expires in 100s",
+                "a no longer expires",
+                "a already did not expire",
+                "a does not expire",
+                "a expires in 5s",
+                "a expires in 5s",
+                "1",
+                "b not found",
+                "seconds must be more than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_pages() {
+        let handler = handler();
+        run(
+            &handler,
+            &["set a 0", "set k1 1", "set k2 2", "set k3 3", "set l 4"],
+        );
+        let out = run(
+            &handler,
+            &[
+                "scan k \"\" 2",
+                "scan k3 \"\" 2",
+                "scan k1 k3",
+                "scan k1 k3 0",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "k1 1\nk2 2\nnext cursor k3",
+                "k3 3\nl 4",
+                "k1 1\nk2 2",
+                "limit must be more than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn prefix_pages() {
+        let handler = handler();
+        run(
+            &handler,
+            &["set a 0", "set k1 1", "set k2 2", "set k3 3", "set l 4"],
+        );
+        let out = run(
+            &handler,
+            &["prefix k 2", "prefix k 2 k3", "prefix k", "prefix x"],
+        );
+        assert_eq!(
+            out,
+            ["k1 1\nk2 2\nnext cursor k3", "k3 3", "k1 1\nk2 2\nk3 3", ""]
+        );
+    }
+
+    #[test]
+    fn prefix_of_0xff_runs_to_the_end() {
+        let handler = handler();
+        run(
+            &handler,
+            &["set \\xff 1", "set \\xff\\xff 2", "set \\xfe 3"],
+        );
+        let out = run(&handler, &["prefix \\xff"]);
+        assert_eq!(out, ["\\xff 1\n\\xff\\xff 2"]);
+    }
+
+    #[test]
+    fn keyspaces() {
+        let handler = handler();
+        let out = run(
+            &handler,
+            &[
+                "set a 1",
+                "keyspaces",
+                "create other",
+                "create other",
+                "create default",
+                "create no/slashes",
+                "use other",
+                "get a",
+                "set a 2",
+                "keyspaces",
+                "drop other",
+                "get a",
+                "keyspaces",
+                "use default",
+                "get a",
+                "drop other",
+                "use other",
+                "drop default",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "wrote a=1. 2 bytes",
+                "default (in use)",
+                "created keyspace other",
+                "keyspace other already exists",
+                "keyspace default already exists",
+                "keyspace name no/slashes can only have letters, digits, - and _",
+                "using keyspace other",
+                "a not found",
+                "wrote a=2. 2 bytes",
+                "default\nother (in use)",
+                "dropped keyspace other",
+                "keyspace other was dropped. use another one",
+                "default",
+                "using keyspace default",
+                "1",
+                "no keyspace named other",
+                "no keyspace named other",
+                "keyspace default can't be dropped",
+            ]
+        );
+    }
+
+    #[test]
+    fn keyspace_dropped_by_another_session_is_no_longer_served() {
+        let handler = handler();
+        let mut session = handler.session();
+        session.handle(b"create other");
+        session.handle(b"use other");
+        session.handle(b"set a 1");
+
+        assert_eq!(
+            run(&handler, &["drop other", "create other"]),
+            ["dropped keyspace other", "created keyspace other"]
+        );
+        let dropped = "keyspace other was dropped. use another one";
+        assert_eq!(session.handle(b"get a"), dropped);
+        session.handle(b"batch");
+        session.handle(b"set b 2");
+        assert_eq!(session.handle(b"end"), dropped);
+
+        // a keyspace created under the same name starts out empty
+        session.handle(b"use other");
+        assert_eq!(session.handle(b"get a"), "a not found");
+    }
+
+    #[test]
+    fn keys_and_values_have_limits() {
+        let out = run(
+            &handler(),
+            &[
+                "set 123456789 1",
+                "set a 12345678901234567",
+                "cas a 0 12345678901234567",
+                "batch",
+                "set b 1",
+                "set 123456789 1",
+                "end",
+                "get b",
+                "set 12345678 1234567890123456",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "key too large: 9 bytes, limit is 8 bytes",
+                "value too large: 17 bytes, limit is 16 bytes",
+                "value too large: 17 bytes, limit is 16 bytes",
+                "started batch",
+                "queued set of b",
+                "queued set of 123456789",
+                "key too large: 9 bytes, limit is 8 bytes",
+                "b not found",
+                "wrote 12345678=1234567890123456. 24 bytes",
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_that_ends_before_it_starts_is_empty() {
+        let handler = handler();
+        let mut session = handler.session();
+        session.handle(b"set a 1");
+        session.handle(b"set z 2");
+        assert_eq!(session.handle(b"scan z a"), "");
+        assert_eq!(session.handle(b"scan a a"), "");
+    }
+
+    #[test]
+    fn prefix_with_cursor_past_the_prefix_is_empty() {
+        let handler = handler();
+        let mut session = handler.session();
+        session.handle(b"set a 1");
+        session.handle(b"set ab 2");
+        session.handle(b"set c 3");
+        assert_eq!(session.handle(b"prefix a 10 c"), "");
+        assert_eq!(session.handle(b"prefix a 10 b"), "");
+        assert_eq!(session.handle(b"prefix a 10 ab"), "ab 2");
+    }
+
+    #[test]
+    fn compact_runs_in_the_keyspace_in_use() {
+        let handler = handler();
+        let mut session = handler.session();
+        session.handle(b"set a 1");
+        session.handle(b"create other");
+        session.handle(b"use other");
+        session.handle(b"set bb 22");
+        assert_eq!(session.handle(b"compact"), "compacted to 4 bytes");
+    }
+
+    #[test]
+    fn snapshots_stay_in_the_snapshot_dir() {
+        let dir = file::test_dir("handler-snapshot");
+        let out = run(
+            &handler_with_snapshots(&dir),
+            &[
+                "set a 1",
+                "snapshot ../a",
+                "snapshot a/b",
+                "snapshot .a",
+                "snapshot",
+                "snapshot a.snap",
+                "snapshot a.snap",
+            ],
+        );
+        for refused in &out[1..4] {
+            assert!(refused.starts_with("snapshot name"), "{refused}");
+        }
+        assert_eq!(out[4], "missing name argument");
+        assert!(
+            out[5].starts_with(&format!("wrote snapshot of 1 entries to {dir}/a.snap.")),
+            "{}",
+            out[5]
+        );
+        assert_eq!(out[6], format!("{dir}/a.snap already exists"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
     }
 }
@@ -0,0 +1,4 @@
+pub mod args;
+pub mod disk;
+pub mod handler;
+pub mod net;
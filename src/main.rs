@@ -1,9 +1,10 @@
-use std::{error, io, mem, ptr};
+use std::io::Write;
+use std::{env, error, io, mem, ptr};
 
+use diskmap::disk::keyspaces::Keyspaces;
+use diskmap::disk::map::DiskMap;
+use diskmap::{args, disk, handler, net};
 use nix::{libc, unistd};
-mod disk;
-mod handler;
-mod net;
 
 static mut SELF_PIPE_WRITE: i32 = -1;
 
@@ -17,21 +18,78 @@ extern "C" fn handle_signal(signal_no: libc::c_int) {
 }
 
 fn main() -> Result<(), Box<dyn error::Error>> {
+    // parse command line
+    let args = args::Args::parse(env::args().skip(1))?;
+
+    // clients get cut off once a line runs past --max-line-len, or past what a set of the
+    // biggest key and value needs if that's less
+    let max_line_len = args.max_line_len.min(net::server::TCPServer::max_line_len(
+        args.options.max_key_size,
+        args.options.max_value_size,
+    ));
+
+    // define handlers. a server that only keeps keyspaces in memory has no map to export,
+    // import or restore into
+    let handler: Box<dyn net::types::Handler> = match args.in_memory {
+        true => {
+            let max_key_size = args.options.max_key_size;
+            let max_value_size = args.options.max_value_size;
+            let default = disk::memory::MemoryStore::new(max_key_size, max_value_size);
+            let keyspaces = Keyspaces::in_memory(default, move || {
+                disk::memory::MemoryStore::new(max_key_size, max_value_size)
+            });
+            Box::new(handler::DiskHandler::new(keyspaces, &args.snapshot_dir))
+        }
+        false => {
+            // export and import only need the map, not the server, and nothing running in the
+            // background. opening it fails while anything else has it open, a server included
+            match &args.command {
+                args::Command::Export(format) => {
+                    let disk_map = DiskMap::new_for_command(&args.file_path, args.options)?;
+                    let mut out = io::BufWriter::new(io::stdout().lock());
+                    let n = disk_map.export(*format, &mut out)?;
+                    out.flush()?;
+                    eprintln!("exported {n} entries from {}", args.file_path);
+                    return Ok(());
+                }
+                args::Command::Import(format) => {
+                    let disk_map = DiskMap::new_for_command(&args.file_path, args.options)?;
+                    let n = disk_map.import(*format, io::stdin().lock())?;
+                    eprintln!("imported {n} entries into {}", args.file_path);
+                    return Ok(());
+                }
+                args::Command::Serve | args::Command::Restore(_) => {}
+            }
+
+            // a restore fills the map before it is served
+            let disk_map = DiskMap::new(&args.file_path, args.options.clone())?;
+            if let args::Command::Restore(name) = &args.command {
+                let path = disk::snapshot::path_in(&args.snapshot_dir, name)?;
+                let (metadata, n) = disk_map.restore(&path)?;
+                eprintln!(
+                    "restored {n} entries into {} from the snapshot at {path}, which has {} entries and was created at {}",
+                    args.file_path, metadata.entries, metadata.created_at
+                );
+            }
+
+            // open every other keyspace next to the default one
+            let options = args.options;
+            let keyspaces = Keyspaces::open(disk_map, &args.data_dir, move |path| {
+                DiskMap::new(path, options.clone())
+            })?;
+            Box::new(handler::DiskHandler::new(keyspaces, &args.snapshot_dir))
+        }
+    };
+
     // init signal pipe
     let pipe_fd = init_signal_pipe()?;
 
     // process id
     let pid = unistd::getpid();
 
-    // define deps
-    let disk_map = disk::map::DiskMap::new("/tmp/map")?;
-
-    // define handlers
-    let handler = Box::new(handler::DiskHandler::new(disk_map));
-
     // start server
-    let tcp_server = net::server::TCPServer::new(pid, handler);
-    let result = tcp_server.start(pipe_fd[0], "8080");
+    let tcp_server = net::server::TCPServer::new(pid, handler, max_line_len);
+    let result = tcp_server.start(pipe_fd[0], &args.port);
 
     // close self-write fds
     unsafe { libc::close(pipe_fd[0]) };
@@ -48,7 +106,7 @@ fn init_signal_pipe() -> Result<[i32; 2], io::Error> {
 
         // register handler
         let mut action = libc::sigaction {
-            sa_sigaction: handle_signal as usize,
+            sa_sigaction: handle_signal as *const () as usize,
             sa_mask: mem::zeroed(),
             sa_flags: 0,
             sa_restorer: mem::zeroed(),
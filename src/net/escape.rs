@@ -0,0 +1,180 @@
+// the line protocol is text, but keys and values can be any bytes. a token wrapped in double
+// quotes can spell out any byte with an escape:
+//
+//   \xNN  the byte with hex value NN
+//   \n \r \t \0  newline, carriage return, tab, nul
+//   \\ \"  a literal backslash or double quote
+//
+// and output that isn't plain printable text is written back the same way
+
+pub fn split(line: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut tokens = Vec::<Vec<u8>>::new();
+    let mut i = 0;
+    while i < line.len() {
+        if line[i].is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if line[i] != b'"' {
+            let start = i;
+            while i < line.len() && !line[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            tokens.push(line[start..i].to_vec());
+            continue;
+        }
+
+        // quoted token
+        let start = i;
+        i += 1;
+        let mut token = Vec::<u8>::new();
+        loop {
+            match line.get(i) {
+                None => return Err(format!("unterminated quote at column {start}")),
+                Some(b'"') => {
+                    i += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    let (byte, len) = unescape(&line[i..])
+                        .ok_or(format!("invalid escape sequence at column {i}"))?;
+                    token.push(byte);
+                    i += len;
+                }
+                Some(byte) => {
+                    token.push(*byte);
+                    i += 1;
+                }
+            }
+        }
+        if i < line.len() && !line[i].is_ascii_whitespace() {
+            return Err(format!("expected whitespace after quote at column {i}"));
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// returns the byte an escape sequence stands for and how long the sequence was
+fn unescape(sequence: &[u8]) -> Option<(u8, usize)> {
+    match sequence.get(1)? {
+        b'n' => Some((b'\n', 2)),
+        b'r' => Some((b'\r', 2)),
+        b't' => Some((b'\t', 2)),
+        b'0' => Some((0, 2)),
+        b'\\' => Some((b'\\', 2)),
+        b'"' => Some((b'"', 2)),
+        b'x' => {
+            let hex = str::from_utf8(sequence.get(2..4)?).ok()?;
+            Some((u8::from_str_radix(hex, 16).ok()?, 4))
+        }
+        _ => None,
+    }
+}
+
+// bytes that are printable text without spaces go out as they are. anything else gets quoted
+// and escaped, and so does anything that already starts with a quote, so output can always be
+// split back into the same tokens
+pub fn render(bytes: &[u8]) -> String {
+    if let Ok(s) = str::from_utf8(bytes)
+        && !s.is_empty()
+        && !s.starts_with('"')
+        && !s.chars().any(|c| c.is_control() || c.is_whitespace())
+    {
+        return s.to_owned();
+    }
+
+    let mut out = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0 => out.push_str("\\0"),
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b' '..=b'~' => out.push(*byte as char),
+            _ => out.push_str(&format!("\\x{byte:02x}")),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(
+            split(b"  set\tkey   value \r").unwrap(),
+            [b"set".to_vec(), b"key".to_vec(), b"value".to_vec()]
+        );
+        assert!(split(b"   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn quotes_spell_out_any_byte() {
+        assert_eq!(
+            split(br#"set "a b" "\x00\xff\n\r\t\0\\\"" """#).unwrap(),
+            [
+                b"set".to_vec(),
+                b"a b".to_vec(),
+                vec![0, 0xff, b'\n', b'\r', b'\t', 0, b'\\', b'"'],
+                Vec::new(),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_quotes_are_errors() {
+        assert_eq!(
+            split(br#"get "abc"#).unwrap_err(),
+            "unterminated quote at column 4"
+        );
+        assert_eq!(
+            split(br#"get "a\q""#).unwrap_err(),
+            "invalid escape sequence at column 6"
+        );
+        assert_eq!(
+            split(br#"get "a\x4""#).unwrap_err(),
+            "invalid escape sequence at column 6"
+        );
+        assert_eq!(
+            split(br#"get "a"b"#).unwrap_err(),
+            "expected whitespace after quote at column 7"
+        );
+    }
+
+    #[test]
+    fn plain_text_renders_as_it_is() {
+        assert_eq!(render(b"key"), "key");
+        assert_eq!(render("héllo".as_bytes()), "héllo");
+        assert_eq!(render(b"a\"b"), "a\"b");
+    }
+
+    #[test]
+    fn everything_else_is_quoted() {
+        assert_eq!(render(b""), r#""""#);
+        assert_eq!(render(b"a b"), r#""a b""#);
+        assert_eq!(render(b"\"a"), r#""\"a""#);
+        assert_eq!(render(&[0, b'\n', 0xff, 0x7f]), r#""\0\n\xff\x7f""#);
+    }
+
+    #[test]
+    fn render_splits_back_into_the_same_bytes() {
+        let mut values: Vec<Vec<u8>> = (0..=255u8).map(|byte| vec![byte]).collect();
+        values.push((0..=255u8).collect());
+        values.push(Vec::new());
+        values.push("\"quoted\" and spaced ünïcode".as_bytes().to_vec());
+        for value in values {
+            let line = format!("set {} {}", render(&value), render(&value));
+            assert_eq!(
+                split(line.as_bytes()).unwrap(),
+                [b"set".to_vec(), value.clone(), value]
+            );
+        }
+    }
+}
@@ -1,8 +1,15 @@
 use nix::{libc, unistd};
-use std::{error, ffi, io, mem, ptr};
+use std::{error, ffi, io, mem, ptr, time};
 
 use crate::net::types::Handler;
 
+// how often the handler gets to do background work. epoll_wait times out after this long, so
+// ticks keep coming even when nobody is connecting
+pub const TICK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// room on a line for the command, the other arguments and the quotes around the key and value
+const LINE_OVERHEAD: usize = 1024;
+
 enum Error {
     RetryableErr,
     UnexpectedErr(String),
@@ -12,6 +19,8 @@ enum ReadError {
     RetryableErr,
     UnexpectedErr(String),
     Closed,
+    // no newline within the longest line a command can need
+    TooLong,
 }
 
 pub struct ConnectionCtx<'a> {
@@ -23,18 +32,31 @@ pub struct TCPServer {
     pid: unistd::Pid,
     handler: Box<dyn Handler>,
     help_message: String,
+    // how many bytes a client can send without a newline before it is cut off. every
+    // connection can hold this much in its read buffer at once
+    max_line_len: usize,
 }
 
 impl TCPServer {
-    pub fn new(pid: unistd::Pid, handler: Box<dyn Handler>) -> TCPServer {
+    pub fn new(pid: unistd::Pid, handler: Box<dyn Handler>, max_line_len: usize) -> TCPServer {
         let help_message = TCPServer::build_help_message(handler.supported_commands());
         TCPServer {
             pid,
             handler,
             help_message,
+            max_line_len,
         }
     }
 
+    // the longest line a set or cas of the biggest key and value can take. every byte of them
+    // can take up to 4 once escaped
+    pub fn max_line_len(max_key_size: usize, max_value_size: usize) -> usize {
+        max_key_size
+            .saturating_add(max_value_size)
+            .saturating_mul(4)
+            .saturating_add(LINE_OVERHEAD)
+    }
+
     pub fn start(&self, signal_fd: i32, port: &str) -> Result<(), Box<dyn error::Error>> {
         let sock_fd = TCPServer::local_sockfd(port)?;
 
@@ -48,8 +70,15 @@ impl TCPServer {
 
         const MAX_EVENTS: i32 = 256;
         let mut events: [libc::epoll_event; MAX_EVENTS as usize] = unsafe { mem::zeroed() };
+        let mut last_tick = time::Instant::now();
         loop {
-            match self.handle_events(epoll_fd, &mut events, MAX_EVENTS, signal_fd, sock_fd) {
+            let result = self.handle_events(epoll_fd, &mut events, MAX_EVENTS, signal_fd, sock_fd);
+            if last_tick.elapsed() >= TICK_INTERVAL {
+                self.handler.tick();
+                last_tick = time::Instant::now();
+            }
+
+            match result {
                 Ok(()) | Err(Error::RetryableErr) => continue,
                 Err(Error::UnexpectedErr(err)) => {
                     eprintln!("{err}");
@@ -71,19 +100,21 @@ impl TCPServer {
         signal_fd: i32,
         sock_fd: i32,
     ) -> Result<(), Error> {
-        let count = unsafe { libc::epoll_wait(epoll_fd, events.as_mut_ptr(), max_events, -1) };
+        let timeout = TICK_INTERVAL.as_millis() as i32;
+        let count = unsafe { libc::epoll_wait(epoll_fd, events.as_mut_ptr(), max_events, timeout) };
         if count == -1 {
             let last_err = io::Error::last_os_error();
             if last_err.raw_os_error() == Some(libc::EINTR) {
                 return Err(Error::RetryableErr);
             }
-            return Err(Error::UnexpectedErr(
-                format!("got -1 from epoll_wait: {}", last_err).into(),
-            ));
+            return Err(Error::UnexpectedErr(format!(
+                "got -1 from epoll_wait: {}",
+                last_err
+            )));
         }
 
-        for i in 0..count as usize {
-            if let Err(err) = self.handle_event(events[i], signal_fd, sock_fd) {
+        for event in events.iter().take(count as usize) {
+            if let Err(err) = self.handle_event(*event, signal_fd, sock_fd) {
                 return Err(Error::UnexpectedErr(err.to_string()));
             }
         }
@@ -122,10 +153,12 @@ impl TCPServer {
         if signal == libc::SIGINT {
             return Err("received SIGINT".into());
         } else if signal == libc::SIGUSR1 {
-            self.handler.handle("compact");
+            // connections compact whatever keyspace they use, a signal only has the default one
+            let out = self.handler.session().handle(b"compact");
+            eprintln!("SIGUSR1: {out}");
         }
 
-        return Ok(());
+        Ok(())
     }
 
     fn accept_conn(&self, sock_fd: i32) -> Result<(), Box<dyn error::Error>> {
@@ -175,14 +208,19 @@ impl TCPServer {
     fn repl(&self, conn: i32) -> ReadError {
         // welcome user
         let welcome_msg = &format!(
-            "Connected to DiskMap TCP server! Process ID: {}.\n\n{}\n\n",
-            self.pid, self.help_message,
+            "Connected to DiskMap TCP server! Process ID: {}.\n{}\n\n{}\n\n",
+            self.pid,
+            self.handler.settings(),
+            self.help_message,
         );
-        match TCPServer::safe_write(conn, &welcome_msg) {
-            Err(Error::UnexpectedErr(err)) => return ReadError::UnexpectedErr(err),
-            _ => {}
+        if let Err(Error::UnexpectedErr(err)) = TCPServer::safe_write(conn, welcome_msg) {
+            return ReadError::UnexpectedErr(err);
         }
 
+        // bytes we've read that come after the last full line
+        let mut pending = Vec::<u8>::new();
+        let mut session = self.handler.session();
+
         loop {
             // show prompt
             match TCPServer::safe_write(conn, "~> ") {
@@ -192,20 +230,27 @@ impl TCPServer {
             }
 
             // read input
-            let input = match TCPServer::safe_read(conn) {
-                Ok(ref s) if s == "" => continue,
-                Ok(ref s) if s == "quit" || s == "exit" => return ReadError::Closed,
+            let input = match TCPServer::safe_read(conn, &mut pending, self.max_line_len) {
+                Ok(ref s) if s.is_empty() => continue,
+                Ok(ref s) if s == b"quit" || s == b"exit" => return ReadError::Closed,
                 Ok(s) => s,
                 Err(ReadError::RetryableErr) => continue,
                 Err(ReadError::UnexpectedErr(err)) => return ReadError::UnexpectedErr(err),
                 Err(ReadError::Closed) => return ReadError::Closed,
+                Err(ReadError::TooLong) => {
+                    let msg = format!(
+                        "line too long: more than {} bytes. closing connection.\n",
+                        self.max_line_len
+                    );
+                    let _ = TCPServer::safe_write(conn, &msg);
+                    return ReadError::TooLong;
+                }
             };
 
             // process
             let out = match input {
-                ref s if s == "help" => self.help_message.clone(),
-                ref s if s == "compact" => self.send_sigusr1(),
-                _ => self.handler.handle(&input),
+                ref s if s == b"help" => self.help_message.clone(),
+                _ => session.handle(&input),
             };
 
             // write output
@@ -217,11 +262,6 @@ impl TCPServer {
         }
     }
 
-    fn send_sigusr1(&self) -> String {
-        unsafe { libc::kill(self.pid.as_raw(), libc::SIGUSR1) };
-        String::from("compacted")
-    }
-
     fn safe_accept(sock_fd: i32) -> Result<i32, Error> {
         let conn = unsafe { libc::accept(sock_fd, ptr::null_mut(), ptr::null_mut()) };
         if conn == -1 {
@@ -234,24 +274,42 @@ impl TCPServer {
         Ok(conn)
     }
 
-    fn safe_read(conn: i32) -> Result<String, ReadError> {
-        let mut buf = [0u8; 1024];
-        let n = unsafe { libc::read(conn, buf.as_mut_ptr().cast(), buf.len() as libc::size_t) };
-        if n == -1 {
-            let err = io::Error::last_os_error();
-            match err.raw_os_error() {
-                Some(libc::EAGAIN) | Some(libc::EINTR) => Err(ReadError::RetryableErr),
-                _ => Err(ReadError::UnexpectedErr(err.to_string())),
+    // reads until there is a whole line in `pending`, and returns it. a line can be bigger than
+    // a single read, for example when setting a large value
+    fn safe_read(
+        conn: i32,
+        pending: &mut Vec<u8>,
+        max_line_len: usize,
+    ) -> Result<Vec<u8>, ReadError> {
+        let mut searched = 0;
+        let end = loop {
+            // a line that arrived whole in one read is held to the same limit as one that didn't
+            let newline = pending[searched..].iter().position(|b| *b == b'\n');
+            let len = newline.map_or(pending.len(), |i| searched + i);
+            if len > max_line_len {
+                return Err(ReadError::TooLong);
             }
-        } else if n == 0 {
-            Err(ReadError::Closed)
-        } else {
-            let bytes = &buf[..n as usize];
-            match str::from_utf8(bytes) {
-                Err(err) => Err(ReadError::UnexpectedErr(err.to_string())),
-                Ok(s) => Ok(s.trim().to_owned()),
+            if newline.is_some() {
+                break len;
             }
-        }
+            searched = pending.len();
+
+            let mut buf = [0u8; 4096];
+            let n = unsafe { libc::read(conn, buf.as_mut_ptr().cast(), buf.len() as libc::size_t) };
+            if n == -1 {
+                let err = io::Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(libc::EAGAIN) | Some(libc::EINTR) => Err(ReadError::RetryableErr),
+                    _ => Err(ReadError::UnexpectedErr(err.to_string())),
+                };
+            } else if n == 0 {
+                return Err(ReadError::Closed);
+            }
+            pending.extend_from_slice(&buf[..n as usize]);
+        };
+
+        let line: Vec<u8> = pending.drain(..=end).collect();
+        Ok(line.trim_ascii().to_vec())
     }
 
     fn safe_write(conn: i32, s: &str) -> Result<(), Error> {
@@ -358,6 +416,9 @@ impl TCPServer {
 
     fn build_help_message(supported_commands: &[&str]) -> String {
         let joined_commands = supported_commands.join("\n- ");
-        format!("Supported commands:\n- {joined_commands}\n- help\n- exit (or quit)")
+        format!(
+            "Supported commands:\n- {joined_commands}\n- help\n- exit (or quit)\n\n\
+             Keys and values can be wrapped in double quotes to use \\xNN, \\n, \\r, \\t, \\0, \\\\ and \\\" escapes."
+        )
     }
 }
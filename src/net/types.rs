@@ -1,4 +1,13 @@
 pub trait Handler {
-    fn handle(&self, s: &str) -> String;
+    // state for a single connection, e.g. a batch that is being built up
+    fn session(&self) -> Box<dyn Session + '_>;
     fn supported_commands(&self) -> &[&str];
+    // how the handler is set up, shown to users when they connect
+    fn settings(&self) -> String;
+    // background work, called by the server every `TICK_INTERVAL` or so
+    fn tick(&self);
+}
+
+pub trait Session {
+    fn handle(&mut self, s: &[u8]) -> String;
 }